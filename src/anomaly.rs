@@ -0,0 +1,227 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use serde_json;
+use walkdir::WalkDir;
+
+use layout;
+
+/// マニフェストを格納するディレクトリ名 (状態ディレクトリ内)
+const MANIFEST_DIR_NAME: &str = "manifest";
+
+/// エントロピーを計算する、ファイルの先頭のバイト数
+const ENTROPY_HEAD: usize = 4096;
+
+/// エントロピーを計算する最小のバイト数 (小さなファイルは偏りが大きいため対象外とする)
+const ENTROPY_MIN_BYTES: usize = 256;
+
+/// MassChangeConfig構造体
+/// 暗号化や上書きなどによる大量の変更を検知する設定 (`[mass_change]`)
+/// 検知した場合はアーカイブを不審として記録し、`backupfs-client ack` で確認されるまで
+/// 古いアーカイブの削除を一時停止する。
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct MassChangeConfig {
+    /// 検知を行うかどうか (デフォルト: false)
+    pub enabled: bool,
+    /// 前回のバックアップから変更、削除されたファイルがこの割合以上の場合に大量の変更とみなす。
+    pub change_ratio: f64,
+    /// 前回のファイル数がこの数未満の場合は確認しない。
+    pub min_files: usize,
+    /// ファイルの先頭のエントロピー(ビット/バイト)がこの値以上の場合に暗号化の疑いとみなす。
+    pub entropy: f64,
+    /// 変更、追加されたファイルのうち、暗号化の疑いがある割合がこの値以上の場合に不審とする。
+    pub entropy_ratio: f64,
+    /// エントロピーを計算する、変更、追加されたファイルの最大数
+    pub sample: usize,
+}
+
+impl Default for MassChangeConfig {
+    fn default() -> Self {
+        MassChangeConfig {
+            enabled: false,
+            change_ratio: 0.5,
+            min_files: 20,
+            entropy: 7.5,
+            entropy_ratio: 0.5,
+            sample: 32,
+        }
+    }
+}
+
+/// ManifestEntry構造体
+/// マニフェストに記録するファイル1件分の情報
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ManifestEntry {
+    pub size: u64,
+    /// 更新時刻(UNIX時間)
+    pub mtime: i64,
+}
+
+/// Manifest型
+/// バックアップ対象からの相対パスごとの、前回のバックアップ時のファイルの情報
+pub type Manifest = BTreeMap<String, ManifestEntry>;
+
+/// Finding構造体
+/// 大量の変更を検知した結果
+#[derive(Clone, Debug, PartialEq)]
+pub struct Finding {
+    /// 前回のファイルの数
+    pub previous: usize,
+    /// 変更、削除されたファイルの数
+    pub changed: usize,
+    /// エントロピーを計算したファイルの数
+    pub sampled: usize,
+    /// 暗号化の疑いがあるファイルの数
+    pub high_entropy: usize,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} of {} files changed or deleted", self.changed, self.previous)?;
+        if self.sampled > 0 {
+            write!(f, ", {} of {} sampled files look encrypted", self.high_entropy, self.sampled)?;
+        }
+        Ok(())
+    }
+}
+
+/// バックアップ対象のマニフェストのパスを取得する。
+fn manifest_path(state_dir: &Path, target: &Path) -> PathBuf {
+    state_dir.join(MANIFEST_DIR_NAME).join(format!("{}.json", layout::target_id(target)))
+}
+
+/// 前回のバックアップ時のマニフェストを読み込む。
+/// 記録されていない場合は None となる。
+pub fn load_manifest<P: AsRef<Path>>(state_dir: P, target: P) -> Option<Manifest> {
+    let data = fs::read(manifest_path(state_dir.as_ref(), target.as_ref())).ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
+/// バックアップ時のマニフェストを記録する。
+pub fn save_manifest<P: AsRef<Path>>(state_dir: P, target: P, manifest: &Manifest) -> io::Result<()> {
+    let path = manifest_path(state_dir.as_ref(), target.as_ref());
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, serde_json::to_vec(manifest)?)
+}
+
+/// バックアップ対象のファイルを走査し、マニフェストを作成する。
+pub fn scan<P: AsRef<Path>>(target: P) -> Manifest {
+    let target = target.as_ref();
+    WalkDir::new(target).into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| {
+            let info = e.metadata().ok()?;
+            let mtime = info.modified().ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or(0);
+            let rel = e.path().strip_prefix(target).unwrap_or(e.path()).to_string_lossy().to_string();
+            Some((rel, ManifestEntry { size: info.len(), mtime }))
+        })
+        .collect()
+}
+
+/// 前回と今回のマニフェストを比較し、大量の変更が疑われる場合は結果を返却する。
+/// 変更、削除の割合が change_ratio 以上で、かつ変更、追加されたファイルの先頭のエントロピーが
+/// 高い(暗号化された)割合が entropy_ratio 以上の場合に不審とする。
+/// すべて削除された場合など、エントロピーを計算できるファイルがない場合は割合のみで判断する。
+pub fn analyze<P: AsRef<Path>>(config: &MassChangeConfig, target: P, previous: &Manifest, current: &Manifest)
+    -> Option<Finding> {
+    if previous.len() < config.min_files.max(1) {
+        return None;
+    }
+    let changed = previous.iter()
+        .filter(|&(rel, entry)| current.get(rel) != Some(entry))
+        .count();
+    if (changed as f64) / (previous.len() as f64) < config.change_ratio {
+        return None;
+    }
+
+    let target = target.as_ref();
+    let mut sampled = 0;
+    let mut high_entropy = 0;
+    let candidates = current.iter()
+        .filter(|&(rel, entry)| previous.get(rel) != Some(entry))
+        .map(|(rel, _)| if rel.is_empty() { target.to_path_buf() } else { target.join(rel) });
+    for path in candidates {
+        if sampled >= config.sample {
+            break;
+        }
+        if let Some(e) = head_entropy(&path) {
+            sampled += 1;
+            if e >= config.entropy {
+                high_entropy += 1;
+            }
+        }
+    }
+    if sampled > 0 && (high_entropy as f64) / (sampled as f64) < config.entropy_ratio {
+        return None;
+    }
+    Some(Finding { previous: previous.len(), changed, sampled, high_entropy })
+}
+
+/// ファイルの先頭のエントロピー(ビット/バイト)を計算する。
+/// 読み込めない場合や、小さなファイルの場合は None となる。
+fn head_entropy(path: &Path) -> Option<f64> {
+    let mut buf = Vec::with_capacity(ENTROPY_HEAD);
+    File::open(path).ok()?.take(ENTROPY_HEAD as u64).read_to_end(&mut buf).ok()?;
+    if buf.len() < ENTROPY_MIN_BYTES {
+        return None;
+    }
+    Some(entropy(&buf))
+}
+
+/// バイト列のシャノンエントロピー(ビット/バイト)を計算する。
+pub fn entropy(data: &[u8]) -> f64 {
+    if data.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0usize; 256];
+    for &b in data {
+        counts[b as usize] += 1;
+    }
+    let len = data.len() as f64;
+    counts.iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| {
+            let p = c as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(n: usize, mtime: i64) -> Manifest {
+        (0..n).map(|i| (format!("{}.txt", i), ManifestEntry { size: 10, mtime })).collect()
+    }
+
+    #[test]
+    fn test_analyze() {
+        assert_eq!(0.0, entropy(&[0u8; 1024]));
+        let random: Vec<u8> = (0..4096u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        assert!(entropy(&random) > 7.5);
+
+        let config = MassChangeConfig { enabled: true, ..MassChangeConfig::default() };
+        let previous = manifest(40, 1);
+        // 変更が少ない場合
+        let mut current = previous.clone();
+        current.insert("0.txt".to_string(), ManifestEntry { size: 10, mtime: 2 });
+        assert_eq!(None, analyze(&config, "/nonexistent", &previous, &current));
+        // ファイルが少ない場合
+        assert_eq!(None, analyze(&config, "/nonexistent", &manifest(10, 1), &Manifest::new()));
+        // すべて削除された場合
+        let finding = analyze(&config, "/nonexistent", &previous, &Manifest::new()).unwrap();
+        assert_eq!((40, 40, 0), (finding.previous, finding.changed, finding.sampled));
+    }
+}
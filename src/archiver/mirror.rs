@@ -0,0 +1,212 @@
+use std::fs;
+use std::fs::Metadata;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::prelude::*;
+use walkdir::WalkDir;
+
+use archiver::{copy_file, Archiver};
+use result::{ErrorKind, Result, ResultExt};
+
+/// ミラーの出力先のディレクトリ名
+pub const MIRROR_DIR_NAME: &str = "latest";
+
+/// 削除されたファイルの退避先のディレクトリ名
+pub const TRASH_DIR_NAME: &str = ".trash";
+
+/// Mirror構造体
+/// アーカイブを作成せず、バックアップ先のディレクトリをバックアップ対象と同じ状態に同期する。
+/// サイズと更新日時が一致するファイルはコピーを行わない。
+/// delete が true の場合、バックアップ対象から削除されたファイルは
+/// 出力先と同じ階層の `.trash/<日時>/` 配下へ移動する。
+#[derive(Default)]
+pub struct Mirror {
+    delete: bool,
+}
+
+impl Mirror {
+    /// Mirror構造体のコンストラクタ
+    pub fn new(delete: bool) -> Self {
+        Mirror { delete }
+    }
+
+    /// ファイルをコピーし、パーミッションと更新日時を引き継ぐ。
+    /// サイズと更新日時が一致する場合はコピーを行わない。
+    fn sync_file(src: &Path, src_info: &Metadata, dest: &Path) -> io::Result<bool> {
+        if let Ok(dest_info) = fs::symlink_metadata(dest) {
+            if dest_info.is_file()
+                && dest_info.len() == src_info.len()
+                && dest_info.modified().ok() == src_info.modified().ok() {
+                return Ok(false);
+            }
+            if dest_info.is_dir() {
+                fs::remove_dir_all(dest)?;
+            }
+        }
+
+        copy_file(src, src_info, dest)?;
+
+        Ok(true)
+    }
+
+    /// シンボリックリンクをそのまま複製する。
+    #[cfg(unix)]
+    fn sync_symlink(src: &Path, dest: &Path) -> io::Result<()> {
+        use std::os::unix::fs::symlink;
+
+        let link = fs::read_link(src)?;
+        if fs::read_link(dest).ok() == Some(link.clone()) {
+            return Ok(());
+        }
+        Mirror::remove(dest)?;
+        symlink(link, dest)?;
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn sync_symlink(src: &Path, _dest: &Path) -> io::Result<()> {
+        warn!("symlink is not supported: {:?}", src);
+        Ok(())
+    }
+
+    /// ディレクトリを作成する。
+    /// 既存のものがディレクトリでない場合(シンボリックリンクを含む)は、置き換える。
+    /// シンボリックリンクをたどると、ミラーの外へ書き込んでしまうため。
+    fn ensure_dir(dest: &Path) -> io::Result<()> {
+        match fs::symlink_metadata(dest) {
+            Ok(ref info) if info.is_dir() => Ok(()),
+            Ok(_) => {
+                fs::remove_file(dest)?;
+                fs::create_dir(dest)
+            },
+            Err(_) => fs::create_dir_all(dest),
+        }
+    }
+
+    /// ファイル、シンボリックリンク、ディレクトリを削除する。存在しない場合は何もしない。
+    fn remove(dest: &Path) -> io::Result<()> {
+        match fs::symlink_metadata(dest) {
+            Ok(ref info) if info.is_dir() => fs::remove_dir_all(dest),
+            Ok(_) => fs::remove_file(dest),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// バックアップ対象に存在しないファイルをゴミ箱へ移動する。
+    fn move_removed<P: AsRef<Path>>(src: P, dest: P) -> io::Result<usize> {
+        let src = src.as_ref();
+        let dest = dest.as_ref();
+        let trash = dest.with_file_name(TRASH_DIR_NAME)
+            .join(Utc::now().format("%Y%m%dT%H%M%SZ").to_string());
+        let mut count = 0;
+
+        let mut walk_dir = WalkDir::new(dest).min_depth(1).into_iter();
+        while let Some(entry) = walk_dir.next() {
+            let entry = entry?;
+            let rel = entry.path().strip_prefix(dest).unwrap_or(entry.path());
+            if fs::symlink_metadata(src.join(rel)).is_ok() {
+                continue;
+            }
+
+            let to = trash.join(rel);
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(entry.path(), &to)?;
+            if entry.file_type().is_dir() {
+                walk_dir.skip_current_dir();
+            }
+            debug!("mirror: moved to trash {:?}", to);
+            count += 1;
+        }
+
+        Ok(count)
+    }
+}
+
+impl Archiver for Mirror {
+    fn archive<P: AsRef<Path>>(&self, src: P, dest: P) -> Result<()> {
+        let src = src.as_ref();
+        let dest = dest.as_ref();
+        Mirror::ensure_dir(dest).context(ErrorKind::DestinationUnwritable, dest)?;
+
+        // ファイルの場合は出力先のディレクトリ直下に同期する。
+        let src_info = fs::metadata(src).context(ErrorKind::SourceMissing, src)?;
+        if src_info.is_file() {
+            let name = src.file_name().ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+            Mirror::sync_file(src, &src_info, &dest.join(name))?;
+            return Ok(());
+        }
+
+        let mut copied = 0;
+        for entry in WalkDir::new(src).min_depth(1) {
+            let entry = entry?;
+            let rel: PathBuf = entry.path().strip_prefix(src).unwrap_or(entry.path()).to_path_buf();
+            let to = dest.join(&rel);
+            let file_type = entry.file_type();
+
+            if file_type.is_dir() {
+                Mirror::ensure_dir(&to)?;
+            } else if file_type.is_symlink() {
+                Mirror::sync_symlink(entry.path(), &to)?;
+            } else if file_type.is_file() && Mirror::sync_file(entry.path(), &entry.metadata()?, &to)? {
+                copied += 1;
+            }
+        }
+
+        let removed = if self.delete {
+            Mirror::move_removed(src, dest)?
+        } else {
+            0
+        };
+
+        debug!("mirror: {:?} -> {:?} copied: {}, removed: {}", src, dest, copied, removed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_mirror() {
+        let root = env::temp_dir().join(format!("backupfs-mirror-test-{}", process::id()));
+        let src = root.join("src");
+        let dest = root.join("dest").join(MIRROR_DIR_NAME);
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("a.txt"), b"a").unwrap();
+        fs::write(src.join("sub/b.txt"), b"b").unwrap();
+
+        // 既存のミラーへ同期する。(バックアップ対象にないファイルは delete の場合のみ退避する)
+        fs::create_dir_all(&dest).unwrap();
+        fs::write(dest.join("old.txt"), b"old").unwrap();
+        Mirror::new(false).archive(&src, &dest).unwrap();
+        assert_eq!(b"b".to_vec(), fs::read(dest.join("sub/b.txt")).unwrap());
+        assert!(dest.join("old.txt").exists());
+        Mirror::new(true).archive(&src, &dest).unwrap();
+        assert!(!dest.join("old.txt").exists());
+        let trash: Vec<PathBuf> = fs::read_dir(root.join("dest").join(TRASH_DIR_NAME)).unwrap()
+            .map(|e| e.unwrap().path().join("old.txt"))
+            .collect();
+        assert_eq!(1, trash.len());
+        assert_eq!(b"old".to_vec(), fs::read(&trash[0]).unwrap());
+
+        // ミラー内のディレクトリがシンボリックリンクに置き換えられていても、リンク先へは書き込まない。
+        #[cfg(unix)]
+        {
+            let outside = root.join("outside");
+            fs::create_dir_all(&outside).unwrap();
+            fs::remove_dir_all(dest.join("sub")).unwrap();
+            ::std::os::unix::fs::symlink(&outside, dest.join("sub")).unwrap();
+            Mirror::new(false).archive(&src, &dest).unwrap();
+            assert!(!outside.join("b.txt").exists());
+            assert!(fs::symlink_metadata(dest.join("sub")).unwrap().is_dir());
+            assert_eq!(b"b".to_vec(), fs::read(dest.join("sub/b.txt")).unwrap());
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
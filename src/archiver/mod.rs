@@ -1,9 +1,43 @@
+use std::fs;
+use std::fs::{File, Metadata};
+use std::io;
 use std::path::Path;
 use result::Result;
+use throttle;
 
+mod mirror;
+mod snapshot;
 mod zipper;
+pub use self::mirror::{Mirror, MIRROR_DIR_NAME, TRASH_DIR_NAME};
+pub use self::snapshot::Snapshot;
 pub use self::zipper::ZIP;
 
+/// Mode列挙型
+/// バックアップ対象ごとに選択できるバックアップ方式
+#[derive(Deserialize, Serialize, Clone, Copy, Eq, PartialEq, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// ZIPアーカイブを作成する。
+    #[default]
+    Zip,
+    /// アーカイブを作成せず、ファイルをそのままコピーする。
+    Mirror,
+    /// 変更のないファイルを前回のスナップショットへのハードリンクとした、
+    /// ディレクトリ形式のスナップショットを作成する。
+    Snapshot,
+}
+
+impl Mode {
+    /// 設定ファイルでの表記を取得する。
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Mode::Zip => "zip",
+            Mode::Mirror => "mirror",
+            Mode::Snapshot => "snapshot",
+        }
+    }
+}
+
 /// Archiverトレイト
 /// アーカイブ処理を行う構造体を定義するトレイト
 /// Monitor構造体に本トレイトが満たされていれば
@@ -15,3 +49,25 @@ pub trait Archiver {
     fn archive<P: AsRef<Path>>(&self, src: P, dest: P) -> Result<()>;
 }
 
+/// ファイルをコピーし、パーミッションと更新日時を引き継ぐ。
+/// 途中で失敗した場合に不完全なファイルが残らないよう、
+/// 一時ファイルへコピーしたのちに置き換える。
+/// 読み書きは設定された帯域に制限される。
+fn copy_file(src: &Path, src_info: &Metadata, dest: &Path) -> io::Result<()> {
+    let tmp = dest.with_file_name(format!(
+        ".{}.backupfs-tmp",
+        dest.file_name().map(|s| s.to_string_lossy().to_string()).unwrap_or_default(),
+    ));
+    {
+        throttle::wait_while_paused();
+        let mut reader = throttle::reader(File::open(src)?);
+        let mut writer = File::create(&tmp)?;
+        io::copy(&mut reader, &mut throttle::writer(&mut writer))?;
+        if let Ok(modified) = src_info.modified() {
+            writer.set_modified(modified)?;
+        }
+    }
+    fs::set_permissions(&tmp, src_info.permissions())?;
+    fs::rename(&tmp, dest)?;
+    Ok(())
+}
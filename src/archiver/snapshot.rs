@@ -0,0 +1,266 @@
+use std::fs;
+use std::fs::Metadata;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use walkdir::WalkDir;
+
+use archiver::{copy_file, Archiver, MIRROR_DIR_NAME};
+use naming;
+use result::{ErrorKind, Result, ResultExt};
+
+/// 作成途中のスナップショットに付与する拡張子
+const PARTIAL_EXT: &str = "partial";
+
+/// Placed列挙型
+/// スナップショットへファイルを配置した方法
+enum Placed {
+    /// 前回のスナップショットへのハードリンク
+    Linked,
+    /// コピー
+    Copied,
+    /// 前回から変更はないが、ハードリンクを作成できなかったためコピーした。
+    LinkFailed(io::Error),
+}
+
+/// Snapshot構造体
+/// バックアップ先にディレクトリ形式のスナップショットを作成する。(rsnapshot方式)
+/// 前回のスナップショットからサイズと更新日時が変わっていないファイルはハードリンクとし、
+/// 変更のあったファイルのみコピーを行うため、変更分の容量のみ消費する。
+/// 各スナップショットは独立したディレクトリのため、古いものから個別に削除しても
+/// 新しいスナップショットが壊れることはない。
+#[derive(Default)]
+pub struct Snapshot;
+
+impl Snapshot {
+    /// ディレクトリ配下の作成済みのスナップショットを古い順に取得する。
+    /// 作成途中(.partial)、ミラー、隠しディレクトリは対象外とする。
+    pub fn list<P: AsRef<Path>>(dir: P) -> io::Result<Vec<PathBuf>> {
+        let mut snapshots: Vec<PathBuf> = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !entry.file_type()?.is_dir()
+                || name.starts_with('.')
+                || name == MIRROR_DIR_NAME
+                || entry.path().extension().map(|e| e == PARTIAL_EXT).unwrap_or(false) {
+                continue;
+            }
+            snapshots.push(entry.path());
+        }
+        naming::sort_by_created(&mut snapshots);
+        Ok(snapshots)
+    }
+
+    /// プロセスの強制終了などにより残った、作成途中のスナップショットを削除する。
+    /// 同じバックアップ対象のバックアップが同時に行われることはないため、すべて削除してよい。
+    fn remove_partials(dir: &Path) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().map(|e| e == PARTIAL_EXT).unwrap_or(false) && path.is_dir() {
+                info!("snapshot: remove incomplete snapshot {:?}", path);
+                fs::remove_dir_all(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 前回のスナップショットと同一とみなせる場合はハードリンクを作成し、
+    /// そうでない場合はコピーを行う。
+    /// 同一とみなせるもののハードリンクを作成できなかった場合は、コピーしたうえでその理由を返却する。
+    fn link_or_copy(src: &Path, src_info: &Metadata, prev: Option<PathBuf>, dest: &Path) -> io::Result<Placed> {
+        let mut link_err = None;
+        if let Some(prev) = prev {
+            if let Ok(prev_info) = fs::symlink_metadata(&prev) {
+                if prev_info.is_file()
+                    && prev_info.len() == src_info.len()
+                    && prev_info.modified().ok() == src_info.modified().ok() {
+                    match fs::hard_link(&prev, dest) {
+                        Ok(()) => return Ok(Placed::Linked),
+                        Err(err) => link_err = Some(err),
+                    }
+                }
+            }
+        }
+        copy_file(src, src_info, dest)?;
+        Ok(match link_err {
+            Some(err) => Placed::LinkFailed(err),
+            None => Placed::Copied,
+        })
+    }
+
+    /// バックアップ対象をディレクトリ(作成途中のスナップショット)へ複製する。
+    /// ハードリンクとした数と、コピーした数を返却する。
+    fn fill(src: &Path, partial: &Path, prev: Option<&Path>) -> Result<(usize, usize)> {
+        // ファイルの場合はスナップショットのディレクトリ直下に配置する。
+        let (root, entries) = if fs::metadata(src).context(ErrorKind::SourceMissing, src)?.is_file() {
+            (src.parent().map(|p| p.to_path_buf()).unwrap_or_default(), WalkDir::new(src))
+        } else {
+            (src.to_path_buf(), WalkDir::new(src).min_depth(1))
+        };
+
+        let mut linked = 0;
+        let mut copied = 0;
+        let mut link_failed: Option<(usize, io::Error)> = None;
+        for entry in entries {
+            let entry = entry?;
+            let rel = entry.path().strip_prefix(&root).unwrap_or(entry.path()).to_path_buf();
+            let to = partial.join(&rel);
+            let file_type = entry.file_type();
+
+            if file_type.is_dir() {
+                fs::create_dir_all(&to)?;
+            } else if file_type.is_symlink() {
+                Snapshot::copy_symlink(entry.path(), &to)?;
+            } else if file_type.is_file() {
+                let prev_path = prev.map(|p| p.join(&rel));
+                match Snapshot::link_or_copy(entry.path(), &entry.metadata()?, prev_path, &to)? {
+                    Placed::Linked => linked += 1,
+                    Placed::Copied => copied += 1,
+                    Placed::LinkFailed(err) => {
+                        copied += 1;
+                        link_failed.get_or_insert((0, err)).0 += 1;
+                    },
+                }
+            }
+        }
+        // [protect] の immutable、append-only 属性を設定したファイルはハードリンクできない。(EPERM)
+        // 変更分のみの容量を見込んでいると不足するため、警告する。
+        if let Some((count, err)) = link_failed {
+            warn!("snapshot: copied {} unchanged files that could not be hard-linked ({}), \
+                   remove [protect] attribute to link them", count, err);
+        }
+        Ok((linked, copied))
+    }
+
+    /// シンボリックリンクをそのまま複製する。
+    #[cfg(unix)]
+    fn copy_symlink(src: &Path, dest: &Path) -> io::Result<()> {
+        use std::os::unix::fs::symlink;
+        symlink(fs::read_link(src)?, dest)
+    }
+
+    #[cfg(not(unix))]
+    fn copy_symlink(src: &Path, _dest: &Path) -> io::Result<()> {
+        warn!("symlink is not supported: {:?}", src);
+        Ok(())
+    }
+}
+
+impl Archiver for Snapshot {
+    /// destはスナップショットのディレクトリのパスとなる。
+    /// 同じ階層にある最新のスナップショットを前回のスナップショットとして利用する。
+    fn archive<P: AsRef<Path>>(&self, src: P, dest: P) -> Result<()> {
+        let src = src.as_ref();
+        let dest = dest.as_ref();
+        let parent = dest.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+        fs::create_dir_all(&parent).context(ErrorKind::DestinationUnwritable, &parent)?;
+
+        let prev = Snapshot::list(&parent)?.into_iter().rfind(|p| p != dest);
+        debug!("snapshot: previous {:?}", prev);
+
+        // 作成途中に失敗したスナップショットが、次回の比較対象とならないよう
+        // .partial として作成し、完了後に名前を変更する。
+        let partial = dest.with_file_name(format!(
+            "{}.{}",
+            dest.file_name().map(|s| s.to_string_lossy().to_string()).unwrap_or_default(),
+            PARTIAL_EXT,
+        ));
+        Snapshot::remove_partials(&parent)?;
+        fs::create_dir_all(&partial).context(ErrorKind::DestinationUnwritable, &partial)?;
+
+        // 失敗、中断した場合は作成途中のディレクトリを残さない。
+        let res = Snapshot::fill(src, &partial, prev.as_deref())
+            .and_then(|counts| fs::rename(&partial, dest).map(|_| counts).context(ErrorKind::DestinationUnwritable, dest));
+        let (linked, copied) = match res {
+            Ok(counts) => counts,
+            Err(err) => {
+                if let Err(err) = fs::remove_dir_all(&partial) {
+                    error!("{:?}", err);
+                }
+                return Err(err);
+            },
+        };
+        debug!("snapshot: {:?} -> {:?} linked: {}, copied: {}", src, dest, linked, copied);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_snapshot() {
+        let root = env::temp_dir().join(format!("backupfs-snapshot-test-{}", process::id()));
+        let src = root.join("src");
+        let dest = root.join("dest");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("a.txt"), b"a").unwrap();
+        fs::write(src.join("sub/b.txt"), b"b").unwrap();
+        // 中断されたスナップショットの残り
+        fs::create_dir_all(dest.join("20181017T000000Z-000000.partial/sub")).unwrap();
+
+        let first = dest.join("20181018T000000Z-000001");
+        Snapshot.archive(&src, &first).unwrap();
+        fs::write(src.join("sub/b.txt"), b"changed").unwrap();
+        let second = dest.join("20181019T000000Z-000002");
+        Snapshot.archive(&src, &second).unwrap();
+        assert_eq!(vec![first.clone(), second.clone()], Snapshot::list(&dest).unwrap());
+        assert_eq!(b"changed".to_vec(), fs::read(second.join("sub/b.txt")).unwrap());
+        assert_eq!(b"b".to_vec(), fs::read(first.join("sub/b.txt")).unwrap());
+
+        // 変更のないファイルは前回のスナップショットへのハードリンクとなる。
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let inode = |path: PathBuf| fs::metadata(path).unwrap().ino();
+            assert_eq!(inode(first.join("a.txt")), inode(second.join("a.txt")));
+            assert_ne!(inode(first.join("sub/b.txt")), inode(second.join("sub/b.txt")));
+        }
+
+        // 失敗した場合は作成途中のディレクトリを残さない。
+        let third = dest.join("20181020T000000Z-000003");
+        fs::write(&third, b"not a directory").unwrap();
+        assert!(Snapshot.archive(&src, &third).is_err());
+        let names: Vec<String> = fs::read_dir(&dest).unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        assert!(names.iter().all(|name| !name.ends_with(".partial")), "{:?}", names);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_snapshot_immutable() {
+        use std::os::unix::fs::MetadataExt;
+        use protect::{self, Attribute, ProtectConfig};
+
+        let root = env::temp_dir().join(format!("backupfs-snapshot-immutable-test-{}", process::id()));
+        let src = root.join("src");
+        let dest = root.join("dest");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("a.txt"), b"a").unwrap();
+        let first = dest.join("20181018T000000Z-000001");
+        Snapshot.archive(&src, &first).unwrap();
+        let config = ProtectConfig { attribute: Attribute::Immutable, ..ProtectConfig::default() };
+        protect::protect(&first, &config).unwrap();
+        // 属性を設定できない環境(CAP_LINUX_IMMUTABLE がない、対応していないファイルシステム)では確認しない。
+        if fs::hard_link(first.join("a.txt"), root.join("link")).is_ok() {
+            protect::unprotect(&first).unwrap();
+            fs::remove_dir_all(&root).unwrap();
+            return;
+        }
+
+        // ハードリンクできないファイルはコピーする。
+        let second = dest.join("20181019T000000Z-000002");
+        Snapshot.archive(&src, &second).unwrap();
+        let inode = |path: PathBuf| fs::metadata(path).unwrap().ino();
+        assert_ne!(inode(first.join("a.txt")), inode(second.join("a.txt")));
+        assert_eq!(b"a".to_vec(), fs::read(second.join("a.txt")).unwrap());
+        protect::unprotect(&first).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
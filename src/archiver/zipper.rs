@@ -1,13 +1,17 @@
 use std::path::Path;
-use std::io::prelude::*;
+use std::io;
 use std::fs::File;
+use std::time::UNIX_EPOCH;
+
+use time::{self, Timespec};
 
 use zip::ZipWriter;
 use zip::write::FileOptions;
 use walkdir::WalkDir;
 
 use archiver::Archiver;
-use result::Result;
+use result::{ErrorKind, Result, ResultExt};
+use throttle;
 
 /// ZIP構造体
 /// ZIPアーカイブを行う
@@ -15,23 +19,39 @@ pub struct ZIP;
 
 impl Archiver for ZIP {
     fn archive<P: AsRef<Path>>(&self, src: P, dest: P) -> Result<()> {
-        let mut out = File::create(dest).unwrap();
-        let mut writer = ZipWriter::new(&mut out);
+        let out = File::create(&dest).context(ErrorKind::DestinationUnwritable, &dest)?;
+        let mut writer = ZipWriter::new(throttle::writer(out));
         let walk_dir = WalkDir::new(&src);        
         let dir = walk_dir.into_iter().filter_map(|e| e.ok());
-        let mut buffer = Vec::default();
+
+        // ファイルの場合はファイル名をエントリ名とするため、親ディレクトリからの相対パスとする。
+        let root = if src.as_ref().is_file() {
+            src.as_ref().parent().map(|p| p.to_path_buf()).unwrap_or_default()
+        } else {
+            src.as_ref().to_path_buf()
+        };
         
         for entry in dir {
             let path = entry.path();
-            let name = path.strip_prefix(&src).unwrap().to_str().unwrap();
+            let name = match path.strip_prefix(&root) {
+                Ok(name) => name.to_string_lossy().to_string(),
+                Err(_) => continue,
+            };
 
             if path.is_file() {
-                writer.start_file(name, FileOptions::default())?;
-                let mut f = File::open(path)?;
+                throttle::wait_while_paused();
+                // エントリの更新時刻は元のファイルの更新時刻とする。(カタログで参照する)
+                let mut options = FileOptions::default();
+                if let Some(modified) = entry.metadata().ok().and_then(|m| m.modified().ok()) {
+                    if let Ok(d) = modified.duration_since(UNIX_EPOCH) {
+                        options = options.last_modified_time(time::at(Timespec::new(d.as_secs() as i64, 0)));
+                    }
+                }
+                writer.start_file(name, options)?;
+                let f = File::open(path).context(ErrorKind::SourceMissing, path)?;
 
-                f.read_to_end(&mut buffer)?;
-                writer.write_all(&buffer)?;
-                buffer.clear();
+                // ファイル全体をメモリへ読み込まず、帯域を制限しながら少しずつ書き込む。
+                io::copy(&mut throttle::reader(f), &mut writer)?;
             }
         }
         
@@ -1,4 +1,5 @@
 extern crate backupfs;
+extern crate chrono;
 extern crate clap;
 extern crate dirs;
 extern crate filedb;
@@ -6,11 +7,27 @@ extern crate serde;
 extern crate serde_json;
 
 use std::env;
+use std::error;
 use std::ffi::OsStr;
-use std::path::PathBuf;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::sync::{Mutex, MutexGuard};
 
 use backupfs::PathItem;
+use backupfs::catalog::{self, Change};
+use backupfs::config::Config;
+use backupfs::guard;
+use backupfs::extract;
+use backupfs::journal::{self, Event, EventKind};
+use backupfs::layout;
+use backupfs::marks::Marks;
+use backupfs::search::{self, Pattern, Query};
+use backupfs::result::{Error, ErrorKind, Result, ResultExt};
+use backupfs::systemd;
+
+use chrono::prelude::*;
 
 use clap::{Arg, ArgMatches, App, SubCommand};
 
@@ -22,20 +39,72 @@ fn main() {
     let mut ctx = Context::init();
 
     if ctx.is_call_add() {
-        return ctx.add_command().unwrap();
+        return exit_on_error(ctx.add_command());
     }
 
     if ctx.is_call_remove() {
-        return ctx.remove_command().unwrap();
+        return exit_on_error(ctx.remove_command());
     }
 
     if ctx.is_call_list() {
-        return ctx.list_command().unwrap();
+        return exit_on_error(ctx.list_command());
+    }
+
+    if ctx.is_call_ack() {
+        return exit_on_error(ctx.ack_command());
+    }
+
+    if ctx.is_call_hold() {
+        return exit_on_error(ctx.hold_command());
+    }
+
+    if ctx.is_call_release() {
+        return exit_on_error(ctx.release_command());
+    }
+
+    if ctx.is_call_rebuild_catalog() {
+        return exit_on_error(ctx.rebuild_catalog_command());
+    }
+
+    if ctx.is_call_find() {
+        return exit_on_error(ctx.find_command());
+    }
+
+    if ctx.is_call_history() {
+        return exit_on_error(ctx.history_command());
+    }
+
+    if ctx.is_call_diff() {
+        return exit_on_error(ctx.diff_command());
+    }
+
+    if ctx.is_call_cat() {
+        return exit_on_error(ctx.cat_command());
+    }
+
+    if ctx.is_call_extract() {
+        return exit_on_error(ctx.extract_command());
+    }
+
+    if ctx.is_call_log() {
+        return exit_on_error(ctx.log_command());
+    }
+
+    if ctx.is_call_install_service() {
+        return exit_on_error(ctx.install_service_command());
     }
 
     ctx.usage()
 }
 
+/// エラーの場合はメッセージを出力し、エラーの種別ごとの終了コードで終了する。
+fn exit_on_error(res: Result<()>) {
+    if let Err(err) = res {
+        eprintln!("[backupfs-client] {}", err.chain().join(": "));
+        process::exit(err.kind().exit_code());
+    }
+}
+
 /// Context構造体  
 /// 
 pub struct Context {
@@ -76,6 +145,67 @@ impl Context {
             .subcommand(SubCommand::with_name("list")
                 .about("show backup target list")
             )
+            .subcommand(SubCommand::with_name("ack")
+                .about("accept the current state of a target whose backup or pruning is on hold")
+                .arg_from_usage("<PATH> 'directory or file path'")
+            )
+            .subcommand(SubCommand::with_name("hold")
+                .about("keep an archive regardless of retention (without ARCHIVE, show marked archives)")
+                .arg_from_usage("<PATH> 'directory or file path'")
+                .arg_from_usage("[ARCHIVE] 'archive (snapshot) name'")
+                .arg_from_usage("--reason [TEXT] 'reason for the hold'")
+            )
+            .subcommand(SubCommand::with_name("release")
+                .about("release the hold on an archive")
+                .arg_from_usage("<PATH> 'directory or file path'")
+                .arg_from_usage("<ARCHIVE> 'archive (snapshot) name'")
+            )
+            .subcommand(SubCommand::with_name("rebuild-catalog")
+                .about("re-scan all archives in the destination and rebuild the file catalog")
+            )
+            .subcommand(SubCommand::with_name("find")
+                .about("find files across all archives using the catalog")
+                .arg_from_usage("<PATTERN> 'glob (e.g. *.yml, config/**/app.yml) or regex with --regex'")
+                .arg_from_usage("--regex 'treat PATTERN as a regular expression'")
+                .arg_from_usage("--target [PATH] 'only files of this backup target'")
+                .arg_from_usage("--since [TIME] 'only archives created at or after TIME'")
+                .arg_from_usage("--until [TIME] 'only archives created at or before TIME'")
+                .arg_from_usage("--larger-than [SIZE] 'only files larger than SIZE (e.g. 10M)'")
+                .arg_from_usage("--hash [MD5] 'only files with this content hash'")
+                .arg_from_usage("--all 'list every archive, not only versions whose content changed'")
+            )
+            .subcommand(SubCommand::with_name("history")
+                .about("list the archives containing a file using the catalog")
+                .arg_from_usage("<PATH> 'file path inside a backup target'")
+            )
+            .subcommand(SubCommand::with_name("diff")
+                .about("list files added, removed or modified between two archives using the catalog")
+                .arg_from_usage("<PATH> 'directory or file path'")
+                .arg_from_usage("<FROM> 'archive (snapshot) name'")
+                .arg_from_usage("[TO] 'archive (snapshot) name (default: the latest archive)'")
+            )
+            .subcommand(SubCommand::with_name("cat")
+                .about("write a file from the latest archive to stdout")
+                .arg_from_usage("<PATH> 'file path inside a backup target'")
+                .arg_from_usage("--at [TIME] 'use the latest archive created at or before TIME'")
+            )
+            .subcommand(SubCommand::with_name("extract")
+                .about("extract a single file from an archive")
+                .arg_from_usage("<ARCHIVE> 'archive (snapshot) path'")
+                .arg_from_usage("<INNER_PATH> 'file path inside the archive'")
+                .arg_from_usage("--to <DEST> 'destination file or directory'")
+                .arg_from_usage("--force 'overwrite an existing file'")
+            )
+            .subcommand(SubCommand::with_name("log")
+                .about("show backup events from the journal")
+                .arg_from_usage("--since [TIME] 'RFC 3339 time, YYYY-MM-DD or duration (e.g. 24h, 7d)'")
+                .arg_from_usage("--target [PATH] 'only events of this backup target'")
+                .arg_from_usage("--json 'print events as JSON lines'")
+            )
+            .subcommand(SubCommand::with_name("install-service")
+                .about("write a systemd user unit for backupfsd")
+                .arg_from_usage("--force 'overwrite an existing unit file'")
+            )
             .get_matches()
     }
     pub fn is_call_add(&self) -> bool {
@@ -87,8 +217,41 @@ impl Context {
     pub fn is_call_list(&self) -> bool {
         self.args.subcommand_matches("list").is_some()
     }
+    pub fn is_call_ack(&self) -> bool {
+        self.args.subcommand_matches("ack").is_some()
+    }
+    pub fn is_call_hold(&self) -> bool {
+        self.args.subcommand_matches("hold").is_some()
+    }
+    pub fn is_call_release(&self) -> bool {
+        self.args.subcommand_matches("release").is_some()
+    }
+    pub fn is_call_rebuild_catalog(&self) -> bool {
+        self.args.subcommand_matches("rebuild-catalog").is_some()
+    }
+    pub fn is_call_find(&self) -> bool {
+        self.args.subcommand_matches("find").is_some()
+    }
+    pub fn is_call_history(&self) -> bool {
+        self.args.subcommand_matches("history").is_some()
+    }
+    pub fn is_call_diff(&self) -> bool {
+        self.args.subcommand_matches("diff").is_some()
+    }
+    pub fn is_call_cat(&self) -> bool {
+        self.args.subcommand_matches("cat").is_some()
+    }
+    pub fn is_call_extract(&self) -> bool {
+        self.args.subcommand_matches("extract").is_some()
+    }
+    pub fn is_call_log(&self) -> bool {
+        self.args.subcommand_matches("log").is_some()
+    }
+    pub fn is_call_install_service(&self) -> bool {
+        self.args.subcommand_matches("install-service").is_some()
+    }
 
-    pub fn add_command(&mut self) -> filedb::Result<()> {
+    pub fn add_command(&mut self) -> Result<()> {
         let option_add = self.args.subcommand_matches("add");
         if option_add.is_none() {
             return Ok(());
@@ -96,21 +259,23 @@ impl Context {
         let matches = option_add.unwrap();
         if let Some(path_str) = matches.value_of("PATH") {
             let param_path = PathBuf::from(path_str);
-            let current_dir = env::current_dir();
-            let path = Self::to_absolute_path(current_dir.unwrap(), param_path);
+            let current_dir = env::current_dir()?;
+            let path = Self::to_absolute_path(current_dir, param_path);
 
-            if path.exists() {
-                let mutex: &Mutex<C> = self.db.c("paths")?;
-                let mut col: MutexGuard<C> = mutex.lock().unwrap();
+            if !path.exists() {
+                return Err(Error::new(ErrorKind::SourceMissing, "no such file or directory").with_path(path));
+            }
 
-                let path_item = PathItem::new(path, Vec::new());
+            let mutex: &Mutex<C> = self.db.c("paths")?;
+            let mut col: MutexGuard<C> = mutex.lock()?;
 
-                let json = serde_json::to_vec(&path_item).unwrap();
+            let path_item = PathItem::new(path, Vec::new());
 
-                println!("[backupfs-client] added: {}", path_item.path().to_string_lossy());
+            let json = serde_json::to_vec(&path_item)?;
 
-                col.insert(&json.as_slice())?;
-            }
+            println!("[backupfs-client] added: {}", path_item.path().to_string_lossy());
+
+            col.insert(json.as_slice())?;
         }
         Ok(())
     }
@@ -137,50 +302,383 @@ impl Context {
         current_dir.join(some_path)
     }
 
-    pub fn remove_command(&mut self) -> filedb::Result<()> {
+    pub fn remove_command(&mut self) -> Result<()> {
         let option_remove = self.args.subcommand_matches("remove");
         if option_remove.is_none() {
             return Ok(());
         }
         let matches = option_remove.unwrap();
         if let Some(path_str) = matches.value_of("PATH") {
-            let param_path = PathBuf::from(path_str)
-                .strip_prefix("./")
-                .map(|p| p.to_path_buf())
-                .unwrap();
-
-            let mut path: PathBuf = env::current_dir()?;
-
-            path.push(param_path);
+            let param_path = PathBuf::from(path_str);
+            let path = Self::to_absolute_path(env::current_dir()?, param_path);
 
             let mutex: &Mutex<C> = self.db.c("paths")?;
-            let mut col: MutexGuard<C> = mutex.lock().unwrap();
+            let mut col: MutexGuard<C> = mutex.lock()?;
 
             col.remove_each(|_, b| {
-
-                let path_item: PathItem = serde_json::from_slice(b.as_slice()).unwrap();
-
-                RemoveResultValue::new(path_item.path() == path, false)
+                // 壊れた行は削除せずに残す。
+                match serde_json::from_slice::<PathItem>(b.as_slice()) {
+                    Ok(path_item) => RemoveResultValue::new(path_item.path() == path, false),
+                    Err(_) => RemoveResultValue::new(false, false),
+                }
             })?;
         }
         Ok(())
     }
 
-    pub fn list_command(&mut self) -> filedb::Result<()> {
+    pub fn list_command(&mut self) -> Result<()> {
         let option_list = self.args.subcommand_matches("list");
         if option_list.is_none() {
             return Ok(());
         }
-        let mutex: &Mutex<C> =self.db.c("paths").unwrap();
-        let col: MutexGuard<C> = mutex.lock().unwrap();
+        let mutex: &Mutex<C> = self.db.c("paths")?;
+        let col: MutexGuard<C> = mutex.lock()?;
         col.for_each(|_, b| {
-            let path: PathItem = serde_json::from_slice(b.as_slice()).unwrap();
-            println!("{}", path);
+            match serde_json::from_slice::<PathItem>(b.as_slice()) {
+                Ok(path) => println!("{}", path),
+                Err(err) => eprintln!("[backupfs-client] invalid entry: {}", err),
+            }
             ForEachResultValue::new(false)
         })?;
         Ok(())
     }
 
+    /// ファイルの数の急減などにより保留しているバックアップ対象を確認済みとし、
+    /// 次回の変更検知でバックアップを再開させる。
+    /// 大量の変更の検知により古いアーカイブの削除を一時停止している場合は、削除も再開させる。
+    pub fn ack_command(&mut self) -> Result<()> {
+        let matches = match self.args.subcommand_matches("ack") {
+            Some(matches) => matches,
+            None => return Ok(()),
+        };
+        if let Some(path_str) = matches.value_of("PATH") {
+            let path = Self::to_absolute_path(env::current_dir()?, PathBuf::from(path_str));
+            let state_dir = Config::state_dir();
+            let acknowledged = guard::acknowledge(&state_dir, &path).context(ErrorKind::Io, &state_dir)?;
+            if let Some(files) = acknowledged {
+                println!("[backupfs-client] acknowledged: {} ({} files)", path.to_string_lossy(), files);
+            }
+
+            let config = Config::load(self.config_file_path()?)?;
+            let dest_dir = layout::target_dir(config.destination(), path.clone());
+            let marks = Marks::load(&dest_dir).context(ErrorKind::Io, &dest_dir)?;
+            if marks.prune_paused {
+                Marks::update(&dest_dir, |marks| marks.prune_paused = false).context(ErrorKind::Io, &dest_dir)?;
+                println!("[backupfs-client] pruning resumed: {}", path.to_string_lossy());
+            } else if acknowledged.is_none() {
+                println!("[backupfs-client] nothing on hold: {}", path.to_string_lossy());
+            }
+        }
+        Ok(())
+    }
+
+    /// アーカイブ(スナップショット)を保持(リーガルホールド)し、保持数による削除の対象外とする。
+    /// アーカイブを指定しない場合は、保持、不審の印のあるアーカイブを表示する。
+    pub fn hold_command(&mut self) -> Result<()> {
+        let matches = match self.args.subcommand_matches("hold") {
+            Some(matches) => matches,
+            None => return Ok(()),
+        };
+        let dest_dir = self.target_dir(matches.value_of("PATH").unwrap_or_default())?;
+        let name = match matches.value_of("ARCHIVE") {
+            Some(archive) => Self::archive_name(archive),
+            None => {
+                let marks = Marks::load(&dest_dir).context(ErrorKind::Io, &dest_dir)?;
+                if marks.prune_paused {
+                    println!("pruning paused (run `backupfs-client ack` to resume)");
+                }
+                for (name, archive) in &marks.archives {
+                    if let Some(ref hold) = archive.hold {
+                        println!("{} held since {}{}", name, hold.time,
+                                 hold.reason.as_ref().map(|r| format!(": {}", r)).unwrap_or_default());
+                    }
+                    if let Some(ref suspicious) = archive.suspicious {
+                        println!("{} suspicious: {}", name, suspicious);
+                    }
+                }
+                return Ok(());
+            },
+        };
+        let archive = dest_dir.join(&name);
+        if !archive.exists() {
+            return Err(Error::new(ErrorKind::InvalidArgument, "no such archive").with_path(archive));
+        }
+        let time = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+        let reason = matches.value_of("reason").map(|r| r.to_string());
+        Marks::update(&dest_dir, |marks| marks.hold(&name, time, reason)).context(ErrorKind::Io, &dest_dir)?;
+        println!("[backupfs-client] held: {}", archive.to_string_lossy());
+        Ok(())
+    }
+
+    /// アーカイブ(スナップショット)の保持を解除し、保持数による削除の対象へ戻す。
+    pub fn release_command(&mut self) -> Result<()> {
+        let matches = match self.args.subcommand_matches("release") {
+            Some(matches) => matches,
+            None => return Ok(()),
+        };
+        let dest_dir = self.target_dir(matches.value_of("PATH").unwrap_or_default())?;
+        let name = Self::archive_name(matches.value_of("ARCHIVE").unwrap_or_default());
+        let mut released = false;
+        Marks::update(&dest_dir, |marks| released = marks.release(&name)).context(ErrorKind::Io, &dest_dir)?;
+        if !released {
+            return Err(Error::new(ErrorKind::InvalidArgument, "archive is not held").with_path(dest_dir.join(&name)));
+        }
+        println!("[backupfs-client] released: {}", dest_dir.join(&name).to_string_lossy());
+        Ok(())
+    }
+
+    /// バックアップ対象のパスから、バックアップ先の対象ごとのディレクトリを取得する。
+    fn target_dir(&self, path_str: &str) -> Result<PathBuf> {
+        let path = Self::to_absolute_path(env::current_dir()?, PathBuf::from(path_str));
+        let config = Config::load(self.config_file_path()?)?;
+        let dest_dir = layout::target_dir(config.destination(), path);
+        if !dest_dir.is_dir() {
+            return Err(Error::new(ErrorKind::InvalidArgument, "no archives for the target").with_path(dest_dir));
+        }
+        Ok(dest_dir)
+    }
+
+    /// アーカイブ(スナップショット)の名前を取得する。(パスで指定された場合はファイル名とする)
+    fn archive_name(archive: &str) -> String {
+        Path::new(archive).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default()
+    }
+
+    /// バックアップ先の既存のアーカイブをすべて読み込み、ファイルのカタログを作り直す。
+    pub fn rebuild_catalog_command(&mut self) -> Result<()> {
+        let config = Config::load(self.config_file_path()?)?;
+        let (archives, files) = catalog::rebuild(Config::state_dir(), config.destination())?;
+        println!("[backupfs-client] catalog rebuilt: {} archives, {} files", archives, files);
+        Ok(())
+    }
+
+    /// カタログから、すべてのアーカイブのファイルを検索する。
+    /// 一致したファイルのバージョンごとに、アーカイブの作成日時、サイズ、アーカイブのパス、
+    /// アーカイブ内のパスをタブ区切りで表示する。(extract へそのまま渡せる)
+    pub fn find_command(&mut self) -> Result<()> {
+        let matches = match self.args.subcommand_matches("find") {
+            Some(matches) => matches,
+            None => return Ok(()),
+        };
+        let pattern = matches.value_of("PATTERN").unwrap_or_default();
+        let pattern = if matches.is_present("regex") { Pattern::regex(pattern)? } else { Pattern::glob(pattern)? };
+        let now = Utc::now();
+        let time = |name: &str| -> Result<Option<DateTime<Utc>>> {
+            match matches.value_of(name) {
+                Some(value) => Ok(Some(journal::parse_since(value, now)
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidArgument, format!("invalid --{}: {}", name, value)))?)),
+                None => Ok(None),
+            }
+        };
+        let larger_than = match matches.value_of("larger-than") {
+            Some(size) => Some(search::parse_size(size)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidArgument, format!("invalid --larger-than: {}", size)))?),
+            None => None,
+        };
+        let target = match matches.value_of("target") {
+            Some(target) => Some(Self::to_absolute_path(env::current_dir()?, PathBuf::from(target))),
+            None => None,
+        };
+        let query = Query {
+            pattern,
+            target,
+            since: time("since")?,
+            until: time("until")?,
+            larger_than,
+            hash: matches.value_of("hash").map(|h| h.to_string()),
+            all: matches.is_present("all"),
+        };
+
+        let config = Config::load(self.config_file_path()?)?;
+        let state_dir = Config::state_dir();
+        if catalog::is_empty(&state_dir) {
+            eprintln!("[backupfs-client] catalog is empty (run `backupfs-client rebuild-catalog`)");
+        }
+        let entries = catalog::select(&state_dir, query.target.as_deref(), |e| query.matches(e))?;
+        for entry in search::find(&entries, &query) {
+            println!("{}\t{}\t{}\t{}",
+                     entry.created_at().with_timezone(&Local).to_rfc3339_opts(SecondsFormat::Secs, false),
+                     entry.size,
+                     entry.archive_path(config.destination()).to_string_lossy(),
+                     entry.path);
+        }
+        Ok(())
+    }
+
+    /// カタログから、バックアップ対象内のファイルを含むアーカイブを古い順に表示する。
+    /// アーカイブの作成日時、サイズ、内容のmd5ハッシュ値、アーカイブのパスをタブ区切りで表示する。
+    pub fn history_command(&mut self) -> Result<()> {
+        let matches = match self.args.subcommand_matches("history") {
+            Some(matches) => matches,
+            None => return Ok(()),
+        };
+        let path = Self::to_absolute_path(env::current_dir()?, PathBuf::from(matches.value_of("PATH").unwrap_or_default()));
+        let config = Config::load(self.config_file_path()?)?;
+        let (target, inner) = extract::resolve(&config.destination(), &path)?;
+        let entries = catalog::history(Config::state_dir(), target, &inner)?;
+        if entries.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidArgument, "no archive in the catalog contains the file").with_path(path));
+        }
+        for entry in entries {
+            println!("{}\t{}\t{}\t{}",
+                     entry.created_at().with_timezone(&Local).to_rfc3339_opts(SecondsFormat::Secs, false),
+                     entry.size,
+                     entry.hash,
+                     entry.archive_path(config.destination()).to_string_lossy());
+        }
+        Ok(())
+    }
+
+    /// カタログから、2つのアーカイブ間で追加(A)、削除(D)、内容が変更(M)されたファイルを表示する。
+    pub fn diff_command(&mut self) -> Result<()> {
+        let matches = match self.args.subcommand_matches("diff") {
+            Some(matches) => matches,
+            None => return Ok(()),
+        };
+        let path = Self::to_absolute_path(env::current_dir()?, PathBuf::from(matches.value_of("PATH").unwrap_or_default()));
+        let from = Self::archive_name(matches.value_of("FROM").unwrap_or_default());
+        let to = matches.value_of("TO").map(Self::archive_name);
+        let config = Config::load(self.config_file_path()?)?;
+        let (target, _) = extract::resolve(&config.destination(), &path)?;
+        for change in catalog::diff(Config::state_dir(), target, &from, to.as_deref())? {
+            match change {
+                Change::Added(entry) => println!("A\t{}", entry.path),
+                Change::Removed(entry) => println!("D\t{}", entry.path),
+                Change::Modified(_, entry) => println!("M\t{}", entry.path),
+            }
+        }
+        Ok(())
+    }
+
+    /// バックアップ対象内のファイルを、指定した時刻以前の最新のアーカイブから標準出力へ書き出す。
+    pub fn cat_command(&mut self) -> Result<()> {
+        let matches = match self.args.subcommand_matches("cat") {
+            Some(matches) => matches,
+            None => return Ok(()),
+        };
+        let path = Self::to_absolute_path(env::current_dir()?, PathBuf::from(matches.value_of("PATH").unwrap_or_default()));
+        let at = match matches.value_of("at") {
+            Some(at) => journal::parse_since(at, Utc::now())
+                .ok_or_else(|| Error::new(ErrorKind::InvalidArgument, format!("invalid --at: {}", at)))?,
+            None => Utc::now(),
+        };
+        let config = Config::load(self.config_file_path()?)?;
+        let located = extract::locate(Config::state_dir(), config.destination(), path, at)?;
+        let stdout = io::stdout();
+        match extract::copy_entry(&located.archive, &located.inner, &mut stdout.lock()) {
+            // 出力先(head など)が先に終了した場合はエラーとしない。
+            Err(ref err) if Self::is_broken_pipe(err) => Ok(()),
+            res => res.map(|_| ()),
+        }
+    }
+
+    fn is_broken_pipe(err: &Error) -> bool {
+        let mut source = error::Error::source(err);
+        while let Some(cause) = source {
+            if let Some(io_err) = cause.downcast_ref::<io::Error>() {
+                return io_err.kind() == io::ErrorKind::BrokenPipe;
+            }
+            source = cause.source();
+        }
+        false
+    }
+
+    /// アーカイブ(スナップショット)内のファイルを1件取り出し、ジャーナルへ復元として記録する。
+    pub fn extract_command(&mut self) -> Result<()> {
+        let matches = match self.args.subcommand_matches("extract") {
+            Some(matches) => matches,
+            None => return Ok(()),
+        };
+        let current_dir = env::current_dir()?;
+        let archive = Self::to_absolute_path(current_dir.clone(), PathBuf::from(matches.value_of("ARCHIVE").unwrap_or_default()));
+        let inner = matches.value_of("INNER_PATH").unwrap_or_default();
+        let to = Self::to_absolute_path(current_dir, PathBuf::from(matches.value_of("to").unwrap_or_default()));
+        if !archive.exists() {
+            return Err(Error::new(ErrorKind::InvalidArgument, "no such archive").with_path(archive));
+        }
+
+        let dest = extract::extract(&archive, inner, &to, matches.is_present("force"))?;
+        println!("[backupfs-client] extracted: {}", dest.to_string_lossy());
+
+        let target = archive.parent().and_then(layout::read_target).unwrap_or_else(|| archive.clone());
+        journal::open(journal::default_path(Config::state_dir()));
+        journal::record(Event::new(EventKind::Restore, target)
+            .with_archive(&archive)
+            .with_count(1)
+            .with_message(format!("{} -> {}", inner, dest.to_string_lossy())));
+        Ok(())
+    }
+
+    /// ジャーナルに記録されたバックアップ、失敗、削除、復元のイベントを表示する。
+    pub fn log_command(&mut self) -> Result<()> {
+        let matches = match self.args.subcommand_matches("log") {
+            Some(matches) => matches,
+            None => return Ok(()),
+        };
+        let since = match matches.value_of("since") {
+            Some(since) => Some(journal::parse_since(since, Utc::now())
+                .ok_or_else(|| Error::new(ErrorKind::InvalidArgument, format!("invalid --since: {}", since)))?),
+            None => None,
+        };
+        let target = match matches.value_of("target") {
+            Some(target) => Some(Self::to_absolute_path(env::current_dir()?, PathBuf::from(target))),
+            None => None,
+        };
+
+        let events = journal::read(journal::default_path(Config::state_dir()), since, target.as_deref())?;
+        for event in events {
+            if matches.is_present("json") {
+                println!("{}", serde_json::to_string(&event)?);
+            } else {
+                println!("{}", event);
+            }
+        }
+        Ok(())
+    }
+
+    /// 設定ファイルのパス(絶対パス)を取得する。
+    /// 指定されていない場合はデフォルトのパスとなる。
+    fn config_file_path(&self) -> Result<PathBuf> {
+        let current_dir = env::current_dir()?;
+        Ok(self.args.value_of("config")
+            .map(|path| Self::to_absolute_path(current_dir, PathBuf::from(path)))
+            .unwrap_or_else(Config::default_path))
+    }
+
+    /// backupfsd を起動するsystemdのユーザーユニットを書き出す。
+    /// backupfsd は本コマンドと同じディレクトリにあるものとし、現在の設定ファイルを指定する。
+    pub fn install_service_command(&mut self) -> Result<()> {
+        let matches = match self.args.subcommand_matches("install-service") {
+            Some(matches) => matches,
+            None => return Ok(()),
+        };
+        let config_path = self.config_file_path()?;
+        let config = Config::load(&config_path)?;
+        let daemon = env::current_exe()?.with_file_name("backupfsd");
+
+        let unit_path = systemd::user_unit_path();
+        if unit_path.exists() && !matches.is_present("force") {
+            return Err(Error::new(ErrorKind::InvalidArgument, "unit file already exists (use --force to overwrite)")
+                .with_path(unit_path));
+        }
+        if let Some(dir) = unit_path.parent() {
+            fs::create_dir_all(dir).context(ErrorKind::Io, dir)?;
+        }
+        fs::write(&unit_path, systemd::user_unit(&daemon, &config_path, &config)).context(ErrorKind::Io, &unit_path)?;
+        println!("[backupfs-client] installed: {}", unit_path.to_string_lossy());
+
+        // メトリクスが有効な場合は、待ち受けるソケットをsystemdから渡すソケットユニットも書き出す。
+        let mut units = vec![systemd::UNIT_NAME];
+        if config.metrics.enabled {
+            let socket_path = systemd::user_socket_path();
+            fs::write(&socket_path, systemd::user_socket(&config)).context(ErrorKind::Io, &socket_path)?;
+            println!("[backupfs-client] installed: {}", socket_path.to_string_lossy());
+            units.push(systemd::SOCKET_NAME);
+        }
+
+        println!("run `systemctl --user daemon-reload && systemctl --user enable --now {}`", units.join(" "));
+        Ok(())
+    }
+
     pub fn usage(&self) {
         println!("{}", self.args.usage());
     }
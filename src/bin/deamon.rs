@@ -1,8 +1,8 @@
 extern crate backupfs;
+extern crate chrono;
 extern crate clap;
 extern crate ctrlc;
 extern crate dirs;
-extern crate env_logger;
 extern crate filedb;
 #[macro_use]
 extern crate log;
@@ -11,14 +11,25 @@ extern crate serde_json;
 
 use std::collections::HashMap;
 use std::path::PathBuf;
-use std::sync::mpsc;
-use std::thread;
-use std::time::Duration;
+use std::process;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
 
 use backupfs::archiver::ZIP;
+use backupfs::config::Config;
+use backupfs::destination::S3;
+use backupfs::journal;
+use backupfs::lock::InstanceLock;
+use backupfs::logging::{self, LogFormat};
+use backupfs::metrics;
 use backupfs::monitor::Monitor;
 use backupfs::PathItem;
-use backupfs::result::Result;
+use backupfs::result::{Error, ErrorKind, Result, ResultExt};
+use backupfs::signal::{self, Signal};
+use backupfs::systemd;
+use backupfs::throttle;
+
+use chrono::prelude::*;
 
 use clap::{Arg, ArgMatches, App};
 
@@ -26,12 +37,25 @@ use filedb::FileDB;
 use filedb::callback::*;
 
 fn main() {
-    env_logger::init();
+    let args = Context::parse_args();
+    let format = args.value_of("log-format").and_then(LogFormat::parse).unwrap_or(LogFormat::Text);
+    logging::init(format);
+
+    // 常駐する場合、シグナルは専用のスレッドで受け取るため、スレッドを生成する前にブロックする。
+    // --once, --dry-run では受け取るスレッドを起動しないため、ブロックせず既定の動作(終了)とする。
+    if !args.is_present("once") && !args.is_present("dry-run") {
+        if let Err(err) = signal::block() {
+            warn!("{:?}", err);
+        }
+    }
 
-    let mut ctx: Context = Context::init();
-    match ctx.run() {
+    // エラーの種別ごとに終了コードを変える。
+    match Context::init(args).and_then(|mut ctx| ctx.run()) {
         Ok(_) => info!("exit"),
-        Err(err) => error!("{:?}", err),
+        Err(err) => {
+            error!("{:?}", err);
+            process::exit(err.kind().exit_code());
+        },
     }
 }
 
@@ -41,34 +65,113 @@ pub struct Context {
     args: ArgMatches<'static>,
     monitor: Monitor<ZIP>,
     db: FileDB,
+    lock: Option<InstanceLock>,
 }
 
 impl Context {
     /// 初期化処理
     /// 本コマンドを利用する際の初期のセットアップを担当する。
-    pub fn init() -> Self {
+    pub fn init(args: ArgMatches<'static>) -> Result<Self> {
+        // dry-run時は何も書き込まないため、多重起動を許容する。
+        let lock = if args.is_present("dry-run") {
+            None
+        } else {
+            Some(InstanceLock::acquire(Config::state_dir())?)
+        };
         let db = FileDB::default();
-        let args = Context::parse_args();
-        let mut ctx = Context::new(db, args);
-        if let Ok(paths) = ctx.load() {
-            ctx.monitor.set_paths(paths);
+        if lock.is_some() {
+            journal::open(journal::default_path(Config::state_dir()));
         }
-        ctx
+        let mut ctx = Context::new(db, args)?;
+        ctx.lock = lock;
+        let items = ctx.load()?;
+        ctx.monitor.set_paths(items.iter().map(|item| (item.path(), item.hash())).collect());
+        ctx.monitor.set_last_runs(items.iter()
+            .filter_map(|item| item.last_run().map(|t| (item.path(), Utc.timestamp(t, 0))))
+            .collect());
+        ctx.monitor.set_files(items.iter()
+            .filter_map(|item| item.files().map(|n| (item.path(), n)))
+            .collect());
+        // dry-run時はバックアップ先へ書き込まないため、旧形式のレイアウトの移行も行わない。
+        if !ctx.args.is_present("dry-run") {
+            if let Err(err) = ctx.monitor.migrate_layout() {
+                error!("{:?}", err);
+            }
+        }
+        Ok(ctx)
     }
 
     /// Context構造体のコンストラクタ
-    /// 内部にて、バックアップ先のディレクトリの設定、
+    /// 内部にて、設定ファイルの読み込み、バックアップ先のディレクトリの設定、
     /// Monitor構造体の生成などをおこなっている。
-    pub fn new(db: FileDB, args: ArgMatches<'static>) -> Self {
+    pub fn new(db: FileDB, args: ArgMatches<'static>) -> Result<Self> {
+        let config_path = args.value_of("config")
+            .map(PathBuf::from)
+            .unwrap_or_else(Config::default_path);
+        let config = Config::load(&config_path)?;
+
         let path = if let Some(dest) = args.value_of("dest") {
             PathBuf::from(dest)
         } else {
-            dirs::home_dir().unwrap_or_default().join(".backupfs_archive")
+            config.destination()
         };
         debug!("destination_path: {:?}", path);
-        let monitor = Monitor::new(ZIP, HashMap::new(), path);
 
-        Context { args, monitor, db }
+        // ワーカースレッドへ引き継ぐため、Monitor構造体の生成前に優先度を設定する。
+        throttle::apply_priority(&config.throttle);
+        let mut monitor = Monitor::new(ZIP, HashMap::new(), path);
+        Context::apply_config(&mut monitor, config)?;
+
+        Ok(Context { args, monitor, db, lock: None })
+    }
+
+    /// 設定ファイルの内容をMonitor構造体などへ反映する。
+    fn apply_config(monitor: &mut Monitor<ZIP>, config: Config) -> Result<()> {
+        throttle::configure(&config.throttle);
+
+        // S3互換オブジェクトストレージへの転送設定
+        // 再読み込みで設定が削除された場合は転送を止める。
+        match config.s3.clone() {
+            Some(s3_config) => {
+                let s3 = S3::new(s3_config)?;
+                let keep_local = s3.keep_local();
+                monitor.set_remote(Some(Box::new(s3)), keep_local);
+            },
+            None => monitor.set_remote(None, true),
+        }
+        monitor.set_config(config);
+        Ok(())
+    }
+
+    /// 設定ファイルを再読み込みする。(SIGHUP)
+    /// 実行中のバックアップの完了を待ってから反映する。
+    /// バックアップ先のディレクトリは再起動するまで反映されない。
+    fn reload(&mut self) -> Result<()> {
+        let config_path = self.args.value_of("config")
+            .map(PathBuf::from)
+            .unwrap_or_else(Config::default_path);
+        let config = Config::load(&config_path)?;
+        systemd::reloading();
+        // 完了を待つ間も、ウォッチドッグの期限を超えないよう通知する。
+        let interval = systemd::watchdog_interval().unwrap_or_else(|| Duration::from_secs(60));
+        let mut count = 0;
+        while self.monitor.running() > 0 {
+            let deadline = Instant::now() + interval;
+            count += self.monitor.wait_until(deadline);
+            systemd::watchdog();
+            // 期限の前に待ち終えた場合は、結果を受け取れなくなっている。(ワーカーの異常終了など)
+            if self.monitor.running() > 0 && Instant::now() < deadline {
+                break;
+            }
+        }
+        throttle::apply_priority(&config.throttle);
+        Context::apply_config(&mut self.monitor, config)?;
+        info!("reloaded {}", config_path.display());
+        systemd::ready("reloaded");
+        if count > 0 {
+            self.save()?;
+        }
+        Ok(())
     }
 
     /// コマンドインターフェースの定義
@@ -79,51 +182,70 @@ impl Context {
             .author("s tomo <uotias64_mole@yahoo.co.jp>")
             .about("backup system deamon")
             .arg(Arg::from_usage("--dest [PATH] 'dest path'"))
+            .arg(Arg::from_usage("--config -c [CONFIG_FILE] 'config file path'"))
+            .arg(Arg::from_usage("--once 'run a single backup pass and exit'"))
+            .arg(Arg::from_usage("--dry-run 'show what would be backed up without writing anything'"))
+            .arg(Arg::from_usage("--log-format [FORMAT] 'log format'").possible_values(&["text", "json"]))
             .get_matches()
     }
 
-    fn load(&mut self) -> Result<HashMap<PathBuf, Vec<u8>>> {
-        let mutex = self.db.c("paths")?;
-        let mut cmap = HashMap::new();
+    fn load(&mut self) -> Result<Vec<PathItem>> {
+        let mutex = self.db.c("paths").context(ErrorKind::RegistryCorrupt, Config::state_dir())?;
+        let mut items: Vec<PathItem> = Vec::new();
         if let Ok(col) = mutex.lock() {
             col.for_each(|_, data| {
-                let res = serde_json::from_slice(&data);
-                if res.is_err() {
-                    error!("{:?}", res.unwrap_err());
-                    return ForEachResultValue::new(false);
-                }
-                let path_item: PathItem = res.unwrap();
+                let path_item: PathItem = match serde_json::from_slice(&data) {
+                    Ok(path_item) => path_item,
+                    Err(err) => {
+                        error!("{:?}", err);
+                        return ForEachResultValue::new(false);
+                    },
+                };
 
-                cmap.entry(path_item.path()).or_insert(path_item.hash());
+                if items.iter().all(|item| item.path() != path_item.path()) {
+                    items.push(path_item);
+                }
 
                 ForEachResultValue::new(false)
             })?;
         }
-        Ok(cmap)
+        Ok(items)
     }
 
     fn save(&mut self) -> Result<()> {
         let paths: HashMap<PathBuf, Vec<u8>> = self.monitor.get_paths_iter()
             .map(|(path, hash)| (PathBuf::from(path), hash.clone()))
             .collect::<HashMap<PathBuf, Vec<u8>>>();
+        let last_runs: HashMap<PathBuf, i64> = self.monitor.last_runs().iter()
+            .map(|(path, t)| (path.clone(), t.timestamp()))
+            .collect();
+        let files = self.monitor.files().clone();
 
-        let mutex = self.db.c("paths")?;
+        // select_each の後は同じハンドルで再度保存できないため、保存のたびに開き直す。
+        self.db = FileDB::default();
+        let mutex = self.db.c("paths").context(ErrorKind::RegistryCorrupt, Config::state_dir())?;
         if let Ok(mut col) = mutex.lock() {
 
             col.select_each(move |_, data| {
 
-                let res = serde_json::from_slice(&data);
-                if res.is_err() {
-                    error!("{:?}", res.unwrap_err());
-                    return SelectResultValue::new(false, data.clone(), false);
-                }
-
-                let mut path_item: PathItem = res.unwrap();
+                let mut path_item: PathItem = match serde_json::from_slice(&data) {
+                    Ok(path_item) => path_item,
+                    Err(err) => {
+                        error!("{:?}", err);
+                        return SelectResultValue::new(false, data.clone(), false);
+                    },
+                };
                 if let Some(hash) = paths.get(&path_item.path()) {
                     if &path_item.hash() != hash {
                         path_item.set_hash(hash.clone());
                     }
                 }
+                if let Some(last_run) = last_runs.get(&path_item.path()) {
+                    path_item.set_last_run(Some(*last_run));
+                }
+                if let Some(n) = files.get(&path_item.path()) {
+                    path_item.set_files(Some(*n));
+                }
 
                 let json = serde_json::to_vec(&path_item).unwrap_or(data);
                 
@@ -139,59 +261,154 @@ impl Context {
     /// 本コマンドの終了処理の設定、
     /// ワーカーの呼び出しを行う。
     pub fn run(&mut self) -> Result<()> {
+        if self.args.is_present("dry-run") {
+            self.dry_run();
+            return Ok(());
+        }
+        if self.args.is_present("once") {
+            return self.once();
+        }
+
         info!("starting");
 
-        // 終了処理の伝達用のチャンネルの生成
-        let (exit_sender, exit_receiver) = mpsc::channel::<()>();
-        let send = exit_sender.clone();
+        // シグナルの伝達用のチャンネルの生成
+        let (signal_sender, signal_receiver) = mpsc::channel::<Signal>();
 
-        // 終了処理
-        // ctrl + c 押下時の処理
-        let res = ctrlc::set_handler(move || {
-            info!("goodbye...");
-            if let Err(err) = send.send(()) {
+        // SIGTERM, SIGINT, SIGHUP を専用のスレッドで受け取る。
+        // 利用できない場合は ctrl + c 押下時の処理のみ設定する。
+        if let Err(err) = signal::listen(signal_sender.clone()) {
+            debug!("{:?}", err);
+            let send = signal_sender.clone();
+            let res = ctrlc::set_handler(move || {
+                if let Err(err) = send.send(Signal::Terminate) {
+                    error!("{:?}", err);
+                }
+            });
+            if let Err(err) = res {
                 error!("{:?}", err);
             }
-        });
+        }
 
-        match res {
-            Err(err) => error!("{:?}", err),
-            _ => {}
-        };
+        // メトリクスを公開するHTTPサーバー (設定ファイルの再読み込みでは起動、停止しない)
+        let metrics_config = self.monitor.config().metrics.clone();
+        if metrics_config.enabled {
+            metrics::serve(&metrics_config, self.monitor.destination().to_path_buf())
+                .context(ErrorKind::InvalidConfig, &metrics_config.listen)?;
+        }
+
+        // Type=notify で起動された場合は、起動の完了を通知する。
+        systemd::ready(&format!("watching {} targets", self.monitor.get_paths_iter().count()));
 
         // ワーカー呼び出し
-        self.watch_worker(&exit_receiver)
+        self.watch_worker(&signal_receiver)
     }
 
-    fn watch_worker(&mut self, exit_receiver: &mpsc::Receiver<()>) -> Result<()> {
-        // ワーカーなので、loop
-        loop {
-            // 終了処理
-            // チャンネルよりデータが渡されると、
-            // return によってループを抜ける。
-            // その際にバックアップ対象のパスとmd5ハッシュ値のキャッシュをfiledbへ保存する。
-            if let Ok(_) = exit_receiver.try_recv() {
-                return self.save();
+    /// 1回だけ変更検知 & バックアップを行い、完了を待って保存する。
+    /// 失敗した対象がある場合は、その種別に応じた終了コードとなる。
+    /// 失敗がなく、見つからない対象、保留している対象がある場合も、それぞれの終了コードとなる。
+    fn once(&mut self) -> Result<()> {
+        let mut count = self.monitor.now()?;
+        count += self.monitor.wait();
+        info!("backed up {} targets", count);
+        self.save()?;
+
+        let failures = self.monitor.retry().failures();
+        if let Some(&(path, failure)) = failures.first() {
+            return Err(Error::new(failure.kind(), format!("{} targets failed", failures.len())).with_path(path));
+        }
+        // 見つからない対象、保留している対象もバックアップされていないため、成功とはしない。
+        let missing = self.monitor.missing();
+        if let Some(path) = missing.iter().next() {
+            return Err(Error::new(ErrorKind::SourceMissing, format!("{} targets missing", missing.len())).with_path(path));
+        }
+        let held = self.monitor.held();
+        if let Some(path) = held.iter().next() {
+            let message = format!("{} targets held (run `backupfs-client ack` to accept)", held.len());
+            return Err(Error::new(ErrorKind::BackupHeld, message).with_path(path));
+        }
+        Ok(())
+    }
+
+    /// バックアップを行った場合の対象、ファイル、バックアップ先を表示する。
+    fn dry_run(&self) {
+        for plan in self.monitor.plan() {
+            if plan.missing {
+                println!("[missing] {} ({})", plan.path.display(), plan.mode.as_str());
+                continue;
             }
+            println!("[{}] {} ({})", if plan.changed { "changed" } else { "unchanged" },
+                     plan.path.display(), plan.mode.as_str());
+            println!("  -> {}", plan.destination.display());
+            if plan.changed {
+                for file in &plan.files {
+                    println!("  {}", file.display());
+                }
+            }
+        }
+    }
 
+    /// 直近の変更検知 & バックアップ処理の概要
+    fn summary(&self) -> String {
+        let failures = self.monitor.retry().failures().len();
+        let last_run = self.monitor.last_runs().values().max()
+            .map(|t| t.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|| "never".to_string());
+        format!("{} targets, last run: {}, failing: {}, missing: {}",
+                self.monitor.get_paths_iter().count(), last_run, failures, self.monitor.missing().len())
+    }
+
+    fn watch_worker(&mut self, signal_receiver: &mpsc::Receiver<Signal>) -> Result<()> {
+        let watchdog = systemd::watchdog_interval();
+        // ワーカーなので、loop
+        loop {
             // 実際の変更検知 & バックアップ処理の受付
             // 基本的にエラー発生時もログに出力するのみで、
             // ハンドリングは行わず、次の処理へ移る。
             match self.monitor.now() {
+                // バックアップごとの結果はイベントとして出力されるため、ここでは出力しない。
                 Ok(count) => {
                     if count > 0 {
-                        info!("changed {} files", count);
-                    } else {
-                        info!("not changed");
+                        // 異常終了した場合に備え、バックアップに成功するたびに保存する。
+                        if let Err(err) = self.save() {
+                            error!("{:?}", err);
+                        }
                     }
-                    // TODO: 保存周期の設定を柔軟にする必要がある。
-                    thread::sleep(Duration::new(5, 0));
                 },
                 Err(err) => {
                     // TODO: 失敗時のハンドルが必要な際は追記すること
                     warn!("{:?}", err);
                 },
             };
+
+            // 直近の処理の概要を systemctl status に表示し、ウォッチドッグへ通知する。
+            systemd::status(&self.summary());
+            systemd::watchdog();
+
+            // 次の変更検知、もしくはスケジュールの時刻まで待機する。
+            // ウォッチドッグが有効な場合は、通知の間隔を超えて待機しない。
+            // 待機中にシグナルを受け取った場合は、再読み込み、もしくは終了処理を行う。
+            let timeout = match watchdog {
+                Some(interval) => self.monitor.next_wake().min(interval),
+                None => self.monitor.next_wake(),
+            };
+            match signal_receiver.recv_timeout(timeout) {
+                Err(RecvTimeoutError::Timeout) => {},
+                Ok(Signal::Reload) => {
+                    if let Err(err) = self.reload() {
+                        error!("{:?}", err);
+                    }
+                },
+                Ok(Signal::Terminate) | Err(RecvTimeoutError::Disconnected) => {
+                    info!("goodbye...");
+                    systemd::stopping();
+                    // 実行中のバックアップの完了を待ち(期限を超えた場合は中断し)、
+                    // バックアップ対象のパスとmd5ハッシュ値のキャッシュをfiledbへ保存する。
+                    let timeout = self.monitor.config().shutdown_timeout();
+                    let count = self.monitor.shutdown(timeout);
+                    info!("finished {} running backups", count);
+                    return self.save();
+                },
+            }
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,734 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::UNIX_EPOCH;
+
+use chrono::prelude::*;
+use crypto::digest::Digest;
+use crypto::md5::Md5;
+use serde_json;
+use walkdir::WalkDir;
+use zip::ZipArchive;
+
+use archiver::Mode;
+use layout;
+use lock::FileLock;
+use naming;
+use result::{Error, ErrorKind, Result, ResultExt};
+use retention;
+
+/// カタログを格納するディレクトリ名 (状態ディレクトリ内)
+const CATALOG_DIR_NAME: &str = "catalog";
+
+/// カタログのファイルの拡張子 (バックアップ対象ごとに1ファイル、1行に1エントリのJSON)
+const CATALOG_EXT: &str = "jsonl";
+
+/// アーカイブごとのカタログ内の範囲と、最新のアーカイブのエントリを格納するファイルの拡張子
+/// (追加のたびにカタログ全体を読み込まず、パスから二分探索するため)
+const META_EXT: &str = "meta.json";
+
+/// カタログの更新を backupfs-client と直列化するロックファイル (状態ディレクトリ内)
+const LOCK_FILE_NAME: &str = "catalog.lock";
+
+/// カタログの読み書きを直列化するロック (ワーカースレッドから並列に更新されるため)
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// CatalogConfig構造体
+/// アーカイブ(スナップショット)内のファイルのカタログの設定 (`[catalog]`)
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CatalogConfig {
+    /// アーカイブの作成時にカタログを更新するかどうか (デフォルト: true)
+    pub enabled: bool,
+}
+
+impl Default for CatalogConfig {
+    fn default() -> Self {
+        CatalogConfig { enabled: true }
+    }
+}
+
+/// CatalogEntry構造体
+/// カタログの1行分(JSON)
+/// アーカイブ(スナップショット)に含まれるファイル1件分の情報
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CatalogEntry {
+    /// バックアップ対象のパス
+    pub target: PathBuf,
+    /// アーカイブ(スナップショット)の名前
+    pub archive: String,
+    /// アーカイブの作成日時(UNIX時間)
+    pub created: i64,
+    /// アーカイブ内の相対パス
+    pub path: String,
+    pub size: u64,
+    /// 更新時刻(UNIX時間) (ZIPは2秒単位)
+    pub mtime: i64,
+    /// 内容のmd5ハッシュ値(16進数)
+    pub hash: String,
+    /// ZIPのエントリのCRC-32 (変更のないエントリのハッシュ値を再利用するため)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crc32: Option<u32>,
+}
+
+impl CatalogEntry {
+    /// アーカイブの作成日時を取得する。
+    pub fn created_at(&self) -> DateTime<Utc> {
+        Utc.timestamp(self.created, 0)
+    }
+
+    /// アーカイブ(スナップショット)のパスを取得する。
+    pub fn archive_path<P: AsRef<Path>>(&self, destination: P) -> PathBuf {
+        layout::target_dir(destination.as_ref(), &self.target).join(&self.archive)
+    }
+}
+
+/// Change列挙型
+/// 2つのアーカイブ(スナップショット)間のファイルの差分
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    /// 追加されたファイル (新しいアーカイブのエントリ)
+    Added(CatalogEntry),
+    /// 削除されたファイル (古いアーカイブのエントリ)
+    Removed(CatalogEntry),
+    /// 内容が変更されたファイル (古いアーカイブのエントリ、新しいアーカイブのエントリ)
+    Modified(CatalogEntry, CatalogEntry),
+}
+
+/// Segment構造体
+/// カタログのうち、1つのアーカイブ(スナップショット)のエントリを格納している範囲
+/// 範囲内のエントリはアーカイブ内のパスの順に並べるため、パスから二分探索できる。
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct Segment {
+    archive: String,
+    created: i64,
+    /// 開始位置(バイト)
+    start: u64,
+    /// 終了位置(バイト)
+    end: u64,
+}
+
+/// Meta構造体
+/// バックアップ対象ごとの、アーカイブごとのカタログ内の範囲と、最も新しいアーカイブのエントリ
+#[derive(Serialize, Deserialize, Default)]
+struct Meta {
+    segments: Vec<Segment>,
+    latest: Vec<CatalogEntry>,
+}
+
+impl Meta {
+    /// 読み込む。記録がない、もしくは旧形式や壊れている場合は、カタログを並べ替えて書き直す。
+    /// カタログをロックしたうえで呼び出す。
+    fn open(catalog: &Path) -> Result<Self> {
+        let path = catalog.with_extension(META_EXT);
+        if let Ok(data) = fs::read(&path) {
+            if let Ok(meta) = serde_json::from_slice(&data) {
+                return Ok(meta);
+            }
+        }
+        if !catalog.exists() {
+            return Ok(Meta::default());
+        }
+        let meta = write_file(catalog, read_file(catalog)?)?;
+        meta.save(&path)?;
+        Ok(meta)
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(self)?).context(ErrorKind::Io, &tmp)?;
+        fs::rename(&tmp, path).context(ErrorKind::Io, path)
+    }
+
+    fn segment(&self, archive: &str) -> Option<&Segment> {
+        self.segments.iter().find(|s| s.archive == archive)
+    }
+}
+
+/// バックアップ対象のカタログのパスを取得する。
+fn catalog_path(state_dir: &Path, target: &Path) -> PathBuf {
+    state_dir.join(CATALOG_DIR_NAME).join(format!("{}.{}", layout::target_id(target), CATALOG_EXT))
+}
+
+/// バックアップ対象のカタログの、アーカイブごとの範囲などを記録するパスを取得する。
+fn meta_path(state_dir: &Path, target: &Path) -> PathBuf {
+    catalog_path(state_dir, target).with_extension(META_EXT)
+}
+
+/// カタログの読み書きを直列化する。
+/// ワーカースレッドのほか、backupfs-client の rebuild-catalog とも重ならないよう、ロックファイルもロックする。
+fn lock(state_dir: &Path) -> Result<(MutexGuard<'static, ()>, FileLock)> {
+    let guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    fs::create_dir_all(state_dir).context(ErrorKind::Io, state_dir)?;
+    let path = state_dir.join(LOCK_FILE_NAME);
+    let file = FileLock::acquire(&path).context(ErrorKind::Io, &path)?;
+    Ok((guard, file))
+}
+
+/// バックアップ対象のカタログを読み込む。
+pub fn read<P: AsRef<Path>>(state_dir: P, target: P) -> Result<Vec<CatalogEntry>> {
+    select(state_dir.as_ref(), Some(target.as_ref()), |_| true)
+}
+
+/// すべてのバックアップ対象のカタログを読み込む。
+pub fn read_all<P: AsRef<Path>>(state_dir: P) -> Result<Vec<CatalogEntry>> {
+    select(state_dir.as_ref(), None, |_| true)
+}
+
+/// カタログが空かどうか
+pub fn is_empty<P: AsRef<Path>>(state_dir: P) -> bool {
+    let dir = state_dir.as_ref().join(CATALOG_DIR_NAME);
+    let files = match fs::read_dir(&dir) {
+        Ok(files) => files,
+        Err(_) => return true,
+    };
+    !files.filter_map(|e| e.ok())
+        .filter(|e| e.path().extension().map(|ext| ext == CATALOG_EXT).unwrap_or(false))
+        .any(|e| e.metadata().map(|m| m.len() > 0).unwrap_or(false))
+}
+
+/// カタログから、条件(filter)に一致するエントリを取得する。
+/// target を指定した場合は、そのバックアップ対象のカタログのみを読み込む。
+/// 一致しないエントリは保持せずに読み進める。
+pub fn select<F: FnMut(&CatalogEntry) -> bool>(state_dir: &Path, target: Option<&Path>, mut filter: F) -> Result<Vec<CatalogEntry>> {
+    let catalogs = match target {
+        Some(target) => vec![catalog_path(state_dir, target)],
+        None => {
+            let dir = state_dir.join(CATALOG_DIR_NAME);
+            if !dir.is_dir() {
+                return Ok(Vec::new());
+            }
+            let mut catalogs = Vec::new();
+            for entry in fs::read_dir(&dir).context(ErrorKind::Io, &dir)? {
+                let path = entry.context(ErrorKind::Io, &dir)?.path();
+                if path.extension().map(|e| e == CATALOG_EXT).unwrap_or(false) {
+                    catalogs.push(path);
+                }
+            }
+            catalogs
+        },
+    };
+
+    let _lock = lock(state_dir)?;
+    let mut entries = Vec::new();
+    for path in &catalogs {
+        let meta = Meta::open(path)?;
+        entries.extend(read_entries(path, &meta, &mut filter)?);
+    }
+    Ok(entries)
+}
+
+/// バックアップ対象内のファイル(アーカイブ内の相対パス)を含むアーカイブのエントリを、作成日時の順に取得する。
+/// アーカイブごとにパスから二分探索するため、カタログ全体は読み込まない。
+pub fn history<P: AsRef<Path>>(state_dir: P, target: P, inner: &str) -> Result<Vec<CatalogEntry>> {
+    let path = catalog_path(state_dir.as_ref(), target.as_ref());
+    let _lock = lock(state_dir.as_ref())?;
+    let meta = Meta::open(&path)?;
+    if meta.segments.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut reader = BufReader::new(File::open(&path).context(ErrorKind::Io, &path)?);
+    let mut entries = Vec::new();
+    for segment in &meta.segments {
+        if let Some(entry) = search_segment(&mut reader, segment, inner).context(ErrorKind::Io, &path)? {
+            entries.push(entry);
+        }
+    }
+    entries.sort_by(|a, b| (a.created, &a.archive).cmp(&(b.created, &b.archive)));
+    Ok(entries)
+}
+
+/// 2つのアーカイブ(スナップショット)間で、追加、削除、内容が変更されたファイルをパスの順に取得する。
+/// to を指定しない場合は最新のアーカイブと比較する。
+pub fn diff<P: AsRef<Path>>(state_dir: P, target: P, from: &str, to: Option<&str>) -> Result<Vec<Change>> {
+    let path = catalog_path(state_dir.as_ref(), target.as_ref());
+    let _lock = lock(state_dir.as_ref())?;
+    let meta = Meta::open(&path)?;
+    let segment = |name: &str| meta.segment(name).ok_or_else(|| {
+        Error::new(ErrorKind::InvalidArgument, format!("archive is not in the catalog: {}", name)).with_path(&path)
+    });
+    let from = segment(from)?;
+    let to = match to {
+        Some(to) => segment(to)?,
+        None => meta.segments.iter().max_by_key(|s| (s.created, &s.archive)).unwrap_or(from),
+    };
+
+    let mut reader = BufReader::new(File::open(&path).context(ErrorKind::Io, &path)?);
+    let old = read_segment(&mut reader, from).context(ErrorKind::Io, &path)?;
+    let new = read_segment(&mut reader, to).context(ErrorKind::Io, &path)?;
+    // いずれもパスの順に並んでいるため、先頭から突き合わせる。
+    let mut changes = Vec::new();
+    let (mut old, mut new) = (old.into_iter().peekable(), new.into_iter().peekable());
+    loop {
+        let order = match (old.peek(), new.peek()) {
+            (Some(a), Some(b)) => a.path.cmp(&b.path),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => break,
+        };
+        match order {
+            Ordering::Less => changes.push(Change::Removed(old.next().unwrap())),
+            Ordering::Greater => changes.push(Change::Added(new.next().unwrap())),
+            Ordering::Equal => {
+                let (a, b) = (old.next().unwrap(), new.next().unwrap());
+                if a.hash != b.hash {
+                    changes.push(Change::Modified(a, b));
+                }
+            },
+        }
+    }
+    Ok(changes)
+}
+
+/// カタログのうち、記録されている範囲のエントリを読み込む。
+fn read_entries<F: FnMut(&CatalogEntry) -> bool>(path: &Path, meta: &Meta, mut filter: F) -> Result<Vec<CatalogEntry>> {
+    if meta.segments.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut reader = BufReader::new(File::open(path).context(ErrorKind::Io, path)?);
+    let mut entries = Vec::new();
+    for segment in &meta.segments {
+        for entry in read_segment(&mut reader, segment).context(ErrorKind::Io, path)? {
+            if filter(&entry) {
+                entries.push(entry);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// カタログのうち、範囲内のエントリを読み込む。
+fn read_segment(reader: &mut BufReader<File>, segment: &Segment) -> io::Result<Vec<CatalogEntry>> {
+    reader.seek(SeekFrom::Start(segment.start))?;
+    let mut entries = Vec::new();
+    for line in reader.by_ref().take(segment.end - segment.start).lines() {
+        entries.push(serde_json::from_str(&line?)?);
+    }
+    Ok(entries)
+}
+
+/// カタログの範囲内から、アーカイブ内のパスが一致するエントリを二分探索する。
+fn search_segment(reader: &mut BufReader<File>, segment: &Segment, inner: &str) -> io::Result<Option<CatalogEntry>> {
+    // lo, hi は常に行の先頭を指し、パスが inner 以上の最初の行は lo と hi の間にある。
+    let (mut lo, mut hi) = (segment.start, segment.end);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        let start = if mid == lo { lo } else { line_start(reader, mid)? };
+        let start = if start < hi { start } else { lo };
+        let (entry, end) = read_line_at(reader, start)?;
+        if entry.path.as_str() < inner {
+            lo = end;
+        } else {
+            hi = start;
+        }
+    }
+    if lo < segment.end {
+        let (entry, _) = read_line_at(reader, lo)?;
+        if entry.path == inner {
+            return Ok(Some(entry));
+        }
+    }
+    Ok(None)
+}
+
+/// pos 以降で最初の行の先頭の位置を取得する。
+fn line_start(reader: &mut BufReader<File>, pos: u64) -> io::Result<u64> {
+    reader.seek(SeekFrom::Start(pos - 1))?;
+    let n = reader.read_until(b'\n', &mut Vec::new())?;
+    Ok(pos - 1 + n as u64)
+}
+
+/// pos から始まる行のエントリと、次の行の先頭の位置を取得する。
+fn read_line_at(reader: &mut BufReader<File>, pos: u64) -> io::Result<(CatalogEntry, u64)> {
+    reader.seek(SeekFrom::Start(pos))?;
+    let mut line = String::new();
+    let n = reader.read_line(&mut line)?;
+    Ok((serde_json::from_str(&line)?, pos + n as u64))
+}
+
+/// カタログを読み込む。(旧形式のカタログの並べ替えに利用する)
+/// 読み込めない行は読み飛ばす。
+fn read_file(path: &Path) -> Result<Vec<CatalogEntry>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let file = File::open(path).context(ErrorKind::Io, path)?;
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.context(ErrorKind::Io, path)?;
+        if let Ok(entry) = serde_json::from_str(&line) {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// カタログを書き込み、アーカイブごとの範囲を返却する。
+/// アーカイブの作成日時、アーカイブ内のパスの順に並べ替えて書き込む。
+/// 途中まで書き込まれたファイルが残らないよう、一時ファイルから置き換える。
+fn write_file(path: &Path, mut entries: Vec<CatalogEntry>) -> Result<Meta> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).context(ErrorKind::Io, dir)?;
+    }
+    entries.sort_by(|a, b| (a.created, &a.archive, &a.path).cmp(&(b.created, &b.archive, &b.path)));
+    let tmp = path.with_extension(format!("{}.tmp", CATALOG_EXT));
+    let mut buf = Vec::new();
+    let mut segments: Vec<Segment> = Vec::new();
+    for entry in &entries {
+        let start = buf.len() as u64;
+        serde_json::to_writer(&mut buf, entry)?;
+        buf.push(b'\n');
+        let end = buf.len() as u64;
+        match segments.last_mut() {
+            Some(segment) if segment.archive == entry.archive => segment.end = end,
+            _ => segments.push(Segment { archive: entry.archive.clone(), created: entry.created, start, end }),
+        }
+    }
+    fs::write(&tmp, &buf).context(ErrorKind::Io, &tmp)?;
+    fs::rename(&tmp, path).context(ErrorKind::Io, path)?;
+
+    let latest = match segments.last() {
+        Some(segment) => entries.iter().filter(|e| e.archive == segment.archive).cloned().collect(),
+        None => Vec::new(),
+    };
+    Ok(Meta { segments, latest })
+}
+
+/// 作成したアーカイブ(スナップショット)のファイルをカタログへ追加する。
+/// 既に追加されているアーカイブの場合は置き換える。
+/// 追加のたびにカタログ全体を読み込まないよう、アーカイブごとの範囲と最新のアーカイブのエントリを別に記録し、
+/// 通常はパスの順に並べて末尾へ追記する。(置き換える場合のみカタログ全体を書き直す)
+/// 追加したファイルの数を返却する。
+pub fn index<P: AsRef<Path>>(state_dir: P, target: P, archive: P) -> Result<usize> {
+    let (state_dir, target, archive) = (state_dir.as_ref(), target.as_ref(), archive.as_ref());
+    let path = catalog_path(state_dir, target);
+    let meta_path = meta_path(state_dir, target);
+    let _lock = lock(state_dir)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).context(ErrorKind::Io, dir)?;
+    }
+
+    let mut meta = Meta::open(&path)?;
+    let name = archive_name(archive);
+
+    if meta.segment(&name).is_some() {
+        let entries = read_entries(&path, &meta, |_| true)?;
+        let created = created(archive);
+        // スナップショットの変更のないファイル(ハードリンク)は、直前のアーカイブのハッシュ値を再利用する。
+        let latest = entries.iter()
+            .filter(|e| e.archive != name && e.created <= created)
+            .map(|e| e.created)
+            .max();
+        let previous: HashMap<&str, &CatalogEntry> = entries.iter()
+            .filter(|e| Some(e.created) == latest)
+            .map(|e| (e.path.as_str(), e))
+            .collect();
+        let scanned = scan(target, archive, &previous)?;
+        let count = scanned.len();
+        let mut rest: Vec<CatalogEntry> = entries.iter().filter(|e| e.archive != name).cloned().collect();
+        rest.extend(scanned);
+        write_file(&path, rest)?.save(&meta_path)?;
+        return Ok(count);
+    }
+
+    // 変更のないファイルは、最新のアーカイブのハッシュ値を再利用する。
+    let mut scanned = {
+        let previous = meta.latest.iter().map(|e| (e.path.as_str(), e)).collect();
+        scan(target, archive, &previous)?
+    };
+    scanned.sort_by(|a, b| a.path.cmp(&b.path));
+    let count = scanned.len();
+    let mut buf = Vec::new();
+    for entry in &scanned {
+        serde_json::to_writer(&mut buf, entry)?;
+        buf.push(b'\n');
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(&path).context(ErrorKind::Io, &path)?;
+    let start = file.metadata().context(ErrorKind::Io, &path)?.len();
+    file.write_all(&buf).context(ErrorKind::Io, &path)?;
+
+    meta.segments.push(Segment { archive: name, created: created(archive), start, end: start + buf.len() as u64 });
+    let latest_created = meta.latest.first().map(|e| e.created);
+    if latest_created.map(|t| t <= created(archive)).unwrap_or(true) {
+        meta.latest = scanned;
+    }
+    meta.save(&meta_path)?;
+    Ok(count)
+}
+
+/// 削除したアーカイブ(スナップショット)のファイルをカタログから取り除く。
+pub fn remove<P: AsRef<Path>>(state_dir: P, target: P, archives: &[String]) -> Result<()> {
+    let (state_dir, target) = (state_dir.as_ref(), target.as_ref());
+    let path = catalog_path(state_dir, target);
+    let _lock = lock(state_dir)?;
+    if archives.is_empty() || !path.exists() {
+        return Ok(());
+    }
+    let meta = Meta::open(&path)?;
+    let entries = read_entries(&path, &meta, |e| !archives.contains(&e.archive))?;
+    write_file(&path, entries)?.save(&meta_path(state_dir, target))
+}
+
+/// バックアップ先の既存のアーカイブ(スナップショット)をすべて読み込み、カタログを作り直す。
+/// ミラーは更新し続けるため対象外とする。
+/// 実行中の backupfsd による追加と重ならないよう、作り直す間はカタログをロックする。
+/// 読み込んだアーカイブの数と、ファイルの数を返却する。
+pub fn rebuild<P: AsRef<Path>>(state_dir: P, destination: P) -> Result<(usize, usize)> {
+    let (state_dir, destination) = (state_dir.as_ref(), destination.as_ref());
+    let _lock = lock(state_dir)?;
+
+    let catalog_dir = state_dir.join(CATALOG_DIR_NAME);
+    if catalog_dir.is_dir() {
+        fs::remove_dir_all(&catalog_dir).context(ErrorKind::Io, &catalog_dir)?;
+    }
+
+    let mut archives = 0;
+    let mut files = 0;
+    for entry in fs::read_dir(destination).context(ErrorKind::Io, destination)? {
+        let dir = entry.context(ErrorKind::Io, destination)?.path();
+        let target = match layout::read_target(&dir) {
+            Some(target) => target,
+            None => continue,
+        };
+        let mut list = retention::list(&dir, Mode::Zip).context(ErrorKind::Io, &dir)?;
+        list.extend(retention::list(&dir, Mode::Snapshot).context(ErrorKind::Io, &dir)?);
+        naming::sort_by_created(&mut list);
+
+        let mut entries: Vec<CatalogEntry> = Vec::new();
+        let mut previous: Vec<CatalogEntry> = Vec::new();
+        for archive in &list {
+            let scanned = {
+                let previous = previous.iter().map(|e| (e.path.as_str(), e)).collect();
+                match scan(&target, archive, &previous) {
+                    Ok(scanned) => scanned,
+                    Err(err) => {
+                        error!("{:?}", err);
+                        continue;
+                    },
+                }
+            };
+            info!("catalog: {:?} {} files", archive, scanned.len());
+            archives += 1;
+            files += scanned.len();
+            entries.extend(scanned.iter().cloned());
+            previous = scanned;
+        }
+        write_file(&catalog_path(state_dir, &target), entries)?.save(&meta_path(state_dir, &target))?;
+    }
+    Ok((archives, files))
+}
+
+/// アーカイブ(スナップショット)のファイルを読み込む。
+/// previous には直前のアーカイブのエントリを指定し、スナップショットでサイズと更新時刻が同じファイル、
+/// ZIPでサイズ、更新時刻とCRC-32が同じエントリは、ハッシュ値を再利用する。
+/// (ZIPは更新時刻が2秒単位のため、CRC-32も比較し、再利用しないエントリのみ展開する)
+fn scan(target: &Path, archive: &Path, previous: &HashMap<&str, &CatalogEntry>) -> Result<Vec<CatalogEntry>> {
+    let name = archive_name(archive);
+    let created = created(archive);
+    let mut entries = Vec::new();
+
+    if archive.is_dir() {
+        for entry in WalkDir::new(archive).min_depth(1) {
+            let entry = entry.map_err(io::Error::from).context(ErrorKind::Io, archive)?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let info = entry.metadata().map_err(io::Error::from).context(ErrorKind::Io, entry.path())?;
+            let path = entry.path().strip_prefix(archive).unwrap_or(entry.path()).to_string_lossy().to_string();
+            let mtime = info.modified().ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or(0);
+            let hash = match previous.get(path.as_str()) {
+                Some(prev) if prev.size == info.len() && prev.mtime == mtime => prev.hash.clone(),
+                _ => md5_hex(File::open(entry.path()).context(ErrorKind::Io, entry.path())?)
+                    .context(ErrorKind::Io, entry.path())?,
+            };
+            entries.push(CatalogEntry {
+                target: target.to_path_buf(), archive: name.clone(), created, path, size: info.len(), mtime, hash,
+                crc32: None,
+            });
+        }
+    } else {
+        let file = File::open(archive).context(ErrorKind::Io, archive)?;
+        let mut zip = ZipArchive::new(file)?;
+        for i in 0..zip.len() {
+            let mut file = zip.by_index(i)?;
+            if file.name().ends_with('/') {
+                continue;
+            }
+            let path = file.name().to_string();
+            let size = file.size();
+            let mtime = dos_time(&file.last_modified());
+            let crc32 = Some(file.crc32());
+            let hash = match previous.get(path.as_str()) {
+                Some(prev) if prev.size == size && prev.mtime == mtime && prev.crc32 == crc32 => prev.hash.clone(),
+                _ => md5_hex(&mut file).context(ErrorKind::Io, archive)?,
+            };
+            entries.push(CatalogEntry {
+                target: target.to_path_buf(), archive: name.clone(), created, path, size, mtime, hash, crc32,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// アーカイブ(スナップショット)の名前を取得する。
+fn archive_name(archive: &Path) -> String {
+    archive.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default()
+}
+
+/// アーカイブの作成日時(UNIX時間)を取得する。
+fn created(archive: &Path) -> i64 {
+    naming::created_at(archive).map(|t| t.timestamp()).unwrap_or(0)
+}
+
+/// ZIPのエントリの更新時刻(ローカル時刻)をUNIX時間へ変換する。
+fn dos_time(tm: &::time::Tm) -> i64 {
+    NaiveDate::from_ymd_opt(tm.tm_year + 1900, (tm.tm_mon + 1) as u32, tm.tm_mday as u32)
+        .and_then(|date| date.and_hms_opt(tm.tm_hour as u32, tm.tm_min as u32, tm.tm_sec as u32))
+        .and_then(|t| Local.from_local_datetime(&t).earliest())
+        .map(|t| t.timestamp())
+        .unwrap_or(0)
+}
+
+/// 内容のmd5ハッシュ値(16進数)を計算する。
+fn md5_hex<R: Read>(mut reader: R) -> io::Result<String> {
+    let mut md5 = Md5::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        md5.input(&buf[..n]);
+    }
+    Ok(md5.result_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_index() {
+        let root = env::temp_dir().join(format!("backupfs-catalog-test-{}", process::id()));
+        let state_dir = root.join("state");
+        let first = root.join("20181018T000000Z-000001");
+        let second = root.join("20181019T000000Z-000002");
+        fs::create_dir_all(first.join("sub")).unwrap();
+        fs::write(first.join("sub/a.txt"), b"a").unwrap();
+        fs::create_dir_all(&second).unwrap();
+        fs::write(second.join("b.txt"), b"b").unwrap();
+
+        let target = PathBuf::from("/src");
+        assert_eq!(1, index(&state_dir, &target, &first).unwrap());
+        assert_eq!(1, index(&state_dir, &target, &second).unwrap());
+        // 同じアーカイブは置き換える。
+        assert_eq!(1, index(&state_dir, &target, &first).unwrap());
+
+        let entries = read(&state_dir, &target).unwrap();
+        assert_eq!(2, entries.len());
+        let a = entries.iter().find(|e| e.path == "sub/a.txt").unwrap();
+        assert_eq!("20181018T000000Z-000001", a.archive);
+        assert_eq!("0cc175b9c0f1b6a831c399e269772661", a.hash);
+        assert_eq!(Utc.ymd(2018, 10, 18).and_hms(0, 0, 0), a.created_at());
+
+        remove(&state_dir, &target, &["20181018T000000Z-000001".to_string()]).unwrap();
+        assert_eq!(1, read_all(&state_dir).unwrap().len());
+
+        // 索引済みのアーカイブの記録がない場合(旧形式)はカタログから作り直す。
+        fs::remove_file(meta_path(&state_dir, &target)).unwrap();
+        assert_eq!(1, index(&state_dir, &target, &second).unwrap());
+        assert_eq!(1, read(&state_dir, &target).unwrap().len());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_history_diff() {
+        let root = env::temp_dir().join(format!("backupfs-catalog-history-test-{}", process::id()));
+        let state_dir = root.join("state");
+        let first = root.join("20181018T000000Z-000001");
+        let second = root.join("20181019T000000Z-000002");
+        for archive in &[&first, &second] {
+            fs::create_dir_all(archive.join("sub")).unwrap();
+            for i in 0..20 {
+                fs::write(archive.join(format!("sub/{:02}.txt", i)), format!("{}", i)).unwrap();
+            }
+        }
+        fs::write(first.join("removed.txt"), b"r").unwrap();
+        fs::write(second.join("added.txt"), b"a").unwrap();
+        fs::write(second.join("sub/07.txt"), b"changed").unwrap();
+
+        let target = PathBuf::from("/src");
+        index(&state_dir, &target, &second).unwrap();
+        index(&state_dir, &target, &first).unwrap();
+        // アーカイブごとにパスから二分探索する。
+        for i in 0..20 {
+            let path = format!("sub/{:02}.txt", i);
+            let found: Vec<String> = history(&state_dir, &target, &path).unwrap().into_iter().map(|e| e.archive).collect();
+            assert_eq!(vec!["20181018T000000Z-000001", "20181019T000000Z-000002"], found);
+        }
+        assert_eq!(1, history(&state_dir, &target, "added.txt").unwrap().len());
+        assert!(history(&state_dir, &target, "sub/99.txt").unwrap().is_empty());
+        assert!(history(&state_dir, &target, "").unwrap().is_empty());
+
+        let changes = diff(&state_dir, &target, "20181018T000000Z-000001", None).unwrap();
+        let paths: Vec<(char, &str)> = changes.iter().map(|c| match c {
+            Change::Added(e) => ('A', e.path.as_str()),
+            Change::Removed(e) => ('D', e.path.as_str()),
+            Change::Modified(_, e) => ('M', e.path.as_str()),
+        }).collect();
+        assert_eq!(vec![('A', "added.txt"), ('D', "removed.txt"), ('M', "sub/07.txt")], paths);
+        assert!(diff(&state_dir, &target, "20181018T000000Z-000001", Some("x")).is_err());
+
+        // 旧形式(アーカイブの範囲の記録がなく、パスの順に並んでいない)のカタログは並べ替えて書き直す。
+        let path = catalog_path(&state_dir, &target);
+        let mut entries = read_file(&path).unwrap();
+        entries.reverse();
+        let mut buf = Vec::new();
+        for entry in &entries {
+            serde_json::to_writer(&mut buf, entry).unwrap();
+            buf.push(b'\n');
+        }
+        fs::write(&path, &buf).unwrap();
+        fs::write(meta_path(&state_dir, &target), br#"{"archives":[],"latest":[]}"#).unwrap();
+        assert_eq!(2, history(&state_dir, &target, "sub/07.txt").unwrap().len());
+        assert_eq!(42, read(&state_dir, &target).unwrap().len());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_index_zip() {
+        use archiver::{Archiver, ZIP};
+
+        let root = env::temp_dir().join(format!("backupfs-catalog-zip-test-{}", process::id()));
+        let state_dir = root.join("state");
+        let src = root.join("src");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("a.txt"), b"a").unwrap();
+        fs::write(src.join("b.txt"), b"b").unwrap();
+        let first = root.join("20181018T000000Z-000001.zip");
+        ZIP.archive(&src, &first).unwrap();
+        fs::write(src.join("b.txt"), b"changed").unwrap();
+        let second = root.join("20181019T000000Z-000002.zip");
+        ZIP.archive(&src, &second).unwrap();
+
+        assert_eq!(2, index(&state_dir, &src, &first).unwrap());
+        assert_eq!(2, index(&state_dir, &src, &second).unwrap());
+        let entries = read(&state_dir, &src).unwrap();
+        let hash = |archive: &Path, path: &str| entries.iter()
+            .find(|e| e.archive == archive_name(archive) && e.path == path)
+            .map(|e| e.hash.clone())
+            .unwrap();
+        assert_eq!("0cc175b9c0f1b6a831c399e269772661", hash(&second, "a.txt"));
+        assert_eq!(hash(&first, "a.txt"), hash(&second, "a.txt"));
+        assert_ne!(hash(&first, "b.txt"), hash(&second, "b.txt"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
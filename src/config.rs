@@ -0,0 +1,193 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use dirs;
+use toml;
+
+use anomaly::MassChangeConfig;
+use archiver::Mode;
+use catalog::CatalogConfig;
+use destination::S3Config;
+use guard::FileDropConfig;
+use hooks::HookConfig;
+use metrics::MetricsConfig;
+use naming::Naming;
+use notify::NotifyConfig;
+use pool::ConcurrencyConfig;
+use protect::{Attribute, ProtectConfig};
+use result::{ErrorKind, Result, ResultExt};
+use retention::RetentionConfig;
+use retry::RetryConfig;
+use scheduler::{CatchUp, Schedule};
+use space::SpaceConfig;
+use throttle::ThrottleConfig;
+
+/// 設定ファイルのデフォルトのファイル名
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// 終了時に実行中のバックアップの完了を待つデフォルトの時間(秒)
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 60;
+
+/// Config構造体
+/// backupfsd / backupfs-client が共通で利用する設定ファイル(toml)の内容を格納する。
+/// 設定ファイルが存在しない場合は、すべてデフォルト値となる。
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Config {
+    /// バックアップ先のディレクトリ
+    pub destination: Option<PathBuf>,
+    /// アーカイブ名のテンプレート (デフォルト: "{time}-{unique}")
+    pub archive_name: Option<String>,
+    /// 変更検知の間隔(秒) (デフォルト: 5)
+    pub check_interval: Option<u64>,
+    /// 終了時に実行中のバックアップの完了を待つ時間(秒) (デフォルト: 60)
+    /// 超えた場合はバックアップを中断して終了する。
+    pub shutdown_timeout: Option<u64>,
+    /// S3互換オブジェクトストレージへのアップロード設定
+    pub s3: Option<S3Config>,
+    /// バックアップに失敗した際の再試行の設定
+    pub retry: RetryConfig,
+    /// バックアップ対象を並列に処理する際の設定
+    pub concurrency: ConcurrencyConfig,
+    /// バックアップ先の空き容量の設定
+    pub space: SpaceConfig,
+    /// バックアップ対象のファイル数が急減した場合の設定
+    pub file_drop: FileDropConfig,
+    /// 暗号化などによる大量の変更を検知する設定
+    pub mass_change: MassChangeConfig,
+    /// メトリクスを公開するHTTPサーバーの設定
+    pub metrics: MetricsConfig,
+    /// バックアップの失敗などの通知の設定
+    pub notify: NotifyConfig,
+    /// すべてのバックアップ対象に共通のアーカイブの保持数の設定
+    pub retention: RetentionConfig,
+    /// 作成したアーカイブを削除や改変から保護する設定
+    pub protect: ProtectConfig,
+    /// アーカイブ内のファイルのカタログの設定
+    pub catalog: CatalogConfig,
+    /// 帯域、優先度の制限と一時停止の設定
+    pub throttle: ThrottleConfig,
+    /// すべてのバックアップ対象に共通のフックコマンドの設定
+    pub hooks: HookConfig,
+    /// バックアップ対象ごとの設定 (`[[target]]`)
+    #[serde(rename = "target")]
+    pub targets: Vec<TargetConfig>,
+}
+
+/// TargetConfig構造体
+/// バックアップ対象ごとの設定
+/// pathはclientで登録したパスと一致させる。
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TargetConfig {
+    /// バックアップ対象のパス
+    pub path: PathBuf,
+    /// バックアップ方式
+    pub mode: Mode,
+    /// ミラー時に、バックアップ対象から削除されたファイルをゴミ箱へ移動するかどうか
+    pub mirror_delete: bool,
+    /// フックコマンドの設定 (設定されていない項目は全体の設定となる)
+    pub hooks: HookConfig,
+    /// 変更検知を行うかどうか (デフォルト: true)
+    /// false の場合は schedule のみでバックアップする。
+    pub watch: bool,
+    /// 変更検知の間隔(秒) (設定されていない場合は全体の設定となる)
+    pub check_interval: Option<u64>,
+    /// 変更の有無によらずバックアップを行うスケジュール (cron形式、もしくは "@every 6h")
+    pub schedule: Option<String>,
+    /// デーモンの停止中に実行されなかったスケジュールの扱い
+    pub catch_up: CatchUp,
+    /// アーカイブの保持数の設定 (設定されていない項目は全体の設定となる)
+    pub retention: RetentionConfig,
+}
+
+impl Default for TargetConfig {
+    fn default() -> Self {
+        TargetConfig {
+            path: PathBuf::new(),
+            mode: Mode::default(),
+            mirror_delete: false,
+            hooks: HookConfig::default(),
+            watch: true,
+            check_interval: None,
+            schedule: None,
+            catch_up: CatchUp::default(),
+            retention: RetentionConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// 状態ディレクトリ(filedbの格納先)のパスを取得する。
+    pub fn state_dir() -> PathBuf {
+        dirs::home_dir().unwrap_or_default().join(".backupfs")
+    }
+
+    /// 設定ファイルのデフォルトのパスを取得する。
+    pub fn default_path() -> PathBuf {
+        Config::state_dir().join(CONFIG_FILE_NAME)
+    }
+
+    /// 設定ファイルを読み込む。
+    /// ファイルが存在しない場合はデフォルト値を返却する。
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            debug!("config file not found: {:?}", path);
+            return Ok(Config::default());
+        }
+        let mut buf = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut buf))
+            .context(ErrorKind::InvalidConfig, path)?;
+        let config: Config = toml::from_str(&buf).context(ErrorKind::InvalidConfig, path)?;
+        config.notify.validate().map_err(|err| err.with_path(path))?;
+        if let Some(ref template) = config.archive_name {
+            Naming::validate(template).map_err(|err| err.with_path(path))?;
+        }
+        for target in &config.targets {
+            if let Some(ref schedule) = target.schedule {
+                Schedule::parse(schedule).context(ErrorKind::InvalidConfig, path)?;
+            }
+        }
+        if config.protect.attribute != Attribute::None && config.targets.iter().any(|t| t.mode == Mode::Snapshot) {
+            warn!("protect.attribute {:?} prevents hard links, snapshot targets will copy every file",
+                  config.protect.attribute);
+        }
+        Ok(config)
+    }
+
+    /// バックアップ対象の設定を取得する。
+    /// 設定されていない場合はデフォルト値となる。
+    /// フックコマンド、保持数は全体の設定と合わせたものとなる。
+    pub fn target<P: AsRef<Path>>(&self, path: P) -> TargetConfig {
+        let path = path.as_ref();
+        let mut target = self.targets.iter()
+            .find(|t| t.path == path)
+            .cloned()
+            .unwrap_or_else(|| TargetConfig { path: path.to_path_buf(), ..TargetConfig::default() });
+        target.hooks = target.hooks.merge(&self.hooks);
+        target.retention = target.retention.merge(&self.retention);
+        target
+    }
+
+    /// アーカイブ名の生成に利用するNaming構造体を取得する。
+    pub fn naming(&self) -> Naming {
+        self.archive_name.clone().map(Naming::new).unwrap_or_default()
+    }
+
+    /// 終了時に実行中のバックアップの完了を待つ時間を取得する。
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT))
+    }
+
+    /// バックアップ先のディレクトリを取得する。
+    /// 設定されていない場合は ~/.backupfs_archive となる。
+    pub fn destination(&self) -> PathBuf {
+        self.destination.clone().unwrap_or_else(|| {
+            dirs::home_dir().unwrap_or_default().join(".backupfs_archive")
+        })
+    }
+}
@@ -0,0 +1,33 @@
+use std::path::Path;
+use result::Result;
+
+mod s3;
+pub use self::s3::{S3, S3Config};
+
+/// Object構造体
+/// バックアップ先に保存されているアーカイブ1件分の情報
+#[derive(Clone, Debug, PartialEq)]
+pub struct Object {
+    /// バックアップ先のルートからの相対キー
+    pub key: String,
+    /// サイズ(バイト)
+    pub size: u64,
+    /// 最終更新日時(バックエンドが返却する文字列のまま)
+    pub last_modified: String,
+}
+
+/// Destinationトレイト
+/// ローカルに作成したアーカイブの転送先(リモートのバックエンド)を定義するトレイト
+/// Monitor構造体に設定すると、アーカイブ作成後に転送が行われる。
+/// 転送はワーカースレッドから並列に呼び出される。
+pub trait Destination: Send + Sync {
+    /// アーカイブの転送関数
+    /// 1つ目がローカルのアーカイブファイル、2つ目が転送先のキーとなる。
+    fn put(&self, src: &Path, key: &str) -> Result<()>;
+
+    /// 前方一致するキーのアーカイブ一覧を取得する。
+    fn list(&self, prefix: &str) -> Result<Vec<Object>>;
+
+    /// アーカイブを削除する。
+    fn delete(&self, key: &str) -> Result<()>;
+}
@@ -0,0 +1,185 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+
+use chrono::prelude::*;
+use zip::ZipArchive;
+use zip::result::ZipError;
+
+use archiver::Mode;
+use catalog;
+use layout;
+use naming;
+use result::{Error, ErrorKind, Result, ResultExt};
+use retention;
+
+/// Located構造体
+/// バックアップ対象内のパスに対応する、アーカイブ(スナップショット)内のファイル
+#[derive(Clone, Debug, PartialEq)]
+pub struct Located {
+    /// バックアップ対象のパス
+    pub target: PathBuf,
+    /// アーカイブ(スナップショット)のパス
+    pub archive: PathBuf,
+    /// アーカイブ内の相対パス
+    pub inner: String,
+}
+
+/// パスを含むバックアップ対象と、アーカイブ内の相対パスを取得する。
+pub fn resolve(destination: &Path, path: &Path) -> Result<(PathBuf, String)> {
+    let target = find_target(destination, path)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidArgument, "not in any backup target").with_path(path))?;
+    // ファイルの場合はファイル名で格納されている。
+    let inner = if path == target {
+        path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default()
+    } else {
+        path.strip_prefix(&target).unwrap_or(path).to_string_lossy().replace('\\', "/")
+    };
+    Ok((target, inner))
+}
+
+/// バックアップ対象内のパスから、at 以前に作成された最新のアーカイブ内のファイルを探す。
+/// カタログに記録されている場合はカタログから、記録されていない場合はアーカイブを新しい順に開いて探す。
+pub fn locate<P: AsRef<Path>>(state_dir: P, destination: P, path: P, at: DateTime<Utc>) -> Result<Located> {
+    let (destination, path) = (destination.as_ref(), path.as_ref());
+    let (target, inner) = resolve(destination, path)?;
+    let at_str = at.to_rfc3339_opts(SecondsFormat::Secs, true);
+    let not_found = || Error::new(ErrorKind::InvalidArgument, format!("no archive contains {} at {}", inner, at_str))
+        .with_path(path);
+
+    let entries = catalog::history(state_dir.as_ref(), &target, &inner)?;
+    let archive = if !entries.is_empty() {
+        entries.iter().rev()
+            .filter(|e| e.created_at() <= at)
+            .map(|e| e.archive_path(destination))
+            .find(|archive| archive.exists())
+    } else {
+        let dir = layout::target_dir(destination, &target);
+        let mut list = retention::list(&dir, Mode::Zip).context(ErrorKind::Io, &dir)?;
+        list.extend(retention::list(&dir, Mode::Snapshot).context(ErrorKind::Io, &dir)?);
+        naming::sort_by_created(&mut list);
+        list.into_iter().rev()
+            .filter(|archive| naming::created_at(archive).map(|t| t <= at).unwrap_or(false))
+            .find(|archive| contains(archive, &inner))
+    };
+    let archive = archive.ok_or_else(not_found)?;
+    Ok(Located { target, archive, inner })
+}
+
+/// バックアップ先に記録されたバックアップ対象のうち、パスを含むもの(最も深いもの)を取得する。
+fn find_target(destination: &Path, path: &Path) -> Option<PathBuf> {
+    fs::read_dir(destination).ok()?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| layout::read_target(e.path()))
+        .filter(|target| path.starts_with(target))
+        .max_by_key(|target| target.components().count())
+}
+
+/// アーカイブ内にファイルが含まれるかどうか
+fn contains(archive: &Path, inner: &str) -> bool {
+    if archive.is_dir() {
+        return safe_join(archive, inner).map(|p| p.is_file()).unwrap_or(false);
+    }
+    File::open(archive).ok()
+        .and_then(|f| ZipArchive::new(f).ok())
+        .map(|mut zip| zip.by_name(inner).is_ok())
+        .unwrap_or(false)
+}
+
+/// スナップショットのディレクトリとアーカイブ内の相対パスを結合する。
+/// ディレクトリの外を指すパスは受け付けない。
+fn safe_join(archive: &Path, inner: &str) -> Option<PathBuf> {
+    let inner = Path::new(inner);
+    if inner.components().any(|c| !matches!(c, Component::Normal(_))) {
+        return None;
+    }
+    Some(archive.join(inner))
+}
+
+/// アーカイブ内のファイルの内容を書き出す。
+/// ZIPの場合も該当するエントリのみを読み込む。
+/// 書き出したバイト数を返却する。
+pub fn copy_entry<W: Write>(archive: &Path, inner: &str, out: &mut W) -> Result<u64> {
+    let not_found = || Error::new(ErrorKind::InvalidArgument, format!("no such file in archive: {}", inner))
+        .with_path(archive);
+    if archive.is_dir() {
+        let path = safe_join(archive, inner).filter(|p| p.is_file()).ok_or_else(not_found)?;
+        let mut file = File::open(&path).context(ErrorKind::Io, &path)?;
+        return io::copy(&mut file, out).context(ErrorKind::Io, &path);
+    }
+    let file = File::open(archive).context(ErrorKind::Io, archive)?;
+    let mut zip = ZipArchive::new(file).context(ErrorKind::Io, archive)?;
+    let mut entry = match zip.by_name(inner) {
+        Ok(entry) => entry,
+        Err(ZipError::FileNotFound) => return Err(not_found()),
+        Err(err) => return Err(Error::from(err).with_path(archive)),
+    };
+    io::copy(&mut entry, out).context(ErrorKind::Io, archive)
+}
+
+/// アーカイブ内のファイルを1件取り出す。
+/// to が既存のディレクトリの場合はその中へ同じファイル名で書き出す。
+/// 既存のファイルは force の場合のみ上書きする。途中で失敗した場合に不完全なファイルが残らないよう、
+/// 一時ファイルへ書き出したのちに置き換える。
+/// 書き出したファイルのパスを返却する。
+pub fn extract<P: AsRef<Path>>(archive: P, inner: &str, to: P, force: bool) -> Result<PathBuf> {
+    let (archive, to) = (archive.as_ref(), to.as_ref());
+    let dest = if to.is_dir() {
+        to.join(Path::new(inner).file_name().unwrap_or_default())
+    } else {
+        to.to_path_buf()
+    };
+    if dest.exists() && !force {
+        return Err(Error::new(ErrorKind::InvalidArgument, "already exists (use --force to overwrite)").with_path(dest));
+    }
+    let tmp = dest.with_file_name(format!(
+        ".{}.backupfs-tmp",
+        dest.file_name().map(|s| s.to_string_lossy().to_string()).unwrap_or_default(),
+    ));
+    let res = File::create(&tmp).context(ErrorKind::Io, &tmp)
+        .and_then(|mut file| copy_entry(archive, inner, &mut file));
+    if let Err(err) = res {
+        let _ = fs::remove_file(&tmp);
+        return Err(err);
+    }
+    fs::rename(&tmp, &dest).context(ErrorKind::Io, &dest)?;
+    Ok(dest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use archiver::{Archiver, ZIP};
+
+    #[test]
+    fn test_locate_and_extract() {
+        let root = env::temp_dir().join(format!("backupfs-extract-test-{}", process::id()));
+        let src = root.join("src");
+        let destination = root.join("dest");
+        fs::create_dir_all(src.join("config")).unwrap();
+        fs::write(src.join("config/app.yml"), b"v1").unwrap();
+        let dir = layout::prepare(&destination, &src).unwrap();
+        ZIP.archive(&src, &dir.join("20181018T000000Z-000001.zip")).unwrap();
+        fs::write(src.join("config/app.yml"), b"v2").unwrap();
+        ZIP.archive(&src, &dir.join("20181019T000000Z-000002.zip")).unwrap();
+
+        // カタログがない場合はアーカイブを開いて探す。
+        let state_dir = root.join("state");
+        let at = Utc.ymd(2018, 10, 18).and_hms(12, 0, 0);
+        let located = locate(&state_dir, &destination, &src.join("config/app.yml"), at).unwrap();
+        assert_eq!(src, located.target);
+        assert_eq!("config/app.yml", located.inner);
+        let mut out = Vec::new();
+        copy_entry(&located.archive, &located.inner, &mut out).unwrap();
+        assert_eq!(b"v1".to_vec(), out);
+
+        let located = locate(&state_dir, &destination, &src.join("config/app.yml"), Utc::now()).unwrap();
+        let extracted = extract(&located.archive, &located.inner, &root, false).unwrap();
+        assert_eq!(b"v2".to_vec(), fs::read(&extracted).unwrap());
+        assert!(extract(&located.archive, &located.inner, &root, false).is_err());
+        assert!(copy_entry(&located.archive, "missing.txt", &mut Vec::new()).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
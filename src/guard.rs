@@ -0,0 +1,155 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use layout;
+
+/// 確認済みの印を格納するディレクトリ名 (状態ディレクトリ内)
+const ACK_DIR_NAME: &str = "ack";
+
+/// バックアップを保留している記録を格納するディレクトリ名 (状態ディレクトリ内)
+const HELD_DIR_NAME: &str = "held";
+
+/// FileDropConfig構造体
+/// バックアップ対象のファイル数が急減した場合(アンマウントや大量削除の疑い)の設定 (`[file_drop]`)
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct FileDropConfig {
+    /// 前回のバックアップからファイル数がこの割合以上減った場合に急減とみなす。(0の場合は確認しない)
+    pub threshold: f64,
+    /// 前回のファイル数がこの数未満の場合は確認しない。
+    pub min_files: u64,
+    /// 急減した場合の動作
+    pub action: DropAction,
+}
+
+impl Default for FileDropConfig {
+    fn default() -> Self {
+        FileDropConfig { threshold: 0.5, min_files: 10, action: DropAction::Skip }
+    }
+}
+
+impl FileDropConfig {
+    /// ファイル数が急減したかどうか
+    pub fn dropped(&self, previous: u64, current: u64) -> bool {
+        if self.threshold <= 0.0 || previous < self.min_files || current >= previous {
+            return false;
+        }
+        (previous - current) as f64 / previous as f64 >= self.threshold
+    }
+}
+
+/// DropAction列挙型
+/// ファイル数が急減した場合の動作
+#[derive(Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DropAction {
+    /// バックアップせず、ファイル数が戻るか `backupfs-client ack` で確認されるまで待つ。
+    Skip,
+    /// 通知したうえでバックアップする。
+    Backup,
+}
+
+/// バックアップ対象の確認済みの印のパスを取得する。
+fn ack_path(state_dir: &Path, target: &Path) -> PathBuf {
+    state_dir.join(ACK_DIR_NAME).join(layout::target_id(target))
+}
+
+/// バックアップ対象の保留の記録のパスを取得する。
+fn held_path(state_dir: &Path, target: &Path) -> PathBuf {
+    state_dir.join(HELD_DIR_NAME).join(layout::target_id(target))
+}
+
+/// ファイルの数を記録する。
+fn write_count(path: &Path, count: u64) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, count.to_string())
+}
+
+/// 記録されたファイルの数を読み込む。記録がない、もしくは壊れている場合は None となる。
+fn read_count(path: &Path) -> Option<u64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// ファイルの数が急減したため、バックアップを保留していることを記録する。(backupfsd から呼び出す)
+/// current には保留した時点のファイルの数を指定する。
+pub fn hold<P: AsRef<Path>>(state_dir: P, target: P, current: u64) -> io::Result<()> {
+    write_count(&held_path(state_dir.as_ref(), target.as_ref()), current)
+}
+
+/// 保留の記録と確認済みの印を削除する。
+/// ファイルの数が戻った場合など、保留していない状態で確認済みの印が残り、
+/// 後の急減を確認なしにバックアップしないようにする。
+pub fn release<P: AsRef<Path>>(state_dir: P, target: P) {
+    let (state_dir, target) = (state_dir.as_ref(), target.as_ref());
+    let _ = fs::remove_file(held_path(state_dir, target));
+    let _ = fs::remove_file(ack_path(state_dir, target));
+}
+
+/// 保留しているバックアップ対象の現在の状態を確認済みとし、バックアップを再開させる。
+/// (backupfs-client から呼び出す)
+/// 保留していない場合は何もせず None を、確認した場合は保留した時点のファイルの数を返却する。
+pub fn acknowledge<P: AsRef<Path>>(state_dir: P, target: P) -> io::Result<Option<u64>> {
+    let (state_dir, target) = (state_dir.as_ref(), target.as_ref());
+    let held = match read_count(&held_path(state_dir, target)) {
+        Some(held) => held,
+        None => return Ok(None),
+    };
+    write_count(&ack_path(state_dir, target), held)?;
+    Ok(Some(held))
+}
+
+/// 確認済みの印があれば削除し、確認した時点からファイルの数がさらに減っていなければ true を返却する。
+pub fn take_ack<P: AsRef<Path>>(state_dir: P, target: P, current: u64) -> bool {
+    let path = ack_path(state_dir.as_ref(), target.as_ref());
+    let acked = read_count(&path);
+    let _ = fs::remove_file(&path);
+    acked.map(|acked| current >= acked).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_dropped() {
+        let config = FileDropConfig::default();
+        assert!(config.dropped(100, 50));
+        assert!(config.dropped(100, 0));
+        assert!(!config.dropped(100, 51));
+        assert!(!config.dropped(100, 150));
+        assert!(!config.dropped(9, 0));
+        let disabled = FileDropConfig { threshold: 0.0, ..FileDropConfig::default() };
+        assert!(!disabled.dropped(100, 0));
+    }
+
+    #[test]
+    fn test_acknowledge() {
+        let state_dir = env::temp_dir().join(format!("backupfs-guard-test-{}", process::id()));
+        let target = PathBuf::from("/home/user/documents");
+
+        // 保留していない場合は確認済みの印を残さない。
+        assert_eq!(None, acknowledge(&state_dir, &target).unwrap());
+        assert!(!ack_path(&state_dir, &target).exists());
+        assert!(!take_ack(&state_dir, &target, 0));
+
+        // 保留した時点のファイルの数までの状態を確認済みとする。
+        hold(&state_dir, &target, 40).unwrap();
+        assert_eq!(Some(40), acknowledge(&state_dir, &target).unwrap());
+        assert!(take_ack(&state_dir, &target, 40));
+        assert!(!take_ack(&state_dir, &target, 40));
+        acknowledge(&state_dir, &target).unwrap();
+        assert!(!take_ack(&state_dir, &target, 10));
+
+        // 保留を解除した後は、確認済みの印も残さない。
+        acknowledge(&state_dir, &target).unwrap();
+        release(&state_dir, &target);
+        assert!(!take_ack(&state_dir, &target, 40));
+        assert_eq!(None, acknowledge(&state_dir, &target).unwrap());
+        fs::remove_dir_all(&state_dir).unwrap();
+    }
+}
@@ -1,8 +1,7 @@
 
 use std::path::Path;
-use std::fs::Metadata;
-use std::fs::File;
-use std::io::prelude::*;
+use std::fs::{self, Metadata};
+use std::io::{self, prelude::*};
 use std::time::SystemTime;
 
 use chrono::prelude::*;
@@ -10,48 +9,69 @@ use crypto::digest::Digest;
 use crypto::md5::Md5;
 use walkdir::WalkDir;
 
-use result::Result;
+use result::{ErrorKind, Result, ResultExt};
 
 /// パスごとのハッシュ生成関数
 /// ディレクトリ/ファイルのメタデータからmd5ハッシュ値を生成する。
 /// 与えられるパスは実在することを期待するが、ディレクトリ/ファイルを選ばない
 pub fn dir_hash<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
+    dir_hash_count(path).map(|(hash, _)| hash)
+}
+
+/// パスごとのハッシュ生成関数
+/// md5ハッシュ値とともに、含まれるファイルの数を返却する。
+/// メタデータのみを参照するため、読み取り権限のないファイルも数える。
+/// 走査中に削除されたファイルは数えず、その他の読み込めないディレクトリやファイルはエラーとする。
+pub fn dir_hash_count<P: AsRef<Path>>(path: P) -> Result<(Vec<u8>, u64)> {
     let mut md5 = Md5::new();
+    let mut count = 0;
     let mut output: [u8; 64] = [0; 64];
     let mut w: Vec<u8> = Vec::new();
 
     let dir = WalkDir::new(path).into_iter();
 
     for entry in dir {
-        if let Ok(ent) = entry {
-            let path: &Path = ent.path();
-            if !path.is_file() {
-                continue;
-            }
-            let mut file: File = File::open(path)?;
-            let mut info: Metadata = file.metadata()?;
-
-            let created: DateTime<Utc> = info.created().unwrap_or(SystemTime::now()).into();
-            let modified: DateTime<Utc> = info.modified().unwrap_or(SystemTime::now()).into();
+        let ent = match entry {
+            Ok(ent) => ent,
+            Err(ref err) if err.depth() > 0 && is_not_found(err.io_error()) => continue,
+            Err(err) => {
+                let path = err.path().map(|p| p.to_path_buf()).unwrap_or_default();
+                return Err(err).context(ErrorKind::SourceMissing, path);
+            },
+        };
+        let path: &Path = ent.path();
+        let info: Metadata = match fs::metadata(path) {
+            Ok(info) => info,
+            // 走査中に削除されたファイルや、リンク先のないシンボリックリンクは数えない。
+            Err(_) if ent.path_is_symlink() => continue,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err).context(ErrorKind::SourceMissing, path),
+        };
+        if !info.is_file() {
+            continue;
+        }
+        count += 1;
+
+        let created: DateTime<Utc> = info.created().unwrap_or(SystemTime::now()).into();
+        let modified: DateTime<Utc> = info.modified().unwrap_or(SystemTime::now()).into();
         
-            let _ = writeln!(w, "{}", path.to_string_lossy());
-            let _ = writeln!(w, "{}", created.timestamp());
-            let _ = writeln!(w, "{}", modified.timestamp());
-
-            let file_type = info.file_type();
-
-            if file_type.is_dir() {
-                let _ = writeln!(w, "is_dir");
-            }
-            if file_type.is_file() {
-                let _ = writeln!(w, "is_file");
-            }
-            if file_type.is_symlink() {
-                let _ = writeln!(w, "is_symlink");
-            }
-            if info.permissions().readonly() {
-                let _ = writeln!(w, "readonly");
-            }
+        let _ = writeln!(w, "{}", path.to_string_lossy());
+        let _ = writeln!(w, "{}", created.timestamp());
+        let _ = writeln!(w, "{}", modified.timestamp());
+
+        let file_type = info.file_type();
+
+        if file_type.is_dir() {
+            let _ = writeln!(w, "is_dir");
+        }
+        if file_type.is_file() {
+            let _ = writeln!(w, "is_file");
+        }
+        if file_type.is_symlink() {
+            let _ = writeln!(w, "is_symlink");
+        }
+        if info.permissions().readonly() {
+            let _ = writeln!(w, "readonly");
         }
     }
 
@@ -59,5 +79,35 @@ pub fn dir_hash<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
     md5.input(w.as_slice());
     md5.result(&mut output);
 
-    Ok(output.to_vec())
-}
\ No newline at end of file
+    Ok((output.to_vec(), count))
+}
+
+fn is_not_found(err: Option<&io::Error>) -> bool {
+    err.map(|err| err.kind() == io::ErrorKind::NotFound).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_dir_hash_count() {
+        let root = env::temp_dir().join(format!("backupfs-hash-test-{}", process::id()));
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("a.txt"), b"a").unwrap();
+        fs::write(root.join("sub/b.txt"), b"b").unwrap();
+        let (hash, count) = dir_hash_count(&root).unwrap();
+        assert_eq!(2, count);
+
+        // リンク先のないシンボリックリンクは数えない。
+        #[cfg(unix)]
+        ::std::os::unix::fs::symlink(root.join("missing"), root.join("dangling")).unwrap();
+        assert_eq!((hash, 2), dir_hash_count(&root).unwrap());
+
+        // 対象が存在しない場合は、ファイルが0件ではなくエラーとなる。
+        fs::remove_dir_all(&root).unwrap();
+        assert!(dir_hash_count(&root).is_err());
+    }
+}
@@ -0,0 +1,286 @@
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use archiver::Mode;
+use result::{Error, ErrorKind, Result, ResultExt};
+
+/// フックコマンドのデフォルトのタイムアウト(秒)
+const DEFAULT_TIMEOUT: u64 = 300;
+
+/// HookConfig構造体
+/// バックアップの前後と失敗時に実行するフックコマンドの設定
+/// 全体の設定(`[hooks]`)とバックアップ対象ごとの設定(`[target.hooks]`)があり、
+/// 対象ごとに設定した項目が優先される。
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct HookConfig {
+    /// バックアップの前に実行するコマンド
+    pub pre_backup: Option<String>,
+    /// バックアップに成功した後に実行するコマンド
+    pub post_backup: Option<String>,
+    /// バックアップに失敗した際に実行するコマンド
+    pub on_failure: Option<String>,
+    /// コマンドのタイムアウト(秒) (デフォルト: 300)
+    pub timeout: Option<u64>,
+    /// pre_backup が失敗した場合にバックアップを中止するかどうか (デフォルト: true)
+    pub skip_on_pre_failure: Option<bool>,
+}
+
+impl HookConfig {
+    /// 対象ごとの設定(self)に、設定されていない項目を全体の設定(global)で補う。
+    pub fn merge(&self, global: &HookConfig) -> HookConfig {
+        HookConfig {
+            pre_backup: self.pre_backup.clone().or_else(|| global.pre_backup.clone()),
+            post_backup: self.post_backup.clone().or_else(|| global.post_backup.clone()),
+            on_failure: self.on_failure.clone().or_else(|| global.on_failure.clone()),
+            timeout: self.timeout.or(global.timeout),
+            skip_on_pre_failure: self.skip_on_pre_failure.or(global.skip_on_pre_failure),
+        }
+    }
+
+    /// pre_backup が失敗した場合にバックアップを中止するかどうか
+    pub fn skip_on_pre_failure(&self) -> bool {
+        self.skip_on_pre_failure.unwrap_or(true)
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(DEFAULT_TIMEOUT))
+    }
+}
+
+/// Hook列挙型
+/// フックコマンドを実行する契機
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Hook {
+    PreBackup,
+    PostBackup,
+    OnFailure,
+}
+
+impl Hook {
+    fn as_str(self) -> &'static str {
+        match self {
+            Hook::PreBackup => "pre_backup",
+            Hook::PostBackup => "post_backup",
+            Hook::OnFailure => "on_failure",
+        }
+    }
+}
+
+/// HookContext構造体
+/// フックコマンドへ環境変数として渡す情報
+///
+/// - `BACKUPFS_HOOK`        : pre_backup | post_backup | on_failure
+/// - `BACKUPFS_TARGET`      : バックアップ対象のパス
+/// - `BACKUPFS_MODE`        : zip | mirror | snapshot
+/// - `BACKUPFS_ARCHIVE`     : アーカイブ(スナップショット)のパス
+/// - `BACKUPFS_SNAPSHOT_ID` : アーカイブ(スナップショット)の名前
+/// - `BACKUPFS_RESULT`      : pending | success | failure
+/// - `BACKUPFS_ERROR`       : 失敗時のエラーメッセージ
+pub struct HookContext<'a> {
+    pub target: &'a Path,
+    pub mode: Mode,
+    pub archive: &'a Path,
+    pub error: Option<&'a Error>,
+}
+
+/// 設定されたフックコマンドを実行する。
+/// 設定されていない場合は何もしない。
+/// コマンドの標準出力、標準エラー出力はデーモンのログへ出力する。
+/// 終了コードが0以外の場合、もしくはタイムアウトした場合はエラーとなる。
+pub fn run(config: &HookConfig, hook: Hook, ctx: &HookContext) -> Result<()> {
+    let command = match hook {
+        Hook::PreBackup => config.pre_backup.as_ref(),
+        Hook::PostBackup => config.post_backup.as_ref(),
+        Hook::OnFailure => config.on_failure.as_ref(),
+    };
+    let command = match command {
+        Some(command) => command,
+        None => return Ok(()),
+    };
+
+    let result = match (hook, ctx.error) {
+        (Hook::PreBackup, _) => "pending",
+        (_, Some(_)) => "failure",
+        (_, None) => "success",
+    };
+    debug!("hook {}: {}", hook.as_str(), command);
+
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("BACKUPFS_HOOK", hook.as_str())
+        .env("BACKUPFS_TARGET", ctx.target)
+        .env("BACKUPFS_MODE", ctx.mode.as_str())
+        .env("BACKUPFS_ARCHIVE", ctx.archive)
+        .env("BACKUPFS_SNAPSHOT_ID", ctx.archive.file_name().unwrap_or_default())
+        .env("BACKUPFS_RESULT", result)
+        .env("BACKUPFS_ERROR", ctx.error.map(|e| e.chain().join(": ")).unwrap_or_default())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context(ErrorKind::HookFailed, ctx.target)?;
+
+    let stdout = child.stdout.take().map(|out| log_output(hook, out, false));
+    let stderr = child.stderr.take().map(|err| log_output(hook, err, true));
+
+    // タイムアウトまで終了を待ち、超えた場合は強制終了する。
+    let deadline = Instant::now() + config.timeout();
+    let status = loop {
+        if let Some(status) = child.try_wait().context(ErrorKind::HookFailed, ctx.target)? {
+            break Some(status);
+        }
+        if Instant::now() >= deadline {
+            if let Err(err) = child.kill() {
+                warn!("{:?}", err);
+            }
+            let _ = child.wait();
+            break None;
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+
+    // タイムアウトした場合は、コマンドの子プロセスが出力を開いたままの可能性があるため待たない。
+    if status.is_some() {
+        for handle in stdout.into_iter().chain(stderr) {
+            let _ = handle.join();
+        }
+    }
+
+    match status {
+        Some(ref status) if status.success() => Ok(()),
+        Some(status) => Err(Error::new(ErrorKind::HookFailed, format!("{} exited with {}", hook.as_str(), status))
+            .with_path(ctx.target)),
+        None => Err(Error::new(ErrorKind::HookFailed, format!("{} timed out", hook.as_str()))
+            .with_path(ctx.target)),
+    }
+}
+
+/// コマンドの出力を1行ずつログへ出力する。
+fn log_output<R: Read + Send + 'static>(hook: Hook, reader: R, is_err: bool) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        for line in BufReader::new(reader).lines() {
+            match line {
+                Ok(line) if is_err => warn!("hook {}: {}", hook.as_str(), line),
+                Ok(line) => info!("hook {}: {}", hook.as_str(), line),
+                Err(_) => break,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::sync::{Mutex, Once};
+    use log::{self, Level, Log, Metadata, Record};
+
+    /// フックコマンドの出力を確認するため、ログを記録するロガー
+    struct Capture;
+
+    static LINES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    static INIT: Once = Once::new();
+
+    impl Log for Capture {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            LINES.lock().unwrap().push(format!("{} {}", record.level(), record.args()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn test_merge() {
+        let global = HookConfig {
+            pre_backup: Some("global".to_string()),
+            on_failure: Some("global".to_string()),
+            timeout: Some(10),
+            ..HookConfig::default()
+        };
+        let target = HookConfig { pre_backup: Some("target".to_string()), ..HookConfig::default() };
+        let merged = target.merge(&global);
+        assert_eq!(Some("target".to_string()), merged.pre_backup);
+        assert_eq!(Some("global".to_string()), merged.on_failure);
+        assert_eq!(None, merged.post_backup);
+        assert_eq!(Duration::from_secs(10), merged.timeout());
+        assert!(merged.skip_on_pre_failure());
+        let merged = HookConfig { skip_on_pre_failure: Some(false), ..target }.merge(&global);
+        assert!(!merged.skip_on_pre_failure());
+        assert_eq!(Duration::from_secs(DEFAULT_TIMEOUT), HookConfig::default().timeout());
+    }
+
+    #[test]
+    fn test_run() {
+        let root = env::temp_dir().join(format!("backupfs-hooks-test-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let out = root.join("env.txt");
+        let archive = root.join("20181018T000000Z-000001");
+        let ctx = HookContext { target: &root, mode: Mode::Snapshot, archive: &archive, error: None };
+
+        // 設定されていない場合は何もしない。
+        assert!(run(&HookConfig::default(), Hook::PreBackup, &ctx).is_ok());
+
+        // 環境変数
+        let command = format!(
+            "echo \"$BACKUPFS_HOOK|$BACKUPFS_TARGET|$BACKUPFS_MODE|$BACKUPFS_SNAPSHOT_ID|$BACKUPFS_RESULT|$BACKUPFS_ERROR\" > {}",
+            out.display(),
+        );
+        let config = HookConfig {
+            pre_backup: Some(command.clone()),
+            on_failure: Some(command),
+            ..HookConfig::default()
+        };
+        run(&config, Hook::PreBackup, &ctx).unwrap();
+        assert_eq!(
+            format!("pre_backup|{}|snapshot|20181018T000000Z-000001|pending|\n", root.display()),
+            fs::read_to_string(&out).unwrap(),
+        );
+        let err = Error::new(ErrorKind::ArchiveFailed, "disk full");
+        run(&config, Hook::OnFailure, &HookContext { error: Some(&err), ..ctx }).unwrap();
+        let written = fs::read_to_string(&out).unwrap();
+        assert!(written.starts_with("on_failure|"));
+        assert!(written.contains("|failure|") && written.contains("disk full"));
+
+        // 終了コードが0以外の場合はエラーとなる。
+        let config = HookConfig { post_backup: Some("exit 3".to_string()), ..HookConfig::default() };
+        let err = run(&config, Hook::PostBackup, &ctx).unwrap_err();
+        assert_eq!(ErrorKind::HookFailed, err.kind());
+
+        // タイムアウトした場合は強制終了し、エラーとなる。
+        let config = HookConfig { pre_backup: Some("sleep 10".to_string()), timeout: Some(1), ..HookConfig::default() };
+        let started = Instant::now();
+        let err = run(&config, Hook::PreBackup, &ctx).unwrap_err();
+        assert_eq!(ErrorKind::HookFailed, err.kind());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_log_output() {
+        INIT.call_once(|| {
+            log::set_logger(&Capture).unwrap();
+            log::set_max_level(log::LevelFilter::Info);
+        });
+        let root = env::temp_dir();
+        let ctx = HookContext { target: &root, mode: Mode::Zip, archive: &root, error: None };
+        let config = HookConfig {
+            post_backup: Some("echo hook-stdout-line; echo hook-stderr-line >&2".to_string()),
+            ..HookConfig::default()
+        };
+        run(&config, Hook::PostBackup, &ctx).unwrap();
+        let lines = LINES.lock().unwrap();
+        assert!(lines.contains(&format!("{} hook post_backup: hook-stdout-line", Level::Info)));
+        assert!(lines.contains(&format!("{} hook post_backup: hook-stderr-line", Level::Warn)));
+    }
+}
@@ -0,0 +1,247 @@
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// 通信のタイムアウト(秒)
+const TIMEOUT_SECS: u64 = 60;
+
+/// Url構造体
+/// `http://host[:port]/path?query` 形式のURLを分解したもの。
+/// TLSには対応していないため、https はエラーとする。
+#[derive(Clone, Debug, PartialEq)]
+pub struct Url {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+    pub query: String,
+}
+
+impl Url {
+    /// URL文字列を解析する。
+    pub fn parse(url: &str) -> io::Result<Self> {
+        if url.starts_with("https://") {
+            return Err(invalid(format!("https is not supported, use a plain http endpoint: {}", url)));
+        }
+        let rest = match url.strip_prefix("http://") {
+            Some(rest) => rest,
+            None => return Err(invalid(format!("unsupported url: {}", url))),
+        };
+
+        let (authority, path_query) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rfind(':') {
+            Some(i) => {
+                let port = authority[i + 1..].parse::<u16>()
+                    .map_err(|_| invalid(format!("invalid port: {}", url)))?;
+                (&authority[..i], port)
+            },
+            None => (authority, 80),
+        };
+        let (path, query) = match path_query.find('?') {
+            Some(i) => (&path_query[..i], &path_query[i + 1..]),
+            None => (path_query, ""),
+        };
+
+        Ok(Url {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+            query: query.to_string(),
+        })
+    }
+
+    /// Hostヘッダーに設定する値を取得する。
+    pub fn host_header(&self) -> String {
+        if self.port == 80 {
+            self.host.clone()
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// Response構造体
+/// HTTPレスポンスのステータスコード、ヘッダー、ボディを格納する。
+#[derive(Clone, Debug, Default)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// ヘッダーの値を取得する。(大文字小文字は区別しない)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// ステータスコードが2xxかどうか
+    pub fn is_success(&self) -> bool {
+        self.status >= 200 && self.status < 300
+    }
+}
+
+/// HTTPリクエストの送信関数
+/// 1リクエストごとに接続を行い、レスポンスを受信後に切断する。
+/// Host, Content-Length, Connection ヘッダーは本関数で付与する。
+pub fn send(method: &str, url: &Url, headers: &[(String, String)], body: &[u8]) -> io::Result<Response> {
+    let stream = TcpStream::connect((url.host.as_str(), url.port))?;
+    stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT_SECS)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(TIMEOUT_SECS)))?;
+
+    let target = if url.query.is_empty() {
+        url.path.clone()
+    } else {
+        format!("{}?{}", url.path, url.query)
+    };
+
+    let mut head = Vec::new();
+    write!(head, "{} {} HTTP/1.1\r\n", method, target)?;
+    write!(head, "Host: {}\r\n", url.host_header())?;
+    for (name, value) in headers {
+        write!(head, "{}: {}\r\n", name, value)?;
+    }
+    write!(head, "Content-Length: {}\r\n", body.len())?;
+    write!(head, "Connection: close\r\n\r\n")?;
+
+    let mut writer = &stream;
+    writer.write_all(&head)?;
+    writer.write_all(body)?;
+    writer.flush()?;
+
+    read_response(BufReader::new(&stream), method == "HEAD")
+}
+
+fn read_response<R: BufRead>(mut reader: R, head_only: bool) -> io::Result<Response> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let status = line.split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or_else(|| invalid(format!("invalid status line: {:?}", line)))?;
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        let l = line.trim_end();
+        if l.is_empty() {
+            break;
+        }
+        if let Some(i) = l.find(':') {
+            headers.push((l[..i].trim().to_string(), l[i + 1..].trim().to_string()));
+        }
+    }
+
+    let mut res = Response { status, headers, body: Vec::new() };
+    if head_only {
+        return Ok(res);
+    }
+
+    let chunked = res.header("Transfer-Encoding")
+        .map(|v| v.eq_ignore_ascii_case("chunked"))
+        .unwrap_or(false);
+    let length = res.header("Content-Length").and_then(|v| v.parse::<usize>().ok());
+
+    if chunked {
+        res.body = read_chunked(&mut reader)?;
+    } else if let Some(length) = length {
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+        res.body = body;
+    } else {
+        reader.read_to_end(&mut res.body)?;
+    }
+
+    Ok(res)
+}
+
+fn read_chunked<R: BufRead>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line)?;
+        let size_str = line.trim().split(';').next().unwrap_or_default();
+        let size = usize::from_str_radix(size_str, 16)
+            .map_err(|_| invalid(format!("invalid chunk size: {:?}", line)))?;
+        if size == 0 {
+            // trailer を読み捨てる。
+            loop {
+                line.clear();
+                if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                    break;
+                }
+            }
+            return Ok(body);
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+        line.clear();
+        reader.read_line(&mut line)?;
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_url_parse() {
+        let url = Url::parse("http://127.0.0.1:9000/bucket/a%20b.zip?uploads=&x=1").unwrap();
+        assert_eq!("127.0.0.1", url.host);
+        assert_eq!(9000, url.port);
+        assert_eq!("/bucket/a%20b.zip", url.path);
+        assert_eq!("uploads=&x=1", url.query);
+        assert_eq!("127.0.0.1:9000", url.host_header());
+
+        let url = Url::parse("http://example.com").unwrap();
+        assert_eq!((80, "/", ""), (url.port, url.path.as_str(), url.query.as_str()));
+        assert_eq!("example.com", url.host_header());
+
+        assert!(Url::parse("https://example.com/").is_err());
+        assert!(Url::parse("ftp://example.com/").is_err());
+        assert!(Url::parse("http://example.com:99999/").is_err());
+    }
+
+    #[test]
+    fn test_read_response() {
+        let chunked = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                       5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        let res = read_response(Cursor::new(chunked), false).unwrap();
+        assert_eq!(200, res.status);
+        assert_eq!(b"hello world".to_vec(), res.body);
+
+        // Content-Length を超えるデータは読まない。
+        let sized = "HTTP/1.1 404 Not Found\r\ncontent-length: 4\r\nETag: \"abc\"\r\n\r\nbodyEXTRA";
+        let res = read_response(Cursor::new(sized), false).unwrap();
+        assert_eq!(404, res.status);
+        assert!(!res.is_success());
+        assert_eq!(Some("\"abc\""), res.header("etag"));
+        assert_eq!(b"body".to_vec(), res.body);
+
+        // HEAD のレスポンスはボディを読まない。
+        let res = read_response(Cursor::new(sized), true).unwrap();
+        assert!(res.body.is_empty());
+
+        let short = "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort";
+        assert!(read_response(Cursor::new(short), false).is_err());
+        assert!(read_response(Cursor::new("garbage\r\n\r\n"), false).is_err());
+        assert!(read_response(Cursor::new("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n"), false).is_err());
+    }
+}
@@ -0,0 +1,271 @@
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::prelude::*;
+use serde_json;
+
+use logging;
+use result::{Error, ErrorKind, Result, ResultExt};
+use scheduler;
+
+/// ジャーナルのファイル名
+const JOURNAL_FILE_NAME: &str = "journal.jsonl";
+
+/// 記録先のジャーナルのパス (open() を呼び出すまではログへの出力のみ)
+static JOURNAL: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// EventKind列挙型
+/// ジャーナルに記録するイベントの種類
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// バックアップに成功した。
+    Backup,
+    /// バックアップに失敗した。
+    Failure,
+    /// 保持数を超えた古いアーカイブを削除した。
+    Prune,
+    /// アーカイブからファイルを復元した。
+    Restore,
+    /// バックアップ対象が見つからない。
+    Missing,
+    /// 見つからなかったバックアップ対象が再び見つかった。
+    Reappeared,
+    /// アンマウントや大量の削除、変更の疑いがあるため、バックアップを保留した、
+    /// もしくは不審なアーカイブとして記録した。
+    Suspicious,
+}
+
+impl EventKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            EventKind::Backup => "backup",
+            EventKind::Failure => "failure",
+            EventKind::Prune => "prune",
+            EventKind::Restore => "restore",
+            EventKind::Missing => "missing",
+            EventKind::Reappeared => "reappeared",
+            EventKind::Suspicious => "suspicious",
+        }
+    }
+}
+
+/// Event構造体
+/// ジャーナルの1行分(JSON)
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Event {
+    /// 発生した時刻 (RFC 3339)
+    pub time: String,
+    pub event: EventKind,
+    /// バックアップ対象のパス
+    pub target: PathBuf,
+    /// アーカイブ(スナップショット)のパス
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive: Option<PathBuf>,
+    /// アーカイブのサイズ(バイト)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    /// 処理時間(ミリ秒)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    /// 削除したアーカイブ、復元したファイルの数
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub count: Option<usize>,
+    /// 補足のメッセージ
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// 失敗時のエラーの種別
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_kind: Option<String>,
+    /// 失敗時のエラーメッセージ
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Event {
+    /// Event構造体のコンストラクタ
+    /// 時刻は現在時刻となる。
+    pub fn new<P: AsRef<Path>>(event: EventKind, target: P) -> Self {
+        Event {
+            time: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            event,
+            target: target.as_ref().to_path_buf(),
+            archive: None,
+            bytes: None,
+            duration_ms: None,
+            count: None,
+            message: None,
+            error_kind: None,
+            error: None,
+        }
+    }
+
+    /// 失敗のイベントを生成する。
+    pub fn failure<P: AsRef<Path>>(target: P, err: &Error) -> Self {
+        let mut event = Event::new(EventKind::Failure, target);
+        event.error_kind = Some(err.kind().to_string());
+        event.error = Some(err.chain().join(": "));
+        event
+    }
+
+    /// アーカイブのパスを設定する。
+    pub fn with_archive<P: AsRef<Path>>(mut self, archive: P) -> Self {
+        self.archive = Some(archive.as_ref().to_path_buf());
+        self
+    }
+
+    /// サイズと処理時間を設定する。
+    pub fn with_size(mut self, bytes: u64, duration: Duration) -> Self {
+        self.bytes = Some(bytes);
+        self.duration_ms = Some(duration.as_millis() as u64);
+        self
+    }
+
+    /// 件数を設定する。
+    pub fn with_count(mut self, count: usize) -> Self {
+        self.count = Some(count);
+        self
+    }
+
+    /// 補足のメッセージを設定する。
+    pub fn with_message<S: Into<String>>(mut self, message: S) -> Self {
+        self.message = Some(message.into());
+        self
+    }
+
+    /// 発生した時刻を取得する。
+    pub fn time(&self) -> Option<DateTime<Utc>> {
+        DateTime::parse_from_rfc3339(&self.time).ok().map(|t| t.with_timezone(&Utc))
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.time, self.event.as_str(), self.target.display())?;
+        if let Some(ref archive) = self.archive {
+            write!(f, " -> {}", archive.display())?;
+        }
+        if let Some(bytes) = self.bytes {
+            write!(f, " {} bytes", bytes)?;
+        }
+        if let Some(duration_ms) = self.duration_ms {
+            write!(f, " {}ms", duration_ms)?;
+        }
+        match (self.event, self.count) {
+            (EventKind::Prune, Some(count)) => write!(f, " removed {} archives", count)?,
+            (_, Some(count)) => write!(f, " {} files", count)?,
+            _ => {},
+        }
+        if let Some(ref message) = self.message {
+            write!(f, ": {}", message)?;
+        }
+        if let Some(ref error) = self.error {
+            write!(f, ": {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// ジャーナルのデフォルトのパスを取得する。(状態ディレクトリの journal.jsonl)
+pub fn default_path<P: AsRef<Path>>(state_dir: P) -> PathBuf {
+    state_dir.as_ref().join(JOURNAL_FILE_NAME)
+}
+
+/// 以降のイベントをジャーナルへ記録する。
+pub fn open<P: AsRef<Path>>(path: P) {
+    *JOURNAL.lock().unwrap_or_else(|e| e.into_inner()) = Some(path.as_ref().to_path_buf());
+}
+
+/// イベントをログへ出力し、ジャーナルへ追記する。
+/// 追記に失敗してもバックアップには影響させず、ログへ出力するのみとする。
+pub fn record(event: Event) {
+    logging::event(&event);
+    let journal = JOURNAL.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(ref path) = *journal {
+        if let Err(err) = append(path, &event) {
+            error!("{:?}", err);
+        }
+    }
+}
+
+fn append(path: &Path, event: &Event) -> Result<()> {
+    let mut line = serde_json::to_vec(event)?;
+    line.push(b'\n');
+    // 1行を1回で書き込み、途中までの行が残らないようにする。
+    OpenOptions::new().create(true).append(true).open(path)
+        .and_then(|mut f| f.write_all(&line))
+        .context(ErrorKind::Io, path)
+}
+
+/// ジャーナルのイベントを読み込む。
+/// since以降、targetを指定した場合はそのバックアップ対象のイベントのみとする。
+/// 読み込めない行は読み飛ばす。
+pub fn read<P: AsRef<Path>>(path: P, since: Option<DateTime<Utc>>, target: Option<&Path>) -> Result<Vec<Event>> {
+    let path = path.as_ref();
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let file = fs::File::open(path).context(ErrorKind::Io, path)?;
+    let mut events = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.context(ErrorKind::Io, path)?;
+        let event: Event = match serde_json::from_str(&line) {
+            Ok(event) => event,
+            Err(_) => continue,
+        };
+        if let Some(since) = since {
+            if event.time().map(|t| t < since).unwrap_or(true) {
+                continue;
+            }
+        }
+        if let Some(target) = target {
+            if event.target != target {
+                continue;
+            }
+        }
+        events.push(event);
+    }
+    Ok(events)
+}
+
+/// `--since` の値を解析する。
+/// RFC 3339 の時刻、`2018-10-18` の日付(ローカル時刻)、`6h` `7d` などの現在からの期間を受け付ける。
+pub fn parse_since(s: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Some(t.with_timezone(&Utc));
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Local.from_local_datetime(&date.and_hms(0, 0, 0)).earliest().map(|t| t.with_timezone(&Utc));
+    }
+    let duration = scheduler::parse_duration(s)?;
+    Some(now - ::chrono::Duration::seconds(duration.as_secs() as i64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_journal() {
+        let path = env::temp_dir().join(format!("backupfs-journal-test-{}.jsonl", process::id()));
+        let mut old = Event::new(EventKind::Prune, "/a").with_count(2);
+        old.time = "2018-10-18T00:00:00Z".to_string();
+        append(&path, &old).unwrap();
+        append(&path, &Event::new(EventKind::Backup, "/a").with_archive("/b/a.zip")).unwrap();
+        append(&path, &Event::new(EventKind::Backup, "/c")).unwrap();
+
+        let since = parse_since("2018-10-19T00:00:00Z", Utc::now());
+        assert_eq!(3, read(&path, None, None).unwrap().len());
+        let events = read(&path, since, Some(Path::new("/a"))).unwrap();
+        assert_eq!(1, events.len());
+        assert_eq!(EventKind::Backup, events[0].event);
+        assert_eq!(Some(PathBuf::from("/b/a.zip")), events[0].archive);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,246 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crypto::digest::Digest;
+use crypto::md5::Md5;
+use serde_json;
+
+use archiver::{MIRROR_DIR_NAME, TRASH_DIR_NAME};
+use naming;
+use result::Result;
+
+/// バックアップ対象の元のパスを記録するファイル名
+pub const TARGET_FILE_NAME: &str = "target.json";
+
+/// TargetRecord構造体
+/// バックアップ対象ごとのディレクトリに記録する、元のパスの情報
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct TargetRecord {
+    pub path: PathBuf,
+}
+
+/// バックアップ対象ごとのディレクトリのパスを取得する。
+///
+/// バックアップ先の直下に `<ファイル/ディレクトリ名>-<パスのmd5の先頭8桁>` のディレクトリを作成する。
+/// 名前のうち英数字と `-_.` 以外の文字は `%XX` にエスケープする。
+/// パスのハッシュを含むため、名前が同じ対象や、親子関係にある対象でもディレクトリは共有されない。
+pub fn target_dir<P: AsRef<Path>>(destination: P, target: P) -> PathBuf {
+    destination.as_ref().join(target_id(target.as_ref()))
+}
+
+/// バックアップ対象のIDを取得する。
+pub fn target_id(target: &Path) -> String {
+    let name = target.file_name()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_else(|| "root".to_string());
+
+    let mut md5 = Md5::new();
+    md5.input_str(&target.to_string_lossy());
+
+    format!("{}-{}", escape(&name), &md5.result_str()[..8])
+}
+
+/// バックアップ対象ごとのディレクトリを作成し、元のパスを記録する。
+pub fn prepare<P: AsRef<Path>>(destination: P, target: P) -> io::Result<PathBuf> {
+    let dir = target_dir(destination.as_ref(), target.as_ref());
+    fs::create_dir_all(&dir)?;
+
+    let record_path = dir.join(TARGET_FILE_NAME);
+    if !record_path.exists() {
+        let record = TargetRecord { path: target.as_ref().to_path_buf() };
+        fs::write(&record_path, serde_json::to_vec_pretty(&record)?)?;
+    }
+    Ok(dir)
+}
+
+/// バックアップ対象ごとのディレクトリに記録された元のパスを取得する。
+pub fn read_target<P: AsRef<Path>>(dir: P) -> Option<PathBuf> {
+    let data = fs::read(dir.as_ref().join(TARGET_FILE_NAME)).ok()?;
+    serde_json::from_slice::<TargetRecord>(&data).ok().map(|r| r.path)
+}
+
+/// 旧形式のレイアウトでのバックアップ対象のディレクトリの候補を取得する。
+/// 旧形式はルートを取り除いたパスをそのままバックアップ先へ接着していた。
+/// ファイルかどうかの判定をルートを取り除いた相対パス(デーモンのカレントディレクトリ基準)で行っていたため、
+/// ファイルの場合、カレントディレクトリが `/` (systemd から起動した場合など)であれば親ディレクトリのパス、
+/// それ以外であればファイルのパスのディレクトリとなる。
+/// ファイルの場合は両方の候補を、ファイルのパス、親ディレクトリのパスの順に返却する。
+pub fn legacy_dirs<P: AsRef<Path>>(destination: P, target: P) -> Vec<PathBuf> {
+    let (destination, target) = (destination.as_ref(), target.as_ref());
+    let relative = target.strip_prefix("/").unwrap_or(target);
+    let mut dirs = vec![destination.join(relative)];
+    if target.is_file() {
+        dirs.push(destination.join(relative.parent().unwrap_or(relative)));
+    }
+    dirs
+}
+
+/// 旧形式のレイアウトのアーカイブを、バックアップ対象ごとのディレクトリへ移動する。
+/// 旧形式のディレクトリには、配下のパスのバックアップ対象のディレクトリが含まれる場合があるため、
+/// 他の対象(others)の旧形式のディレクトリとその親は移動しない。
+/// ファイルの親ディレクトリのパスの候補は、ディレクトリの対象や同じディレクトリにある他のファイルの対象の
+/// アーカイブと区別できないため、他の対象が同じ候補を持たない場合のみ移動する。
+/// 移動したアーカイブの数を返却する。
+pub fn migrate(destination: &Path, target: &Path, others: &[PathBuf]) -> Result<usize> {
+    let others: Vec<Vec<PathBuf>> = others.iter()
+        .filter(|o| o.as_path() != target)
+        .map(|o| legacy_dirs(destination, o.as_path()))
+        .collect();
+
+    let mut count = 0;
+    for (i, legacy) in legacy_dirs(destination, target).into_iter().enumerate() {
+        if !legacy.is_dir() || legacy == destination {
+            continue;
+        }
+        if i > 0 && others.iter().any(|dirs| dirs.contains(&legacy)) {
+            warn!("layout: {:?} is shared with other targets, archives are not migrated", legacy);
+            continue;
+        }
+        let nested: Vec<&PathBuf> = others.iter()
+            .flat_map(|dirs| dirs.iter())
+            .filter(|o| o.starts_with(&legacy) && **o != legacy)
+            .collect();
+        count += migrate_dir(destination, target, &legacy, &nested)?;
+    }
+    Ok(count)
+}
+
+/// 旧形式のディレクトリ(legacy)のアーカイブを、バックアップ対象ごとのディレクトリへ移動する。
+fn migrate_dir(destination: &Path, target: &Path, legacy: &Path, nested: &[&PathBuf]) -> Result<usize> {
+    let dir = prepare(destination, target)?;
+    let mut count = 0;
+    for entry in fs::read_dir(legacy)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !(naming::is_archive_name(&name) || name == MIRROR_DIR_NAME || name == TRASH_DIR_NAME)
+            || nested.iter().any(|o| o.starts_with(entry.path())) {
+            continue;
+        }
+        let to = dir.join(&name);
+        if to.exists() {
+            continue;
+        }
+        fs::rename(entry.path(), &to)?;
+        count += 1;
+    }
+    info!("layout: migrated {} archives {:?} -> {:?}", count, legacy, dir);
+
+    remove_empty_dirs(destination, legacy)?;
+    Ok(count)
+}
+
+/// 空になった旧形式のディレクトリを、バックアップ先の直下まで遡って削除する。
+fn remove_empty_dirs(destination: &Path, dir: &Path) -> io::Result<()> {
+    let mut dir = dir.to_path_buf();
+    while dir.starts_with(destination) && dir != destination {
+        if fs::read_dir(&dir)?.next().is_some() {
+            break;
+        }
+        fs::remove_dir(&dir)?;
+        dir = match dir.parent() {
+            Some(parent) => parent.to_path_buf(),
+            None => break,
+        };
+    }
+    Ok(())
+}
+
+/// 英数字と `-_.` 以外の文字を `%XX` にエスケープする。
+fn escape(name: &str) -> String {
+    let mut escaped = String::new();
+    for b in name.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' => escaped.push(b as char),
+            // 先頭の '.' は隠しファイルと区別できなくなるためエスケープする。
+            b'.' if !escaped.is_empty() => escaped.push('.'),
+            _ => escaped.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_dir() {
+        let dest = Path::new("/backup");
+        let a = target_dir(dest, Path::new("/home/user/my docs"));
+        let b = target_dir(dest, Path::new("/home/user/my docs/my docs"));
+        assert_eq!(Some(dest), a.parent());
+        assert!(a.file_name().unwrap().to_string_lossy().starts_with("my%20docs-"));
+        assert_ne!(a, b);
+        assert!(target_id(Path::new("/home/user/.config")).starts_with("%2Econfig-"));
+    }
+
+    #[test]
+    fn test_migrate() {
+        use std::env;
+        use std::process;
+
+        let root = env::temp_dir().join(format!("backupfs-layout-test-{}", process::id()));
+        let destination = root.join("dest");
+        let dir_target = root.join("src");
+        // ディレクトリの対象の配下にある、ファイルの対象 (名前が旧形式のアーカイブ名と区別できない)
+        let file_target = dir_target.join("2018");
+        fs::create_dir_all(&dir_target).unwrap();
+        fs::write(&file_target, b"file").unwrap();
+        let targets = vec![dir_target.clone(), file_target.clone()];
+
+        // 旧形式ではファイルの対象もファイルのパスのディレクトリに格納されていた。
+        let legacy = legacy_dirs(&destination, &file_target)[0].clone();
+        assert_eq!(destination.join(file_target.strip_prefix("/").unwrap()), legacy);
+        fs::create_dir_all(&legacy).unwrap();
+        fs::write(legacy.join("1538724123456789.zip"), b"f").unwrap();
+        fs::write(legacy_dirs(&destination, &dir_target)[0].join("1538724123456790.zip"), b"d").unwrap();
+
+        assert_eq!(1, migrate(&destination, &dir_target, &targets).unwrap());
+        assert!(legacy.join("1538724123456789.zip").exists());
+        assert_eq!(1, migrate(&destination, &file_target, &targets).unwrap());
+        assert!(target_dir(&destination, &dir_target).join("1538724123456790.zip").exists());
+        assert!(target_dir(&destination, &file_target).join("1538724123456789.zip").exists());
+        assert_eq!(Some(file_target.clone()), read_target(target_dir(&destination, &file_target)));
+        // 空になった旧形式のディレクトリは削除される。
+        assert!(!destination.join(root.strip_prefix("/").unwrap()).exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+    #[test]
+    fn test_migrate_parent() {
+        use std::env;
+        use std::process;
+
+        let root = env::temp_dir().join(format!("backupfs-layout-parent-test-{}", process::id()));
+        let destination = root.join("dest");
+        // 親ディレクトリを他の対象と共有しないファイルの対象
+        let file_target = root.join("a").join("notes.txt");
+        // ディレクトリの対象と、その直下のファイルの対象
+        let dir_target = root.join("b");
+        let shared_target = dir_target.join("c.txt");
+        fs::create_dir_all(&dir_target).unwrap();
+        fs::create_dir_all(file_target.parent().unwrap()).unwrap();
+        fs::write(&file_target, b"file").unwrap();
+        fs::write(&shared_target, b"file").unwrap();
+        let targets = vec![file_target.clone(), dir_target.clone(), shared_target.clone()];
+
+        // カレントディレクトリが `/` の場合、旧形式ではファイルの対象は親ディレクトリのパスに格納されていた。
+        let legacy = legacy_dirs(&destination, &file_target);
+        assert_eq!(2, legacy.len());
+        assert_eq!(destination.join(root.join("a").strip_prefix("/").unwrap()), legacy[1]);
+        fs::create_dir_all(&legacy[1]).unwrap();
+        fs::write(legacy[1].join("1538724123456789.zip"), b"f").unwrap();
+        let shared = legacy_dirs(&destination, &shared_target)[1].clone();
+        assert_eq!(legacy_dirs(&destination, &dir_target)[0], shared);
+        fs::create_dir_all(&shared).unwrap();
+        fs::write(shared.join("1538724123456790.zip"), b"d").unwrap();
+
+        assert_eq!(1, migrate(&destination, &file_target, &targets).unwrap());
+        assert!(target_dir(&destination, &file_target).join("1538724123456789.zip").exists());
+        // ディレクトリの対象の旧形式のディレクトリにあるアーカイブは、ファイルの対象へ移動しない。
+        assert_eq!(0, migrate(&destination, &shared_target, &targets).unwrap());
+        assert_eq!(1, migrate(&destination, &dir_target, &targets).unwrap());
+        assert!(target_dir(&destination, &dir_target).join("1538724123456790.zip").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -2,6 +2,8 @@ extern crate zip;
 extern crate walkdir;
 extern crate chrono;
 extern crate crypto;
+extern crate dirs;
+extern crate libc;
 extern crate time;
 extern crate filedb;
 extern crate serde;
@@ -11,15 +13,43 @@ extern crate serde_json;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
+extern crate toml;
+extern crate regex;
 
 use std::fmt;
 use std::path::PathBuf;
 
 
+pub mod anomaly;
 pub mod archiver;
+pub mod catalog;
+pub mod config;
+pub mod destination;
+pub mod extract;
+pub mod guard;
 pub mod hash;
+pub mod hooks;
+mod http;
+pub mod journal;
+pub mod layout;
+pub mod lock;
+pub mod logging;
+pub mod marks;
+pub mod metrics;
 pub mod monitor;
+pub mod naming;
+pub mod notify;
+pub mod pool;
+pub mod protect;
 pub mod result;
+pub mod retention;
+pub mod retry;
+pub mod scheduler;
+pub mod search;
+pub mod signal;
+pub mod space;
+pub mod systemd;
+pub mod throttle;
 
 
 /// PathItem構造体  
@@ -29,12 +59,18 @@ pub mod result;
 pub struct PathItem {
     path: PathBuf,
     hash: Vec<u8>,
+    /// スケジュールによるバックアップの前回の実行時刻(UNIX時間)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_run: Option<i64>,
+    /// 前回のバックアップ時のファイルの数
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    files: Option<u64>,
 }
 
 impl PathItem {
     /// PathItem構造体のコンストラクタ
     pub fn new(path: PathBuf, hash: Vec<u8>) -> Self {
-        PathItem { path, hash }
+        PathItem { path, hash, last_run: None, files: None }
     }
 
     /// Vec<u8>のバイナリからPathItem構造体へ変換する。
@@ -57,6 +93,26 @@ impl PathItem {
     pub fn set_hash(&mut self, hash: Vec<u8>) {
         self.hash = hash;
     }
+
+    /// スケジュールによるバックアップの前回の実行時刻を取得する。
+    pub fn last_run(&self) -> Option<i64> {
+        self.last_run
+    }
+
+    /// スケジュールによるバックアップの前回の実行時刻を設定する。
+    pub fn set_last_run(&mut self, last_run: Option<i64>) {
+        self.last_run = last_run;
+    }
+
+    /// 前回のバックアップ時のファイルの数を取得する。
+    pub fn files(&self) -> Option<u64> {
+        self.files
+    }
+
+    /// 前回のバックアップ時のファイルの数を設定する。
+    pub fn set_files(&mut self, files: Option<u64>) {
+        self.files = files;
+    }
 }
 
 impl fmt::Display for PathItem {
@@ -0,0 +1,161 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use result::{Error, ErrorKind, Result, ResultExt};
+
+/// ロックファイルのファイル名
+const LOCK_FILE_NAME: &str = "backupfsd.lock";
+/// PIDファイルのファイル名
+const PID_FILE_NAME: &str = "backupfsd.pid";
+
+/// InstanceLock構造体
+/// 状態ディレクトリのロックファイルを排他的にロックし、backupfsdの多重起動を防ぐ。
+/// ロックはプロセスの終了(異常終了を含む)とともに解放される。
+/// 破棄された際にPIDファイルを削除する。
+pub struct InstanceLock {
+    _file: File,
+    pid_path: PathBuf,
+}
+
+impl InstanceLock {
+    /// ロックを取得し、PIDファイルを書き込む。
+    /// 他のプロセスがロックを保持している場合は AlreadyRunning となる。
+    pub fn acquire<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir).context(ErrorKind::Io, dir)?;
+        let lock_path = dir.join(LOCK_FILE_NAME);
+        let pid_path = dir.join(PID_FILE_NAME);
+
+        let file = OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path)
+            .context(ErrorKind::Io, &lock_path)?;
+        if let Err(err) = try_lock(&file) {
+            if err.kind() != io::ErrorKind::WouldBlock {
+                return Err(Error::from(err).with_path(lock_path));
+            }
+            let message = match read_pid(&pid_path) {
+                Some(pid) => format!("another backupfsd is running (pid {})", pid),
+                None => "another backupfsd is running".to_string(),
+            };
+            return Err(Error::new(ErrorKind::AlreadyRunning, message).with_path(lock_path));
+        }
+
+        // ロックを取得できた場合、残っているPIDファイルは異常終了したプロセスのもの
+        if let Some(pid) = read_pid(&pid_path) {
+            warn!("removing stale pid file (pid {})", pid);
+        }
+        fs::write(&pid_path, format!("{}\n", process::id())).context(ErrorKind::Io, &pid_path)?;
+
+        Ok(InstanceLock { _file: file, pid_path })
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        // ロックファイルは削除しない。(削除すると、別のプロセスが別のファイルをロックできてしまう)
+        if let Err(err) = fs::remove_file(&self.pid_path) {
+            warn!("{:?}", err);
+        }
+    }
+}
+
+/// FileLock構造体
+/// ロックファイルを排他的にロックし、backupfsd と backupfs-client の間で同じファイルの更新を直列化する。
+/// 他のプロセスがロックを保持している場合は、解放されるまで待つ。
+/// 破棄された際(プロセスの終了を含む)に解放される。
+pub struct FileLock {
+    _file: File,
+}
+
+impl FileLock {
+    /// ロックを取得する。ロックファイルがない場合は作成する。
+    pub fn acquire<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).truncate(false).write(true).open(path)?;
+        lock(&file)?;
+        Ok(FileLock { _file: file })
+    }
+}
+
+fn read_pid(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+#[cfg(unix)]
+fn try_lock(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    if unsafe { ::libc::flock(file.as_raw_fd(), ::libc::LOCK_EX | ::libc::LOCK_NB) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn try_lock(_file: &File) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn lock(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    loop {
+        if unsafe { ::libc::flock(file.as_raw_fd(), ::libc::LOCK_EX) } == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn lock(_file: &File) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_acquire() {
+        let dir = env::temp_dir().join(format!("backupfs-lock-test-{}", process::id()));
+        {
+            let _lock = InstanceLock::acquire(&dir).unwrap();
+            assert_eq!(Some(process::id()), read_pid(&dir.join(PID_FILE_NAME)));
+            // flockは開いたファイルごとのため、同じプロセス内でも2つ目は取得できない。
+            let err = InstanceLock::acquire(&dir).err().unwrap();
+            assert_eq!(ErrorKind::AlreadyRunning, err.kind());
+        }
+        assert!(!dir.join(PID_FILE_NAME).exists());
+        InstanceLock::acquire(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_lock() {
+        use std::sync::mpsc;
+        use std::thread;
+        use std::time::Duration;
+
+        let path = env::temp_dir().join(format!("backupfs-file-lock-test-{}", process::id())).join("a.lock");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let lock = FileLock::acquire(&path).unwrap();
+        // 解放されるまで待つ。
+        let (sender, receiver) = mpsc::channel();
+        let waiting = path.clone();
+        let handle = thread::spawn(move || {
+            let _lock = FileLock::acquire(&waiting).unwrap();
+            sender.send(()).unwrap();
+        });
+        assert!(receiver.recv_timeout(Duration::from_millis(200)).is_err());
+        drop(lock);
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        handle.join().unwrap();
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,80 @@
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use chrono::prelude::*;
+use env_logger;
+use log::Level;
+use serde_json::{self, Map, Value};
+
+use journal::{Event, EventKind};
+
+/// イベントのログのターゲット
+/// JSON形式の場合、メッセージ(イベントのJSON)をそのまま各フィールドとして出力する。
+const EVENT_TARGET: &str = "backupfs::event";
+
+/// JSON形式で出力しているかどうか
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// LogFormat列挙型
+/// ログの出力形式
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LogFormat {
+    /// env_logger のデフォルトの形式
+    Text,
+    /// 1行に1つのJSONオブジェクト (time, level, target, message と、イベントの各フィールド)
+    Json,
+}
+
+impl LogFormat {
+    /// "text" | "json" を解析する。
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "text" => Some(LogFormat::Text),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// ロガーを初期化する。
+/// 出力するレベルは env_logger と同様に RUST_LOG で指定する。
+pub fn init(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if format == LogFormat::Json {
+        JSON.store(true, Ordering::SeqCst);
+        builder.format(|buf, record| {
+            let mut object = Map::new();
+            object.insert("time".to_string(), Value::from(Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)));
+            object.insert("level".to_string(), Value::from(record.level().to_string()));
+            let message = record.args().to_string();
+            match serde_json::from_str::<Map<String, Value>>(&message) {
+                Ok(fields) if record.target() == EVENT_TARGET => object.extend(fields),
+                _ => {
+                    object.insert("target".to_string(), Value::from(record.target()));
+                    object.insert("message".to_string(), Value::from(message));
+                },
+            }
+            writeln!(buf, "{}", Value::Object(object))
+        });
+    }
+    if let Err(err) = builder.try_init() {
+        eprintln!("{:?}", err);
+    }
+}
+
+/// イベントをログへ出力する。
+/// 失敗、保留のイベントは警告、それ以外は情報のレベルとする。
+pub fn event(event: &Event) {
+    let level = match event.event {
+        EventKind::Failure | EventKind::Missing | EventKind::Suspicious => Level::Warn,
+        _ => Level::Info,
+    };
+    if JSON.load(Ordering::SeqCst) {
+        match serde_json::to_string(event) {
+            Ok(json) => log!(target: EVENT_TARGET, level, "{}", json),
+            Err(err) => error!("{:?}", err),
+        }
+    } else {
+        log!(level, "{}", event);
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde_json;
+
+use lock::FileLock;
+
+/// バックアップ対象ごとのディレクトリに記録する、アーカイブの印のファイル名
+pub const MARKS_FILE_NAME: &str = "marks.json";
+
+/// 印の読み書きを backupfsd と backupfs-client の間で直列化するロックファイル名
+const MARKS_LOCK_FILE_NAME: &str = ".marks.lock";
+
+/// Marks構造体
+/// バックアップ対象ごとのディレクトリに記録する、古いアーカイブの削除の一時停止と、
+/// アーカイブ(スナップショット)ごとの印
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct Marks {
+    /// 古いアーカイブの削除を一時停止しているかどうか
+    /// (不審な変更を検知してから `backupfs-client ack` で確認されるまで)
+    #[serde(default)]
+    pub prune_paused: bool,
+    /// アーカイブ(スナップショット)の名前ごとの印
+    #[serde(default)]
+    pub archives: BTreeMap<String, ArchiveMarks>,
+}
+
+/// ArchiveMarks構造体
+/// アーカイブ(スナップショット)1件分の印
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct ArchiveMarks {
+    /// 不審な変更を含む疑いがある場合の理由
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suspicious: Option<String>,
+    /// 保持(リーガルホールド)
+    /// 設定されている間は、保持数によらず削除しない。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hold: Option<Hold>,
+}
+
+/// Hold構造体
+/// アーカイブ(スナップショット)の保持(リーガルホールド)の情報
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Hold {
+    /// 保持を設定した時刻 (RFC 3339)
+    pub time: String,
+    /// 保持の理由
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl ArchiveMarks {
+    /// 印が何もないかどうか
+    pub fn is_empty(&self) -> bool {
+        self.suspicious.is_none() && self.hold.is_none()
+    }
+}
+
+impl Marks {
+    /// バックアップ対象ごとのディレクトリから読み込む。
+    /// 記録されていない場合は空となる。
+    pub fn load<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        match fs::read(dir.as_ref().join(MARKS_FILE_NAME)) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(Marks::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// バックアップ対象ごとのディレクトリへ記録する。
+    /// 途中まで書き込まれたファイルが残らないよう、一時ファイルから置き換える。
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> io::Result<()> {
+        let path = dir.as_ref().join(MARKS_FILE_NAME);
+        let tmp = dir.as_ref().join(format!(".{}.tmp", MARKS_FILE_NAME));
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp, &path)
+    }
+
+    /// 印の読み込みから記録までの間、他の更新(backupfs-client hold など)を待たせるロックを取得する。
+    /// 保持数による削除では、削除を終えて記録するまで保持し続ける。
+    pub fn lock<P: AsRef<Path>>(dir: P) -> io::Result<FileLock> {
+        FileLock::acquire(dir.as_ref().join(MARKS_LOCK_FILE_NAME))
+    }
+
+    /// ロックを取得したうえで読み込み、更新して記録する。
+    pub fn update<P: AsRef<Path>, F: FnOnce(&mut Marks)>(dir: P, f: F) -> io::Result<Self> {
+        let _lock = Marks::lock(dir.as_ref())?;
+        let mut marks = Marks::load(dir.as_ref())?;
+        f(&mut marks);
+        marks.save(dir)?;
+        Ok(marks)
+    }
+
+    /// アーカイブ(スナップショット)の印を取得する。
+    pub fn archive(&self, name: &str) -> Option<&ArchiveMarks> {
+        self.archives.get(name)
+    }
+
+    /// アーカイブ(スナップショット)が保持されているかどうか
+    pub fn is_held(&self, name: &str) -> bool {
+        self.archive(name).map(|marks| marks.hold.is_some()).unwrap_or(false)
+    }
+
+    /// アーカイブ(スナップショット)の保持を設定する。
+    pub fn hold(&mut self, name: &str, time: String, reason: Option<String>) {
+        self.archives.entry(name.to_string()).or_default().hold = Some(Hold { time, reason });
+    }
+
+    /// アーカイブ(スナップショット)の保持を解除する。
+    /// 保持されていた場合は true を返却する。
+    pub fn release(&mut self, name: &str) -> bool {
+        let released = match self.archives.get_mut(name) {
+            Some(marks) => marks.hold.take().is_some(),
+            None => false,
+        };
+        if self.archives.get(name).map(ArchiveMarks::is_empty).unwrap_or(false) {
+            self.archives.remove(name);
+        }
+        released
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_marks() {
+        let dir = env::temp_dir().join(format!("backupfs-marks-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        assert_eq!(Marks::default(), Marks::load(&dir).unwrap());
+        Marks::update(&dir, |marks| {
+            marks.prune_paused = true;
+            marks.archives.entry("a.zip".to_string()).or_default().suspicious = Some("x".to_string());
+        }).unwrap();
+        let mut marks = Marks::load(&dir).unwrap();
+        assert!(marks.prune_paused);
+        assert_eq!(Some("x"), marks.archive("a.zip").and_then(|m| m.suspicious.as_deref()));
+
+        marks.hold("b.zip", "2018-10-18T00:00:00Z".to_string(), None);
+        assert!(marks.is_held("b.zip"));
+        assert!(marks.release("b.zip"));
+        assert!(!marks.release("b.zip"));
+        assert!(marks.archive("b.zip").is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use chrono::prelude::*;
+
+use space;
+use systemd;
+
+/// ハッシュ値の計算時間のヒストグラムの区切り(秒)
+const HASH_BUCKETS: &[f64] = &[0.01, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0];
+/// アーカイブの作成時間のヒストグラムの区切り(秒)
+const ARCHIVE_BUCKETS: &[f64] = &[1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 1800.0, 3600.0];
+
+/// リクエストの読み込みのタイムアウト
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// MetricsConfig構造体
+/// Prometheus形式のメトリクスを公開するHTTPサーバーの設定 (`[metrics]`)
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// HTTPサーバーを起動するかどうか (デフォルト: false)
+    pub enabled: bool,
+    /// 待ち受けるアドレス (デフォルト: "127.0.0.1:9898")
+    pub listen: String,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig { enabled: false, listen: "127.0.0.1:9898".to_string() }
+    }
+}
+
+/// Histogram構造体
+/// 累積のバケットごとの件数と、合計値
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram { bounds, counts: vec![0; bounds.len()], sum: 0.0, count: 0 }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, sep, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, sep, self.count);
+        let _ = writeln!(out, "{}_sum{} {}", name, braces(labels), self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, braces(labels), self.count);
+    }
+}
+
+/// TargetMetrics構造体
+/// バックアップ対象ごとのメトリクス
+struct TargetMetrics {
+    succeeded: u64,
+    failed: u64,
+    bytes: u64,
+    protect_failures: u64,
+    archive_duration: Histogram,
+    last_success: Option<i64>,
+}
+
+impl TargetMetrics {
+    fn new() -> Self {
+        TargetMetrics {
+            succeeded: 0,
+            failed: 0,
+            bytes: 0,
+            protect_failures: 0,
+            archive_duration: Histogram::new(ARCHIVE_BUCKETS),
+            last_success: None,
+        }
+    }
+}
+
+/// Registry構造体
+/// デーモン全体のメトリクス
+struct Registry {
+    cycles: u64,
+    checked: u64,
+    changed: u64,
+    hash_duration: Option<Histogram>,
+    targets: BTreeMap<PathBuf, TargetMetrics>,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    cycles: 0,
+    checked: 0,
+    changed: 0,
+    hash_duration: None,
+    targets: BTreeMap::new(),
+});
+
+/// 変更検知 & バックアップ処理の1回分を記録する。
+pub fn cycle() {
+    lock().cycles += 1;
+}
+
+/// バックアップ対象の変更の確認(ハッシュ値の計算)を記録する。
+pub fn checked(changed: bool, duration: Duration) {
+    let mut registry = lock();
+    registry.checked += 1;
+    if changed {
+        registry.changed += 1;
+    }
+    registry.hash_duration.get_or_insert_with(|| Histogram::new(HASH_BUCKETS))
+        .observe(duration.as_secs_f64());
+}
+
+/// アーカイブの作成を記録する。
+pub fn archived(path: &Path, bytes: u64, duration: Duration) {
+    let mut registry = lock();
+    let target = registry.targets.entry(path.to_path_buf()).or_insert_with(TargetMetrics::new);
+    target.bytes += bytes;
+    target.archive_duration.observe(duration.as_secs_f64());
+}
+
+/// バックアップの成功を記録する。
+pub fn succeeded(path: &Path, at: DateTime<Utc>) {
+    let mut registry = lock();
+    let target = registry.targets.entry(path.to_path_buf()).or_insert_with(TargetMetrics::new);
+    target.succeeded += 1;
+    target.last_success = Some(at.timestamp());
+}
+
+/// バックアップの失敗を記録する。
+pub fn failed(path: &Path) {
+    lock().targets.entry(path.to_path_buf()).or_insert_with(TargetMetrics::new).failed += 1;
+}
+
+/// アーカイブの保護で、属性を設定できなかったファイルの数を記録する。
+pub fn protect_failed(path: &Path, files: usize) {
+    lock().targets.entry(path.to_path_buf()).or_insert_with(TargetMetrics::new).protect_failures += files as u64;
+}
+
+/// Prometheusのテキスト形式でメトリクスを出力する。
+/// destinationを指定した場合は、その空き容量を含める。
+pub fn render(destination: Option<&Path>) -> String {
+    let registry = lock();
+    let mut out = String::new();
+
+    counter(&mut out, "backupfs_cycles_total", "Number of check cycles run.", registry.cycles);
+    counter(&mut out, "backupfs_targets_checked_total", "Number of target checks (hash computations).", registry.checked);
+    counter(&mut out, "backupfs_targets_changed_total", "Number of target checks that found changes.", registry.changed);
+
+    header(&mut out, "backupfs_hash_duration_seconds", "Time spent hashing a target.", "histogram");
+    registry.hash_duration.as_ref().unwrap_or(&Histogram::new(HASH_BUCKETS))
+        .render(&mut out, "backupfs_hash_duration_seconds", "");
+
+    header(&mut out, "backupfs_backups_total", "Number of finished backups per target.", "counter");
+    for (path, target) in &registry.targets {
+        let label = target_label(path);
+        let _ = writeln!(out, "backupfs_backups_total{{{},result=\"success\"}} {}", label, target.succeeded);
+        let _ = writeln!(out, "backupfs_backups_total{{{},result=\"failure\"}} {}", label, target.failed);
+    }
+
+    header(&mut out, "backupfs_archived_bytes_total", "Bytes written to archives per target.", "counter");
+    for (path, target) in &registry.targets {
+        let _ = writeln!(out, "backupfs_archived_bytes_total{{{}}} {}", target_label(path), target.bytes);
+    }
+
+    header(&mut out, "backupfs_protect_failures_total", "Files whose protection attribute could not be set.", "counter");
+    for (path, target) in &registry.targets {
+        let _ = writeln!(out, "backupfs_protect_failures_total{{{}}} {}", target_label(path), target.protect_failures);
+    }
+
+    header(&mut out, "backupfs_archive_duration_seconds", "Time spent creating an archive.", "histogram");
+    for (path, target) in &registry.targets {
+        target.archive_duration.render(&mut out, "backupfs_archive_duration_seconds", &target_label(path));
+    }
+
+    header(&mut out, "backupfs_last_success_timestamp_seconds", "Unix time of the last successful backup.", "gauge");
+    for (path, target) in &registry.targets {
+        if let Some(t) = target.last_success {
+            let _ = writeln!(out, "backupfs_last_success_timestamp_seconds{{{}}} {}", target_label(path), t);
+        }
+    }
+
+    if let Some(destination) = destination {
+        match space::available(destination) {
+            Ok(free) => {
+                header(&mut out, "backupfs_destination_free_bytes", "Free space at the destination.", "gauge");
+                let _ = writeln!(out, "backupfs_destination_free_bytes {}", free);
+            },
+            Err(err) => debug!("metrics: {:?}", err),
+        }
+    }
+    out
+}
+
+/// メトリクスを公開するHTTPサーバーを別スレッドで起動する。
+/// ソケットアクティベーションで "metrics" という名前(FileDescriptorName)のソケットが渡された場合は、
+/// 設定のアドレスではなくそれを利用する。
+pub fn serve(config: &MetricsConfig, destination: PathBuf) -> io::Result<()> {
+    let listener = match systemd::listen_fd("metrics") {
+        Some(fd) => from_fd(fd)?,
+        None => TcpListener::bind(&config.listen)?,
+    };
+    info!("metrics: listening on {}", listener.local_addr()?);
+    thread::Builder::new()
+        .name("backupfs-metrics".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let res = stream.and_then(|stream| respond(stream, &destination));
+                if let Err(err) = res {
+                    debug!("metrics: {:?}", err);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// リクエストを1件処理する。`GET /metrics` 以外は404とする。
+fn respond(mut stream: TcpStream, destination: &Path) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut request_line = String::new();
+    let mut reader = BufReader::new(stream.try_clone()?);
+    reader.read_line(&mut request_line)?;
+    // ヘッダーは読み捨てる。
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && line.trim_end() != "" {
+        line.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(Some(destination))),
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+           status, body.len(), body)?;
+    stream.flush()
+}
+
+#[cfg(unix)]
+fn from_fd(fd: i32) -> io::Result<TcpListener> {
+    use std::os::unix::io::FromRawFd;
+    Ok(unsafe { TcpListener::from_raw_fd(fd) })
+}
+
+#[cfg(not(unix))]
+fn from_fd(_fd: i32) -> io::Result<TcpListener> {
+    Err(io::Error::other("socket activation is not supported on this platform"))
+}
+
+fn lock() -> MutexGuard<'static, Registry> {
+    REGISTRY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, help, "counter");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn braces(labels: &str) -> String {
+    if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) }
+}
+
+/// バックアップ対象のパスのラベル (エスケープ済み)
+fn target_label(path: &Path) -> String {
+    let value = path.to_string_lossy()
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("target=\"{}\"", value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::new(&[1.0, 10.0]);
+        histogram.observe(0.5);
+        histogram.observe(5.0);
+        histogram.observe(50.0);
+        let mut out = String::new();
+        histogram.render(&mut out, "x", "target=\"/a\"");
+        assert_eq!("x_bucket{target=\"/a\",le=\"1\"} 1\n\
+                    x_bucket{target=\"/a\",le=\"10\"} 2\n\
+                    x_bucket{target=\"/a\",le=\"+Inf\"} 3\n\
+                    x_sum{target=\"/a\"} 55.5\n\
+                    x_count{target=\"/a\"} 3\n", out);
+    }
+}
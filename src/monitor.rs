@@ -1,29 +1,163 @@
-use std::fs::create_dir_all;
+use std::fs::remove_file;
 use std::mem;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Iter;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use time::precise_time_ns;
+use chrono::prelude::*;
+use walkdir::WalkDir;
 
-use archiver::Archiver;
-use hash::dir_hash;
-use result::Result;
+use anomaly::{self, Finding, MassChangeConfig};
+use catalog::{self, CatalogConfig};
+use archiver::{Archiver, Mirror, Mode, Snapshot, MIRROR_DIR_NAME};
+use config::{Config, TargetConfig};
+use destination::Destination;
+use journal::{self, Event, EventKind};
+use layout;
+use marks::Marks;
+use metrics;
+use naming::Naming;
+use notify::{Notifier, NotifyEvent};
+use guard::{self, DropAction, FileDropConfig};
+use hash::{dir_hash, dir_hash_count};
+use hooks::{self, Hook, HookContext};
+use pool::{Pool, Semaphore};
+use protect::{self, Attribute, ProtectConfig};
+use result::{Error, ErrorKind, Result, ResultExt};
+use retention;
+use retry::Retry;
+use scheduler::Scheduler;
+use space::{self, SpaceConfig};
+use throttle;
 
-/// Monitor構造体  
+/// 実行中のジョブがある場合に、結果を確認するまでの最大の待ち時間
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Monitor構造体
 /// バックアップ対象とmd5ハッシュ値のペアを管理しており、
 /// ファイルのバックアップの可否判断、バックアップ処理の指示を行う。
-pub struct Monitor<A: Archiver + Default> {
+/// ハッシュ値の計算とバックアップ処理はワーカースレッドで並列に行う。
+pub struct Monitor<A: Archiver + Default + Send + 'static> {
     paths: HashMap<PathBuf, Vec<u8>>,
     archiver: A,
     destination: PathBuf,
+    remote: Option<Arc<dyn Destination>>,
+    keep_local: bool,
+    config: Config,
+    naming: Naming,
+    retry: Retry,
+    notifier: Notifier,
+    scheduler: Scheduler,
+    pool: Pool<(PathBuf, Outcome)>,
+    in_flight: HashSet<PathBuf>,
+    files: HashMap<PathBuf, u64>,
+    missing: HashSet<PathBuf>,
+    held: HashSet<PathBuf>,
+    local_io: Arc<Semaphore>,
+    remote_io: Arc<Semaphore>,
+}
+
+/// Plan構造体
+/// dry-run時に表示する、バックアップ対象1件分の予定
+#[derive(Clone, Debug)]
+pub struct Plan {
+    /// バックアップ対象のパス
+    pub path: PathBuf,
+    /// 前回のバックアップから変更があるかどうか
+    pub changed: bool,
+    /// バックアップ対象が見つからないかどうか
+    pub missing: bool,
+    /// バックアップ方式
+    pub mode: Mode,
+    /// アーカイブに含まれるファイル
+    pub files: Vec<PathBuf>,
+    /// 作成するアーカイブ(スナップショット)のパス
+    pub destination: PathBuf,
 }
 
-impl<A: Archiver + Default> Monitor<A> {
+/// Outcome列挙型
+/// バックアップ対象1件分の処理結果
+pub enum Outcome {
+    /// 変更がなかったため、バックアップしなかった。
+    Unchanged,
+    /// バックアップに成功した。
+    Done {
+        /// 新しいmd5ハッシュ値
+        hash: Vec<u8>,
+        /// ファイルの数
+        files: u64,
+        /// 大量の変更を検知した場合の、作成したアーカイブのパスと検知の結果
+        suspicious: Option<(PathBuf, Finding)>,
+    },
+    /// バックアップ対象が見つからないため、バックアップしなかった。
+    Missing,
+    /// ファイルの数が急減したため、バックアップを保留した。(前回、今回のファイルの数)
+    Dropped(u64, u64),
+    /// バックアップに失敗した。
+    Failed(Error),
+}
+
+impl<A: Archiver + Default + Send + 'static> Monitor<A> {
     /// Monitor構造体のコンストラクタ
     pub fn new(archiver: A, paths: HashMap<PathBuf, Vec<u8>>, destination: PathBuf) -> Self {
         debug!("Monitor::new destination: {:?}", destination);
-        Monitor { paths, archiver, destination }
+        let config = Config::default();
+        Monitor {
+            paths,
+            archiver,
+            destination,
+            remote: None,
+            keep_local: true,
+            naming: Naming::default(),
+            retry: Retry::default(),
+            notifier: Notifier::default(),
+            scheduler: Scheduler::default(),
+            pool: Pool::new(config.concurrency.max_parallel()),
+            in_flight: HashSet::new(),
+            files: HashMap::new(),
+            missing: HashSet::new(),
+            held: HashSet::new(),
+            local_io: Arc::new(Semaphore::new(config.concurrency.local_writes())),
+            remote_io: Arc::new(Semaphore::new(config.concurrency.remote_uploads())),
+            config,
+        }
+    }
+
+    /// 設定ファイルの内容を設定する。
+    /// バックアップ対象ごとのバックアップ方式などを参照する。
+    /// 実行中のバックアップがある場合は、完了を待ってから設定する。
+    pub fn set_config(&mut self, config: Config) {
+        self.wait();
+        self.naming = config.naming();
+        self.retry.set_config(config.retry.clone());
+        self.notifier.set_config(config.notify.clone());
+        let last_runs = self.scheduler.last_runs().clone();
+        self.scheduler = Scheduler::new(config.clone());
+        self.scheduler.set_last_runs(last_runs);
+        self.pool = Pool::new(config.concurrency.max_parallel());
+        self.local_io = Arc::new(Semaphore::new(config.concurrency.local_writes()));
+        self.remote_io = Arc::new(Semaphore::new(config.concurrency.remote_uploads()));
+        self.config = config;
+    }
+
+    /// バックアップ先のディレクトリを取得する。
+    pub fn destination(&self) -> &Path {
+        &self.destination
+    }
+
+    /// 設定ファイルの内容を取得する。
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// アーカイブの転送先(S3など)を設定する。Noneの場合は転送しない。
+    /// keep_local が false の場合は、転送に成功したローカルのアーカイブを削除する。
+    pub fn set_remote(&mut self, remote: Option<Box<dyn Destination>>, keep_local: bool) {
+        self.remote = remote.map(Arc::from);
+        self.keep_local = keep_local;
     }
 
     /// バックアップ対象のパスとmd5ハッシュ値のキャッシュを設定する。
@@ -36,83 +170,683 @@ impl<A: Archiver + Default> Monitor<A> {
         self.paths.iter()
     }
 
-    /// 更新検知 & バックアップ処理関数  
-    /// 一定時間ごとに本関数を呼び出すことを期待する。
+    /// スケジュールによるバックアップの前回の実行時刻を設定する。
+    pub fn set_last_runs(&mut self, last_runs: HashMap<PathBuf, DateTime<Utc>>) {
+        self.scheduler.set_last_runs(last_runs);
+    }
+
+    /// スケジュールによるバックアップの前回の実行時刻を取得する。
+    pub fn last_runs(&self) -> &HashMap<PathBuf, DateTime<Utc>> {
+        self.scheduler.last_runs()
+    }
+
+    /// 前回のバックアップ時のファイルの数を設定する。
+    pub fn set_files(&mut self, files: HashMap<PathBuf, u64>) {
+        self.files = files;
+    }
+
+    /// 前回のバックアップ時のファイルの数を取得する。
+    pub fn files(&self) -> &HashMap<PathBuf, u64> {
+        &self.files
+    }
+
+    /// 見つからないバックアップ対象を取得する。
+    pub fn missing(&self) -> &HashSet<PathBuf> {
+        &self.missing
+    }
+
+    /// ファイルの数の急減により、バックアップを保留しているバックアップ対象を取得する。
+    pub fn held(&self) -> &HashSet<PathBuf> {
+        &self.held
+    }
+
+    /// 次に now() を呼び出すまでの待ち時間を取得する。
+    /// 実行中のバックアップがある場合は、結果を反映するため短い間隔となる。
+    pub fn next_wake(&self) -> Duration {
+        let wait = self.scheduler.next_wake(self.paths.keys(), Instant::now());
+        if self.in_flight.is_empty() { wait } else { wait.min(POLL_INTERVAL) }
+    }
+
+    /// バックアップの再試行の状態を取得する。
+    pub fn retry(&self) -> &Retry {
+        &self.retry
+    }
+
+    /// 旧形式のレイアウトのアーカイブを、バックアップ対象ごとのディレクトリへ移動する。
+    /// 起動時に一度呼び出すことを期待する。
+    pub fn migrate_layout(&self) -> Result<usize> {
+        let paths: Vec<PathBuf> = self.paths.keys().cloned().collect();
+        let mut count = 0;
+        for path in &paths {
+            match layout::migrate(&self.destination, path, &paths) {
+                Ok(n) => count += n,
+                Err(err) => error!("{:?}", err),
+            }
+        }
+        Ok(count)
+    }
+
+    /// バックアップを行った場合の予定を取得する。(dry-run)
+    /// ハッシュ値の計算のみ行い、バックアップ先へは何も書き込まない。
+    pub fn plan(&self) -> Vec<Plan> {
+        let mut plans: Vec<Plan> = self.paths.iter().map(|(path, hash)| {
+            let target = self.config.target(path);
+            let changed = dir_hash(path).map(|h| &h != hash).unwrap_or(true);
+            let dest_dir = layout::target_dir(&self.destination, path);
+            let file_name = match target.mode {
+                Mode::Zip => format!("{}.zip", self.naming.preview(path, &dest_dir)),
+                Mode::Mirror => MIRROR_DIR_NAME.to_string(),
+                Mode::Snapshot => self.naming.preview(path, &dest_dir),
+            };
+            let files = WalkDir::new(path).into_iter()
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().is_file())
+                .map(|e| e.path().to_path_buf())
+                .collect();
+            Plan {
+                path: path.clone(),
+                changed,
+                missing: !path.exists(),
+                mode: target.mode,
+                files,
+                destination: dest_dir.join(file_name),
+            }
+        }).collect();
+        plans.sort_by(|a, b| a.path.cmp(&b.path));
+        plans
+    }
+
+    /// 更新検知 & バックアップ処理関数
+    /// next_wake() の時間ごとに本関数を呼び出すことを期待する。
+    /// バックアップ対象ごとの変更検知の間隔、スケジュールに従って、
+    /// ハッシュ値の計算とバックアップをワーカースレッドへ依頼する。
+    /// 実行中の対象は、完了するまで重ねて依頼しない。
+    /// 前回までに依頼し、完了したバックアップの数を返却する。
     pub fn now(&mut self) -> Result<usize> {
+        metrics::cycle();
+        let results = self.pool.try_results();
+        let count = self.apply(results);
+        let now = Instant::now();
+        let utc_now = Utc::now();
+
+        let paths: Vec<PathBuf> = self.paths.keys().cloned().collect();
+        for path in paths {
+            if self.in_flight.contains(&path) {
+                continue;
+            }
+
+            // スケジュールによる実行時刻の場合は、変更の有無や再試行の待ちによらずバックアップする。
+            let scheduled = self.scheduler.take_due(&path, utc_now);
+            if !scheduled {
+                // 失敗した対象は、変更検知の設定によらず再試行の時刻になり次第確認する。
+                let check = self.scheduler.take_check(&path, now) || self.retry.has_failed(&path);
+                if !check || self.retry.is_waiting(&path, now) {
+                    continue;
+                }
+            }
+
+            let job = Job {
+                hash: self.paths.get(&path).cloned().unwrap_or_default(),
+                files: self.files.get(&path).cloned(),
+                file_drop: self.config.file_drop.clone(),
+                mass_change: self.config.mass_change.clone(),
+                force: scheduled || self.retry.has_failed(&path),
+                target: self.config.target(&path),
+                destination: self.destination.clone(),
+                naming: self.naming.clone(),
+                space: self.config.space.clone(),
+                protect: self.config.protect.clone(),
+                catalog: self.config.catalog.clone(),
+                archiver: mem::take(&mut self.archiver),
+                remote: self.remote.clone(),
+                keep_local: self.keep_local,
+                local_io: self.local_io.clone(),
+                remote_io: self.remote_io.clone(),
+                path: path.clone(),
+            };
+            self.in_flight.insert(path.clone());
+            self.pool.execute(move || {
+                // ワーカーでのパニックは失敗として扱い、対象が実行中のまま残らないようにする。
+                let outcome = panic::catch_unwind(AssertUnwindSafe(|| job.run()))
+                    .unwrap_or_else(|_| Outcome::Failed(
+                        Error::new(ErrorKind::ArchiveFailed, "worker panicked").with_path(&path)));
+                (path, outcome)
+            });
+        }
+
+        Ok(count)
+    }
+
+    /// 実行中のバックアップがすべて完了するまで待つ。
+    /// 完了したバックアップの数を返却する。
+    pub fn wait(&mut self) -> usize {
         let mut count = 0;
+        while let Some(result) = self.pool.wait_one() {
+            count += self.apply(vec![result]);
+        }
+        count
+    }
+
+    /// 実行中のバックアップの完了を deadline まで待つ。
+    /// 完了したバックアップの数を返却する。
+    pub fn wait_until(&mut self, deadline: Instant) -> usize {
+        let mut count = 0;
+        while let Some(result) = self.pool.wait_one_until(deadline) {
+            count += self.apply(vec![result]);
+        }
+        count
+    }
+
+    /// 実行中(実行待ちを含む)のバックアップの数を取得する。
+    pub fn running(&self) -> usize {
+        self.pool.pending()
+    }
+
+    /// 終了処理
+    /// 実行中のバックアップの完了を timeout まで待ち、超えた場合は中断させる。
+    /// 中断されたバックアップは失敗として扱い、作成途中のZIP、スナップショットは削除される。
+    /// (ミラーは同期済みのファイルのみ更新された状態となる)
+    /// 完了したバックアップの数を返却する。
+    pub fn shutdown(&mut self, timeout: Duration) -> usize {
+        let mut count = self.wait_until(Instant::now() + timeout);
+        if self.pool.pending() > 0 {
+            warn!("aborting {} running backups", self.pool.pending());
+            throttle::abort();
+            count += self.wait();
+            throttle::resume();
+        }
+        count
+    }
+
+    /// ワーカーの処理結果を反映する。
+    /// md5ハッシュ値はバックアップに成功した場合のみ更新し、
+    /// 失敗した対象は変更がなくても再試行の時刻になり次第、再度バックアップする。
+    fn apply(&mut self, results: Vec<(PathBuf, Outcome)>) -> usize {
+        let mut count = 0;
+        for (path, outcome) in results {
+            self.in_flight.remove(&path);
+            if let Outcome::Missing = outcome {
+                // 見つからなくなった時点で1度だけ記録、通知する。
+                if self.missing.insert(path.clone()) {
+                    journal::record(Event::new(EventKind::Missing, &path));
+                    self.notifier.notify(NotifyEvent::Missing, &path, "no such file or directory".to_string());
+                }
+                continue;
+            }
+            if self.missing.remove(&path) {
+                journal::record(Event::new(EventKind::Reappeared, &path));
+                self.notifier.notify(NotifyEvent::Recovery, &path, "target reappeared".to_string());
+            }
+            if !matches!(outcome, Outcome::Dropped(..)) {
+                self.held.remove(&path);
+            }
+            match outcome {
+                Outcome::Unchanged | Outcome::Missing => {},
+                // 保留した時点で1度だけ記録、通知する。
+                Outcome::Dropped(..) if self.held.contains(&path) => {},
+                Outcome::Dropped(previous, current) => {
+                    self.held.insert(path.clone());
+                    let message = format!("file count dropped from {} to {}, backup skipped \
+                                           (run `backupfs-client ack` to accept)", previous, current);
+                    journal::record(Event::new(EventKind::Suspicious, &path).with_message(message.clone()));
+                    self.notifier.notify(NotifyEvent::FileDrop, &path, message);
+                },
+                Outcome::Done { hash, files, suspicious } => {
+                    if let Some(h) = self.paths.get_mut(&path) {
+                        *h = hash;
+                    }
+                    if let Some((archive, finding)) = suspicious {
+                        let message = format!("{}, archive marked suspicious and pruning paused \
+                                               (run `backupfs-client ack` to resume)", finding);
+                        journal::record(Event::new(EventKind::Suspicious, &path)
+                            .with_archive(&archive)
+                            .with_message(message.clone()));
+                        self.notifier.notify(NotifyEvent::Suspicious, &path, message);
+                    }
+                    // 急減してもバックアップする設定の場合は、通知のみ行う。
+                    if let Some(previous) = self.files.insert(path.clone(), files) {
+                        if self.config.file_drop.dropped(previous, files) {
+                            self.notifier.notify(NotifyEvent::FileDrop, &path,
+                                                 format!("file count dropped from {} to {}", previous, files));
+                        }
+                    }
+                    if let Some(failure) = self.retry.failure(&path) {
+                        self.notifier.notify(NotifyEvent::Recovery, &path,
+                                             format!("succeeded after {} failures", failure.count()));
+                    }
+                    self.retry.succeeded(&path);
+                    metrics::succeeded(&path, Utc::now());
+                    if let Ok(available) = space::available(&self.destination) {
+                        self.notifier.check_space(&self.destination, available);
+                    }
+                    count += 1;
+                },
+                Outcome::Failed(err) => {
+                    journal::record(Event::failure(&path, &err));
+                    let event = match err.kind() {
+                        ErrorKind::SourceMissing => NotifyEvent::Missing,
+                        ErrorKind::InsufficientSpace => NotifyEvent::DiskLow,
+                        _ => NotifyEvent::Failure,
+                    };
+                    self.notifier.notify(event, &path, err.chain().join(": "));
+                    if self.retry.failed(&path, &err) {
+                        let count = self.retry.failure(&path).map(|f| f.count()).unwrap_or_default();
+                        self.notifier.notify(NotifyEvent::Unhealthy, &path,
+                                             format!("failed {} times in a row: {}", count, err.chain().join(": ")));
+                    }
+                    metrics::failed(&path);
+                },
+            }
+        }
+        count
+    }
+}
+
+/// バックアップ前のフックコマンドの実行後に計算し直した、ハッシュ値とファイルの数
+type Rehashed = Option<(Vec<u8>, u64)>;
+
+/// Job構造体
+/// ワーカースレッドで処理する、バックアップ対象1件分の情報
+struct Job<A: Archiver> {
+    path: PathBuf,
+    hash: Vec<u8>,
+    files: Option<u64>,
+    file_drop: FileDropConfig,
+    mass_change: MassChangeConfig,
+    force: bool,
+    target: TargetConfig,
+    destination: PathBuf,
+    naming: Naming,
+    space: SpaceConfig,
+    protect: ProtectConfig,
+    catalog: CatalogConfig,
+    archiver: A,
+    remote: Option<Arc<dyn Destination>>,
+    keep_local: bool,
+    local_io: Arc<Semaphore>,
+    remote_io: Arc<Semaphore>,
+}
 
-        for (path, h) in self.paths.iter_mut() {
-            let new_hash = dir_hash(path).unwrap_or_default();
-
-            // 変更を確認する。
-            // 変更がない場合は正常処理として次のバックアップ対象の比較に移る。
-            if *h != new_hash {
-                // 変更がある場合
-
-                *h = new_hash;
-
-                // バックアップ先のパスを生成する。
-                let p = Path::new(path);
-
-                // 1) ルートを含んでいるか、確認する。
-                // ルートを含んでいる場合はルート箇所の削除を行う。
-                // そうでない場合はそのままpに値をバインドする。
-                let p = if p.has_root() {
-                    // TODO: 将来的にWINDOWSなどの対応を行う場合は修正を行う。
-                    p.strip_prefix("/").map(|s| s.to_path_buf()).unwrap_or_default()
-                } else {
-                    p.to_path_buf()
-                };
-
-                // 2) パスがファイルかどうか確認する。
-                // ファイルの場合はファイル名を取り除いたパスにする。
-                // ディレクトリの場合はそのままdir_nameに値をバインドする。
-                let dir_name = if p.is_file() {
-                    p.parent().map(|p| p.to_path_buf()).unwrap_or_default()
-                } else {
-                    p
-                };
-
-                // TODO: zip拡張子をハードコーディングしているため、その他に対応する際に修正する。
-                let file_name = PathBuf::from(format!("{}", precise_time_ns())).with_extension("zip");
-                
-                debug!("{:?}", self.destination);
-                debug!("{:?}", dir_name);
-                debug!("{:?}", file_name);
-
-                // バックアップ先のパスの生成
-                let dest_path = self.destination
-                    .join(dir_name)
-                    .join(file_name);
-
-                debug!("{:?}", dest_path);
-
-                // ファイル名を除くフォルダを取得し、
-                // 存在するか確認する。その際に存在しない場合は、生成する。
-                if let Some(dest_dir_path) = dest_path.parent() {
-                    if !dest_dir_path.exists() {
-                        create_dir_all(dest_dir_path)?;
+impl<A: Archiver> Job<A> {
+    fn run(self) -> Outcome {
+        // 削除やアンマウントされた対象を、すべてのファイルが削除されたものとしてバックアップしない。
+        if !self.path.exists() {
+            return Outcome::Missing;
+        }
+
+        // 読み込めないディレクトリなどがある場合は、ファイルが減った(変更された)とはみなさず失敗とする。
+        let started = Instant::now();
+        let (new_hash, files) = match dir_hash_count(&self.path) {
+            Ok(hashed) => hashed,
+            Err(err) => return Outcome::Failed(err),
+        };
+        metrics::checked(self.hash != new_hash, started.elapsed());
+
+        // 変更を確認する。
+        // 変更がなく、スケジュールや再試行によるバックアップでもない場合は何もしない。
+        if !self.force && self.hash == new_hash {
+            return Outcome::Unchanged;
+        }
+
+        // ファイルの数が急減した場合は、確認されるまでバックアップを保留する。
+        // 保留していることを記録し、backupfs-client ack では保留している場合のみ確認できるようにする。
+        let state_dir = Config::state_dir();
+        if let Some(previous) = self.files {
+            if self.file_drop.action == DropAction::Skip && self.file_drop.dropped(previous, files)
+                && !guard::take_ack(&state_dir, &self.path, files) {
+                if let Err(err) = guard::hold(&state_dir, &self.path, files) {
+                    error!("{:?}", err);
+                }
+                return Outcome::Dropped(previous, files);
+            }
+        }
+        guard::release(&state_dir, &self.path);
+
+        // 前回のバックアップ時のファイルと比較し、暗号化などによる大量の変更を検知する。
+        let manifest = if self.mass_change.enabled { Some(anomaly::scan(&self.path)) } else { None };
+        let finding = manifest.as_ref().and_then(|current| {
+            let previous = anomaly::load_manifest(&state_dir, &self.path)?;
+            anomaly::analyze(&self.mass_change, &self.path, &previous, current)
+        });
+
+        match self.backup(finding.as_ref()) {
+            Ok((archive, rehashed)) => {
+                if let Some(ref manifest) = manifest {
+                    if let Err(err) = anomaly::save_manifest(&state_dir, &self.path, manifest) {
+                        error!("{:?}", err);
                     }
                 }
+                let (hash, files) = rehashed.unwrap_or((new_hash, files));
+                Outcome::Done { hash, files, suspicious: finding.map(|f| (archive, f)) }
+            },
+            Err(err) => Outcome::Failed(err),
+        }
+    }
+
+    /// バックアップし、作成したアーカイブ(スナップショット)のパスを返却する。
+    /// バックアップ前のフックコマンドを実行した場合は、実行後のハッシュ値とファイルの数もあわせて返却する。
+    /// 大量の変更を検知している場合(finding)は、アーカイブを不審として記録し、古いアーカイブの削除を一時停止する。
+    fn backup(&self, finding: Option<&Finding>) -> Result<(PathBuf, Rehashed)> {
+        let path = &self.path;
+        let target = &self.target;
+
+        // ローカルのバックアップ先への同時書き込み数を制限する。
+        let local_io = self.local_io.acquire();
+
+        // バックアップ先のパスを生成する。
+        // バックアップ対象ごとのディレクトリ(<名前>-<ハッシュ>)を作成し、元のパスを記録する。
+        let dest_dir = layout::prepare(&self.destination, path)
+            .context(ErrorKind::DestinationUnwritable, &self.destination)?;
+
+        // ミラー、スナップショットの場合はディレクトリ、ZIPの場合はアーカイブファイルの名前となる。
+        // 名前は設定ファイルの archive_name のテンプレートから生成する。
+        let file_name = match target.mode {
+            Mode::Zip => format!("{}.zip", self.naming.render(path, &dest_dir)),
+            Mode::Mirror => MIRROR_DIR_NAME.to_string(),
+            Mode::Snapshot => self.naming.render(path, &dest_dir),
+        };
+        let dest_path = dest_dir.join(&file_name);
 
-                // [memo]
-                // アーカイブファイルを作成し、出力先のディレクトリは
-                // /hoge/fuga の場合 -> ~/.backupfs_archive/hoge/fuga としたい
-                // しかし、現状のバックアップファイルは /hoge/fuga 内に作成されるため、
-                // バックアップが実行されるたびに、md5ハッシュが変更となり、
-                // 内部の対象ファイルが変更されていない状態でも変更検知が誤作動を起こし、
-                // backupが実行されている状態となっている.
-                let archiver = mem::replace(&mut self.archiver, A::default());
-                let res = archiver.archive(path, &dest_path);
-                if res.is_err() {
-                    error!("{:?}", res.unwrap_err());
-                    continue
+        debug!("{:?}", dest_path);
+
+        let hook_ctx = HookContext { target: path, mode: target.mode, archive: &dest_path, error: None };
+
+        // バックアップ前のフックコマンド
+        // 失敗した場合は設定に応じてバックアップを中止し、失敗として扱う。
+        // コマンドが対象へ書き込んだ場合(DBのダンプなど)に、次回の確認で変更ありとみなして
+        // バックアップを繰り返さないよう、実行後のハッシュ値を記録する。
+        let rehashed = if target.hooks.pre_backup.is_some() {
+            if let Err(err) = hooks::run(&target.hooks, Hook::PreBackup, &hook_ctx) {
+                if target.hooks.skip_on_pre_failure() {
+                    return Err(self.on_failure(err, &hook_ctx));
                 }
-                
-                count += 1;
+                error!("{:?}", err);
+            }
+            match dir_hash_count(path) {
+                Ok(rehashed) => Some(rehashed),
+                Err(err) => return Err(self.on_failure(err.with_path(path), &hook_ctx)),
             }
+        } else {
+            None
+        };
+
+        // バックアップ先の空き容量を確認し、不足する場合は途中で失敗しないようバックアップしない。
+        let estimated = match self.ensure_space(&dest_dir) {
+            Ok(estimated) => estimated,
+            Err(err) => return Err(self.on_failure(err, &hook_ctx)),
+        };
+
+        let started = Instant::now();
+        let res = match target.mode {
+            Mode::Zip => self.archiver.archive(path, &dest_path),
+            Mode::Mirror => Mirror::new(target.mirror_delete).archive(path, &dest_path),
+            Mode::Snapshot => Snapshot.archive(path, &dest_path),
+        };
+        if let Err(err) = res.context(ErrorKind::ArchiveFailed, path) {
+            // 作成途中のZIPファイルを残さない。
+            if target.mode == Mode::Zip && dest_path.is_file() {
+                if let Err(err) = remove_file(&dest_path) {
+                    error!("{:?}", err);
+                }
+            }
+            return Err(self.on_failure(err, &hook_ctx));
         }
+        // ZIPはアーカイブのサイズ、ミラー、スナップショットは変更のあったファイルのサイズとする。
+        let bytes = match target.mode {
+            Mode::Zip => dest_path.metadata().map(|m| m.len()).unwrap_or(0),
+            Mode::Mirror | Mode::Snapshot => estimated,
+        };
+        let elapsed = started.elapsed();
+        metrics::archived(path, bytes, elapsed);
+        journal::record(Event::new(EventKind::Backup, path).with_archive(&dest_path).with_size(bytes, elapsed));
+        drop(local_io);
 
-        Ok(count)
+        // 転送先が設定されている場合は、バックアップ先からの相対パスをキーとして転送する。
+        // ミラー、スナップショットはアーカイブファイルを作成しないため、転送の対象外とする。
+        // 転送に失敗した場合はローカルのアーカイブを残し、バックアップの失敗として再試行させる。
+        if let (Some(ref remote), Mode::Zip) = (&self.remote, target.mode) {
+            let key = dest_path.strip_prefix(&self.destination)
+                .map(|p| p.to_string_lossy().replace('\\', "/"))
+                .unwrap_or_default();
+            let res = {
+                let _remote_io = self.remote_io.acquire();
+                remote.put(&dest_path, &key)
+            };
+            if let Err(err) = res {
+                return Err(self.on_failure(err, &hook_ctx));
+            }
+            if !self.keep_local {
+                if let Err(err) = remove_file(&dest_path) {
+                    error!("{:?}", err);
+                }
+            }
+        }
+
+        // アーカイブ内のファイルをカタログへ追加する。
+        // ミラーは更新し続けるため、転送後にローカルから削除したアーカイブは取り出せないため、いずれも対象外とする。
+        if self.catalog.enabled && target.mode != Mode::Mirror && dest_path.exists() {
+            if let Err(err) = catalog::index(&Config::state_dir(), path, &dest_path) {
+                error!("{:?}", err);
+            }
+        }
+
+        // 不審なアーカイブを記録し、正常な古いアーカイブが削除されないよう削除を一時停止する。
+        if let Some(finding) = finding {
+            let res = Marks::update(&dest_dir, |marks| {
+                marks.prune_paused = true;
+                marks.archives.entry(file_name.clone()).or_default().suspicious = Some(finding.to_string());
+            });
+            if let Err(err) = res {
+                error!("{:?}", err);
+            }
+        }
+
+        // 保持数を超えた古いアーカイブを、ローカルと転送先のそれぞれで削除する。
+        if let Some(keep) = target.retention.keep_last {
+            match retention::prune(&dest_dir, target.mode, keep.max(1)) {
+                Ok(0) => {},
+                Ok(removed) => journal::record(Event::new(EventKind::Prune, path).with_count(removed)),
+                Err(err) => error!("{:?}", err),
+            }
+            if let (Some(ref remote), Mode::Zip) = (&self.remote, target.mode) {
+                match retention::prune_remote(remote.as_ref(), &dest_dir, keep.max(1)) {
+                    Ok(0) => {},
+                    Ok(removed) => journal::record(Event::new(EventKind::Prune, path)
+                        .with_count(removed)
+                        .with_message("remote")),
+                    Err(err) => error!("{:?}", err),
+                }
+            }
+        }
+
+        // バックアップ後のフックコマンド (失敗してもバックアップは成功として扱う)
+        if let Err(err) = hooks::run(&target.hooks, Hook::PostBackup, &hook_ctx) {
+            error!("{:?}", err);
+        }
+
+        // 作成したアーカイブを削除や改変から保護する。(ミラーは更新し続けるため対象外)
+        if target.mode != Mode::Mirror && dest_path.exists() {
+            match protect::protect(&dest_path, &self.protect) {
+                Ok(0) => {},
+                Ok(failed) => metrics::protect_failed(path, failed),
+                Err(err) => error!("{:?}", err),
+            }
+        }
+
+        Ok((dest_path, rehashed))
+    }
+
+    /// バックアップに必要な容量と、最小の空き容量を確保できるか確認する。
+    /// 不足する場合、保持数が設定されていれば保持数を超えた古いものを先に削除する。
+    /// 新しいアーカイブの作成に失敗しても復元できるよう、保持数の範囲のアーカイブは削除しない。
+    /// 見積もったバックアップに必要な容量を返却する。
+    fn ensure_space(&self, dest_dir: &Path) -> Result<u64> {
+        let mode = self.target.mode;
+        // ミラー、スナップショットは前回から変更のあったファイルの分のみ容量を消費する。
+        // ただし immutable、append-only 属性を設定したスナップショットはハードリンクできず、すべてコピーする。
+        let reference = match mode {
+            Mode::Zip => None,
+            Mode::Mirror => Some(dest_dir.join(MIRROR_DIR_NAME)),
+            Mode::Snapshot if self.protect.attribute != Attribute::None => None,
+            Mode::Snapshot => Snapshot::list(dest_dir).ok().and_then(|list| list.last().cloned()),
+        };
+        let estimated = space::estimate(&self.path, reference.as_deref());
+        let required = estimated.saturating_add(self.space.min_free);
+
+        let mut available = space::available(dest_dir).context(ErrorKind::DestinationUnwritable, dest_dir)?;
+        if available < required {
+            if let Some(keep) = self.target.retention.keep_last {
+                let removed = retention::prune(dest_dir, mode, keep.max(1))
+                    .context(ErrorKind::DestinationUnwritable, dest_dir)?;
+                if removed > 0 {
+                    info!("retention: pruned {} archives early to free space", removed);
+                    journal::record(Event::new(EventKind::Prune, &self.path).with_count(removed));
+                    available = space::available(dest_dir).context(ErrorKind::DestinationUnwritable, dest_dir)?;
+                }
+            }
+        }
+        if available < required {
+            return Err(Error::new(ErrorKind::InsufficientSpace, format!(
+                "{} bytes required (including {} bytes reserve), {} bytes available",
+                required, self.space.min_free, available,
+            )).with_path(dest_dir));
+        }
+        Ok(estimated)
     }
-}
\ No newline at end of file
+
+    /// 失敗時のフックコマンドを実行し、エラーを返却する。
+    fn on_failure(&self, err: Error, hook_ctx: &HookContext) -> Error {
+        let hook_ctx = HookContext { error: Some(&err), ..*hook_ctx };
+        if let Err(err) = hooks::run(&self.target.hooks, Hook::OnFailure, &hook_ctx) {
+            error!("{:?}", err);
+        }
+        err
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    /// test_failure_retry でのみ切り替える、アーカイブに失敗させるかどうか
+    static FAIL: AtomicBool = AtomicBool::new(true);
+
+    /// FAIL が設定されている間はアーカイブに失敗し、それ以外は空のファイルを作成するArchiver
+    #[derive(Default)]
+    struct TestArchiver;
+
+    impl Archiver for TestArchiver {
+        fn archive<P: AsRef<Path>>(&self, _src: P, dest: P) -> Result<()> {
+            if FAIL.load(Ordering::SeqCst) {
+                return Err(Error::new(ErrorKind::ArchiveFailed, "test failure"));
+            }
+            fs::write(dest.as_ref(), b"").context(ErrorKind::Io, dest.as_ref())
+        }
+    }
+
+    fn monitor(name: &str, files: usize) -> (PathBuf, PathBuf, Monitor<TestArchiver>) {
+        let root = env::temp_dir().join(format!("backupfs-monitor-{}-test-{}", name, process::id()));
+        let src = root.join("src");
+        fs::create_dir_all(&src).unwrap();
+        for i in 0..files {
+            fs::write(src.join(format!("{}.txt", i)), b"a").unwrap();
+        }
+        let config: Config = ::toml::from_str(r#"
+            [retry]
+            initial_backoff = 1
+            max_backoff = 1
+            unhealthy_threshold = 2
+            [catalog]
+            enabled = false
+        "#).unwrap();
+        let mut paths = HashMap::new();
+        paths.insert(src.clone(), Vec::new());
+        let mut monitor = Monitor::new(TestArchiver, paths, root.join("dest"));
+        monitor.set_config(config);
+        (root, src, monitor)
+    }
+
+    #[test]
+    fn test_failure_retry() {
+        let (root, src, mut monitor) = monitor("retry", 1);
+
+        // 失敗した場合はハッシュ値を更新せず、作成途中のアーカイブも残さない。
+        monitor.now().unwrap();
+        assert_eq!(0, monitor.wait());
+        assert!(monitor.retry().has_failed(&src));
+        assert_eq!(Some(&Vec::new()), monitor.paths.get(&src));
+        let dest_dir = layout::target_dir(&root.join("dest"), &src);
+        assert!(retention::list(&dest_dir, Mode::Zip).unwrap().is_empty());
+
+        // 再試行の時刻までは再試行しない。
+        monitor.now().unwrap();
+        assert_eq!(0, monitor.running());
+
+        // 再試行の時刻になり次第、変更がなくても再試行し、閾値まで失敗が続いた場合は異常とみなす。
+        thread::sleep(Duration::from_millis(1100));
+        monitor.now().unwrap();
+        assert_eq!(1, monitor.running());
+        assert_eq!(0, monitor.wait());
+        assert_eq!(2, monitor.retry().failure(&src).unwrap().count());
+        assert_eq!(vec![src.as_path()], monitor.retry().unhealthy());
+
+        // 成功した場合はハッシュ値を更新し、失敗の状態をリセットする。
+        FAIL.store(false, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(1100));
+        monitor.now().unwrap();
+        assert_eq!(1, monitor.wait());
+        assert!(!monitor.retry().has_failed(&src));
+        assert_eq!(Some(&dir_hash(&src).unwrap()), monitor.paths.get(&src));
+        assert_eq!(1, retention::list(&dest_dir, Mode::Zip).unwrap().len());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_missing_target() {
+        let (root, src, mut monitor) = monitor("missing", 1);
+        fs::remove_dir_all(&src).unwrap();
+
+        // 見つからない対象は、空の対象としてアーカイブせず、失敗とも扱わない。
+        monitor.now().unwrap();
+        assert_eq!(0, monitor.wait());
+        assert!(monitor.missing().contains(&src));
+        assert!(!monitor.retry().has_failed(&src));
+        assert_eq!(Some(&Vec::new()), monitor.paths.get(&src));
+        assert!(!layout::target_dir(&root.join("dest"), &src).exists());
+
+        // 再び見つかった場合は見つからない対象から外す。
+        monitor.apply(vec![(src.clone(), Outcome::Unchanged)]);
+        assert!(monitor.missing().is_empty());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_file_drop() {
+        let (root, src, mut monitor) = monitor("drop", 1);
+        let mut files = HashMap::new();
+        files.insert(src.clone(), 20);
+        monitor.set_files(files);
+
+        // ファイルの数が急減した場合はバックアップを保留し、前回のファイルの数を残す。
+        monitor.now().unwrap();
+        assert_eq!(0, monitor.wait());
+        assert!(monitor.held.contains(&src));
+        assert_eq!(Some(&20), monitor.files().get(&src));
+        assert_eq!(Some(&Vec::new()), monitor.paths.get(&src));
+        assert!(!monitor.retry().has_failed(&src));
+
+        // 保留が続く間は保留のまま、保留以外の結果となった場合は保留を解除する。
+        monitor.apply(vec![(src.clone(), Outcome::Dropped(20, 1))]);
+        assert!(monitor.held.contains(&src));
+        monitor.apply(vec![(src.clone(), Outcome::Unchanged)]);
+        assert!(!monitor.held.contains(&src));
+
+        guard::release(&Config::state_dir(), &src);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,231 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use chrono::prelude::*;
+use crypto::digest::Digest;
+use crypto::md5::Md5;
+use time::precise_time_ns;
+
+use result::{Error, ErrorKind, Result};
+
+/// アーカイブ名のデフォルトのテンプレート
+pub const DEFAULT_TEMPLATE: &str = "{time}-{unique}";
+
+/// {time} の書式 (ISO-8601 basic format, UTC)
+const TIME_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// 連番を保存するファイル名
+const SEQ_FILE_NAME: &str = ".seq";
+
+/// 同一プロセス内で{unique}が重複しないようにするためのカウンター
+static UNIQUE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Naming構造体
+/// アーカイブ(スナップショット)の名前をテンプレートから生成する。
+///
+/// 利用できるトークン
+/// - `{time}`   : 作成日時 (例: 20181018T093000Z)
+/// - `{unique}` : 重複を避けるための短いランダムな文字列 (16進数6桁)
+/// - `{host}`   : ホスト名
+/// - `{target}` : バックアップ対象のファイル/ディレクトリ名
+/// - `{seq}`    : バックアップ対象ごとの連番 (6桁)
+#[derive(Clone, Debug)]
+pub struct Naming {
+    template: String,
+    host: String,
+}
+
+impl Naming {
+    /// Naming構造体のコンストラクタ
+    pub fn new<S: Into<String>>(template: S) -> Self {
+        Naming { template: template.into(), host: hostname() }
+    }
+
+    /// テンプレートを検証する。(設定ファイルの読み込み時に呼び出す)
+    /// 作成日時を名前から取得できないと、保持数による削除、移行、カタログの対象とならないため、
+    /// `{time}` を必須とする。また、旧形式と区別できない名前、パスの区切りを含む名前は受け付けない。
+    pub fn validate(template: &str) -> Result<()> {
+        let invalid = |msg: &str| Err(Error::new(ErrorKind::InvalidConfig, format!("archive_name {:?}: {}", template, msg)));
+        if !["{time}", "{seq}", "{unique}"].iter().any(|token| template.contains(token)) {
+            return invalid("every backup would overwrite the same archive, use {time} with {unique} or {seq}");
+        }
+        if !template.contains("{time}") {
+            return invalid("{time} is required to order archives for retention and restore");
+        }
+        // {time} は秒単位のため、同じ秒に作成したアーカイブを区別できない。
+        if !template.contains("{unique}") && !template.contains("{seq}") {
+            return invalid("archives created within the same second would collide, use {time} with {unique} or {seq}");
+        }
+        if template.contains('/') || template.contains('\\') || template.starts_with('.') {
+            return invalid("must not contain path separators or start with '.'");
+        }
+        let name = Naming::new(template).preview(Path::new("target"), Path::new(""));
+        let stem = name.split('.').next().unwrap_or_default();
+        if is_legacy_name(stem) || !is_archive_name(&name) {
+            return invalid("generated names are not recognized as archives");
+        }
+        Ok(())
+    }
+
+    /// テンプレートから名前を生成する。(拡張子は含まない)
+    /// `{seq}` を含む場合のみ、dirに保存された連番を進める。
+    pub fn render(&self, target: &Path, dir: &Path) -> String {
+        self.render_with(target, dir, true)
+    }
+
+    /// render() と同じ名前を、連番を進めずに生成する。(dry-run用)
+    pub fn preview(&self, target: &Path, dir: &Path) -> String {
+        self.render_with(target, dir, false)
+    }
+
+    fn render_with(&self, target: &Path, dir: &Path, advance: bool) -> String {
+        let now = Utc::now();
+        let mut name = self.template
+            .replace("{time}", &now.format(TIME_FORMAT).to_string())
+            .replace("{host}", &sanitize(&self.host))
+            .replace("{target}", &sanitize(&target.file_name()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_else(|| "root".to_string())));
+        if name.contains("{unique}") {
+            name = name.replace("{unique}", &unique());
+        }
+        if name.contains("{seq}") {
+            let seq = if advance { next_seq(dir) } else { current_seq(dir) + 1 };
+            name = name.replace("{seq}", &format!("{:06}", seq));
+        }
+        name
+    }
+}
+
+impl Default for Naming {
+    fn default() -> Self {
+        Naming::new(DEFAULT_TEMPLATE)
+    }
+}
+
+/// アーカイブ名から作成日時を取得する。
+/// テンプレートで生成した名前は{time}の位置を問わず認識する。
+/// 旧形式(precise_time_nsの数値のみ)の名前は時刻を表さないため、
+/// ファイルの更新日時を作成日時とみなす。
+pub fn created_at<P: AsRef<Path>>(path: P) -> Option<DateTime<Utc>> {
+    let path = path.as_ref();
+    let name = path.file_name()?.to_string_lossy().to_string();
+    let stem = name.split('.').next().unwrap_or_default();
+
+    if is_legacy_name(stem) {
+        return fs::metadata(path).and_then(|m| m.modified()).ok().map(DateTime::<Utc>::from);
+    }
+
+    let len = "YYYYMMDDTHHMMSSZ".len();
+    (0..name.len().saturating_sub(len - 1))
+        .filter(|&i| name.is_char_boundary(i) && name.is_char_boundary(i + len))
+        .filter_map(|i| Utc.datetime_from_str(&name[i..i + len], TIME_FORMAT).ok())
+        .next()
+}
+
+/// アーカイブ(スナップショット)として認識できる名前かどうか
+pub fn is_archive_name(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or_default();
+    !name.starts_with('.') && (is_legacy_name(stem) || created_at(name).is_some())
+}
+
+/// パスの一覧を作成日時の古い順に並び替える。
+/// 作成日時が取得できない場合は名前順とする。
+pub fn sort_by_created<P: AsRef<Path>>(paths: &mut [P]) {
+    paths.sort_by_key(|p| {
+        let name = p.as_ref().file_name().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+        (created_at(p).map(|t| t.timestamp_nanos()), name)
+    });
+}
+
+/// 旧形式(precise_time_nsの数値のみ)の名前かどうか
+fn is_legacy_name(stem: &str) -> bool {
+    !stem.is_empty() && stem.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// ファイル名に利用できない文字を '_' に置き換える。
+fn sanitize(s: &str) -> String {
+    s.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '_' })
+        .collect()
+}
+
+fn unique() -> String {
+    let mut md5 = Md5::new();
+    md5.input_str(&format!(
+        "{}-{}-{}",
+        precise_time_ns(),
+        process::id(),
+        UNIQUE_COUNTER.fetch_add(1, Ordering::SeqCst),
+    ));
+    md5.result_str()[..6].to_string()
+}
+
+/// dirに保存された連番を取得する。
+fn current_seq(dir: &Path) -> u64 {
+    fs::read_to_string(dir.join(SEQ_FILE_NAME)).ok()
+        .and_then(|s| s.trim().parse::<u64>().ok())
+        .unwrap_or(0)
+}
+
+/// dirに保存された連番を1つ進めて返却する。
+fn next_seq(dir: &Path) -> u64 {
+    let path = dir.join(SEQ_FILE_NAME);
+    let seq = current_seq(dir) + 1;
+    if let Err(err) = fs::create_dir_all(dir).and_then(|_| fs::write(&path, seq.to_string())) {
+        warn!("{:?}", err);
+    }
+    seq
+}
+
+/// ホスト名を取得する。(取得できない場合は localhost)
+pub fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| fs::read_to_string("/etc/hostname"))
+        .map(|s| s.trim().to_string())
+        .ok()
+        .or_else(|| env::var("HOSTNAME").ok())
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| "localhost".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_created_at() {
+        let naming = Naming { template: "{host}-{time}-{unique}".to_string(), host: "my.host".to_string() };
+        let name = format!("{}.zip", naming.render(Path::new("/tmp/docs"), Path::new("/nonexistent")));
+        assert!(is_archive_name(&name));
+        assert!(created_at(&name).is_some());
+        assert_eq!(
+            Some(Utc.ymd(2018, 10, 18).and_hms(9, 30, 0)),
+            created_at("docs-20181018T093000Z-1a2b3c.zip"),
+        );
+        assert!(is_archive_name("1538724123456789.zip"));
+        assert!(!is_archive_name(".seq"));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Naming::validate(DEFAULT_TEMPLATE).is_ok());
+        assert!(Naming::validate("{host}-{target}-{time}-{seq}").is_ok());
+        // 作成日時を含まない
+        assert!(Naming::validate("{target}-{unique}").is_err());
+        // 同じ秒に作成すると同じ名前となる
+        assert!(Naming::validate("{time}").is_err());
+        assert!(Naming::validate("{host}-{time}").is_err());
+        // 旧形式(数値のみ)と区別できない
+        assert!(Naming::validate("{seq}").is_err());
+        // 毎回同じ名前となる
+        assert!(Naming::validate("{host}-{target}").is_err());
+        assert!(Naming::validate("backup").is_err());
+        // パスの区切りを含む
+        assert!(Naming::validate("{target}/{time}-{unique}").is_err());
+        assert!(Naming::validate(".{time}-{unique}").is_err());
+    }
+}
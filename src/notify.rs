@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::prelude::*;
+use serde_json;
+
+use http::{self, Url};
+use naming;
+use result::{Error, ErrorKind, Result, ResultExt};
+
+/// 同じ種類、同じバックアップ対象の通知を送る最小の間隔(秒)のデフォルト
+const DEFAULT_MIN_INTERVAL: u64 = 3600;
+
+/// NotifyConfig構造体
+/// バックアップの失敗などを通知する設定 (`[notify]`)
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct NotifyConfig {
+    /// 同じ種類、同じバックアップ対象の通知を送る最小の間隔(秒) (デフォルト: 3600)
+    pub min_interval: Option<u64>,
+    /// バックアップ先の空き容量がこの値(バイト)を下回った場合に disk_low を通知する。
+    pub low_space: Option<u64>,
+    /// 通知先 (`[[notify.sink]]`)
+    #[serde(rename = "sink")]
+    pub sinks: Vec<SinkConfig>,
+}
+
+impl NotifyConfig {
+    /// 通知先の設定を検証する。
+    pub fn validate(&self) -> Result<()> {
+        for sink in &self.sinks {
+            let missing = match sink.kind {
+                SinkKind::Desktop => None,
+                SinkKind::Webhook if sink.url.is_none() => Some("url"),
+                SinkKind::Command if sink.command.is_none() => Some("command"),
+                _ => None,
+            };
+            if let Some(field) = missing {
+                return Err(Error::new(ErrorKind::InvalidConfig, format!("notify sink {:?} requires {}", sink.kind, field)));
+            }
+            if let Some(ref url) = sink.url {
+                Url::parse(url).context(ErrorKind::InvalidConfig, url)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn min_interval(&self) -> Duration {
+        Duration::from_secs(self.min_interval.unwrap_or(DEFAULT_MIN_INTERVAL))
+    }
+}
+
+/// SinkConfig構造体
+/// 通知先1件分の設定
+#[derive(Deserialize, Clone, Debug)]
+pub struct SinkConfig {
+    /// 通知先の種類
+    #[serde(rename = "type")]
+    pub kind: SinkKind,
+    /// 通知する種類 (省略時はすべて)
+    pub events: Option<Vec<NotifyEvent>>,
+    /// webhook のURL (JSONをPOSTする)
+    pub url: Option<String>,
+    /// command で実行するコマンド (sh -c で実行する)
+    pub command: Option<String>,
+}
+
+/// SinkKind列挙型
+/// 通知先の種類
+#[derive(Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SinkKind {
+    /// freedesktopのデスクトップ通知 (D-Bus)
+    Desktop,
+    /// HTTPのwebhook
+    Webhook,
+    /// 外部コマンド
+    Command,
+}
+
+/// NotifyEvent列挙型
+/// 通知の種類
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyEvent {
+    /// バックアップに失敗した。
+    Failure,
+    /// 失敗していたバックアップが成功した。
+    Recovery,
+    /// バックアップ対象が見つからない。
+    Missing,
+    /// バックアップ対象のファイルの数が急減した。
+    FileDrop,
+    /// バックアップの失敗が続き、異常とみなした。
+    Unhealthy,
+    /// バックアップ先の空き容量が少ない。
+    DiskLow,
+    /// 暗号化などによる大量の変更の疑いがある。
+    Suspicious,
+}
+
+impl NotifyEvent {
+    /// 種類の名前
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NotifyEvent::Failure => "failure",
+            NotifyEvent::Recovery => "recovery",
+            NotifyEvent::Missing => "missing",
+            NotifyEvent::FileDrop => "file_drop",
+            NotifyEvent::Unhealthy => "unhealthy",
+            NotifyEvent::DiskLow => "disk_low",
+            NotifyEvent::Suspicious => "suspicious",
+        }
+    }
+
+    fn summary(self) -> &'static str {
+        match self {
+            NotifyEvent::Failure => "backup failed",
+            NotifyEvent::Recovery => "backup recovered",
+            NotifyEvent::Missing => "backup target missing",
+            NotifyEvent::FileDrop => "backup file count dropped",
+            NotifyEvent::Unhealthy => "backup unhealthy",
+            NotifyEvent::DiskLow => "backup disk low",
+            NotifyEvent::Suspicious => "suspicious mass change",
+        }
+    }
+}
+
+/// Notice構造体
+/// 通知1件分の内容 (webhook へはJSONとして送る)
+#[derive(Serialize, Clone, Debug)]
+pub struct Notice {
+    pub event: NotifyEvent,
+    pub target: PathBuf,
+    pub message: String,
+    pub host: String,
+    pub time: String,
+}
+
+/// Notifier構造体
+/// 通知の種類とバックアップ対象ごとに間隔を制限し、設定された通知先へ送る。
+#[derive(Default)]
+pub struct Notifier {
+    config: NotifyConfig,
+    last_sent: HashMap<(NotifyEvent, PathBuf), Instant>,
+}
+
+impl Notifier {
+    /// Notifier構造体のコンストラクタ
+    pub fn new(config: NotifyConfig) -> Self {
+        Notifier { config, last_sent: HashMap::new() }
+    }
+
+    /// 設定を変更する。(設定ファイルの再読み込み)
+    /// 再読み込みの直後に同じ通知を重ねて送らないよう、前回の送信時刻は引き継ぐ。
+    pub fn set_config(&mut self, config: NotifyConfig) {
+        self.config = config;
+    }
+
+    /// 空き容量を確認し、設定された値を下回っている場合は disk_low を通知する。
+    pub fn check_space(&mut self, destination: &Path, available: u64) {
+        if let Some(low_space) = self.config.low_space {
+            if available < low_space {
+                self.notify(NotifyEvent::DiskLow, destination,
+                            format!("{} bytes available (threshold {} bytes)", available, low_space));
+            }
+        }
+    }
+
+    /// 通知する。
+    /// 同じ種類、同じ対象の通知を min_interval 以内に送っている場合は送らない。
+    /// 監視処理を止めないよう、通知は別スレッドで送る。
+    pub fn notify<P: AsRef<Path>>(&mut self, event: NotifyEvent, target: P, message: String) {
+        let target = target.as_ref();
+        let sinks: Vec<SinkConfig> = self.config.sinks.iter()
+            .filter(|sink| sink.events.as_ref().map(|events| events.contains(&event)).unwrap_or(true))
+            .cloned()
+            .collect();
+        if sinks.is_empty() {
+            return;
+        }
+
+        if !self.take(event, target, Instant::now()) {
+            debug!("notify: {} {:?} suppressed", event.as_str(), target);
+            return;
+        }
+
+        let notice = Notice {
+            event,
+            target: target.to_path_buf(),
+            message,
+            host: naming::hostname(),
+            time: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        };
+        thread::spawn(move || {
+            for sink in sinks {
+                if let Err(err) = send(&sink, &notice) {
+                    warn!("notify: failed to send to {:?}: {:?}", sink.kind, err);
+                }
+            }
+        });
+    }
+
+    /// 通知を送ってよいか確認し、送る場合は時刻を記録する。
+    fn take(&mut self, event: NotifyEvent, target: &Path, now: Instant) -> bool {
+        let key = (event, target.to_path_buf());
+        if let Some(last) = self.last_sent.get(&key) {
+            if now.duration_since(*last) < self.config.min_interval() {
+                return false;
+            }
+        }
+        self.last_sent.insert(key, now);
+        true
+    }
+}
+
+/// 通知先へ送る。
+fn send(sink: &SinkConfig, notice: &Notice) -> io::Result<()> {
+    match sink.kind {
+        SinkKind::Desktop => desktop(notice),
+        SinkKind::Webhook => {
+            let url = Url::parse(sink.url.as_deref().unwrap_or_default())?;
+            let body = serde_json::to_vec(notice)?;
+            let headers = vec![("Content-Type".to_string(), "application/json".to_string())];
+            let res = http::send("POST", &url, &headers, &body)?;
+            if !res.is_success() {
+                return Err(io::Error::other(format!("webhook returned {}", res.status)));
+            }
+            Ok(())
+        },
+        SinkKind::Command => {
+            let status = Command::new("sh")
+                .arg("-c")
+                .arg(sink.command.as_deref().unwrap_or_default())
+                .env("BACKUPFS_EVENT", notice.event.as_str())
+                .env("BACKUPFS_TARGET", &notice.target)
+                .env("BACKUPFS_MESSAGE", &notice.message)
+                .stdin(Stdio::null())
+                .status()?;
+            if !status.success() {
+                return Err(io::Error::other(format!("command exited with {}", status)));
+            }
+            Ok(())
+        },
+    }
+}
+
+/// freedesktopの通知サービス(org.freedesktop.Notifications)へ D-Bus で送る。
+/// gdbus が利用できない場合は notify-send を利用する。
+fn desktop(notice: &Notice) -> io::Result<()> {
+    let summary = notice.event.summary();
+    let body = format!("{}: {}", notice.target.display(), notice.message);
+    let urgency = if notice.event == NotifyEvent::Recovery { 1 } else { 2 };
+    let res = Command::new("gdbus")
+        .args(["call", "--session",
+                "--dest", "org.freedesktop.Notifications",
+                "--object-path", "/org/freedesktop/Notifications",
+                "--method", "org.freedesktop.Notifications.Notify",
+                "backupfs", "0", "", summary, &body, "[]",
+                &format!("{{'urgency': <byte {}>}}", urgency), "-1"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .status();
+    let status = match res {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Command::new("notify-send")
+            .args(["--app-name=backupfs", "-u", if urgency == 1 { "normal" } else { "critical" }, summary, &body])
+            .stdin(Stdio::null())
+            .status()?,
+        res => res?,
+    };
+    if !status.success() {
+        return Err(io::Error::other(format!("desktop notification exited with {}", status)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit() {
+        let mut notifier = Notifier::new(NotifyConfig { min_interval: Some(60), ..NotifyConfig::default() });
+        let now = Instant::now();
+        let path = Path::new("/a");
+        assert!(notifier.take(NotifyEvent::Failure, path, now));
+        assert!(!notifier.take(NotifyEvent::Failure, path, now + Duration::from_secs(30)));
+        assert!(notifier.take(NotifyEvent::Recovery, path, now + Duration::from_secs(30)));
+        assert!(notifier.take(NotifyEvent::Failure, path, now + Duration::from_secs(61)));
+    }
+}
@@ -0,0 +1,217 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+/// ConcurrencyConfig構造体
+/// バックアップ対象を並列に処理する際の設定 (`[concurrency]`)
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ConcurrencyConfig {
+    /// 同時に処理するバックアップ対象の最大数 (ハッシュ値の計算を含む)
+    pub max_parallel: usize,
+    /// ローカルのバックアップ先へ同時に書き込む最大数 (0の場合は max_parallel)
+    pub local_writes: usize,
+    /// リモートの転送先(S3など)へ同時に転送する最大数
+    pub remote_uploads: usize,
+}
+
+impl Default for ConcurrencyConfig {
+    fn default() -> Self {
+        ConcurrencyConfig { max_parallel: 2, local_writes: 0, remote_uploads: 1 }
+    }
+}
+
+impl ConcurrencyConfig {
+    /// 同時に処理するバックアップ対象の最大数を取得する。(1以上)
+    pub fn max_parallel(&self) -> usize {
+        self.max_parallel.max(1)
+    }
+
+    /// ローカルのバックアップ先へ同時に書き込む最大数を取得する。(1以上)
+    pub fn local_writes(&self) -> usize {
+        if self.local_writes == 0 { self.max_parallel() } else { self.local_writes }
+    }
+
+    /// リモートの転送先へ同時に転送する最大数を取得する。(1以上)
+    pub fn remote_uploads(&self) -> usize {
+        self.remote_uploads.max(1)
+    }
+}
+
+type Job<T> = Box<dyn FnOnce() -> T + Send>;
+
+/// Pool構造体
+/// 固定数のワーカースレッドでジョブを実行し、結果をチャンネルで受け取る。
+pub struct Pool<T: Send + 'static> {
+    jobs: Option<Sender<Job<T>>>,
+    results: Receiver<T>,
+    workers: Vec<JoinHandle<()>>,
+    pending: usize,
+}
+
+impl<T: Send + 'static> Pool<T> {
+    /// Pool構造体のコンストラクタ
+    /// size個のワーカースレッドを起動する。
+    pub fn new(size: usize) -> Self {
+        let (job_sender, job_receiver) = mpsc::channel::<Job<T>>();
+        let (result_sender, results) = mpsc::channel::<T>();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+
+        let workers = (0..size.max(1)).map(|i| {
+            let job_receiver = job_receiver.clone();
+            let result_sender = result_sender.clone();
+            thread::Builder::new()
+                .name(format!("backupfs-worker-{}", i))
+                .spawn(move || loop {
+                    // 受信中のみロックを取得し、ジョブの実行中は他のワーカーが受信できるようにする。
+                    let job = match job_receiver.lock() {
+                        Ok(receiver) => receiver.recv(),
+                        Err(_) => return,
+                    };
+                    match job {
+                        Ok(job) => {
+                            if result_sender.send(job()).is_err() {
+                                return;
+                            }
+                        },
+                        Err(_) => return,
+                    }
+                })
+                .expect("failed to spawn worker thread")
+        }).collect();
+
+        Pool { jobs: Some(job_sender), results, workers, pending: 0 }
+    }
+
+    /// ジョブを追加する。空いているワーカーから順に実行される。
+    pub fn execute<F: FnOnce() -> T + Send + 'static>(&mut self, job: F) {
+        if let Some(ref jobs) = self.jobs {
+            if jobs.send(Box::new(job)).is_ok() {
+                self.pending += 1;
+            }
+        }
+    }
+
+    /// 完了したジョブの結果を、待たずに取得する。
+    pub fn try_results(&mut self) -> Vec<T> {
+        let results: Vec<T> = self.results.try_iter().collect();
+        self.pending -= results.len();
+        results
+    }
+
+    /// ジョブの完了を1件待ち、結果を取得する。
+    /// 実行中のジョブがない場合は None となる。
+    pub fn wait_one(&mut self) -> Option<T> {
+        if self.pending == 0 {
+            return None;
+        }
+        let result = self.results.recv().ok();
+        if result.is_some() {
+            self.pending -= 1;
+        }
+        result
+    }
+
+    /// 期限までジョブの完了を1件待ち、結果を取得する。
+    /// 実行中のジョブがない場合や、期限を過ぎた場合は None となる。
+    pub fn wait_one_until(&mut self, deadline: Instant) -> Option<T> {
+        if self.pending == 0 {
+            return None;
+        }
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match self.results.recv_timeout(timeout) {
+            Ok(result) => {
+                self.pending -= 1;
+                Some(result)
+            },
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+
+    /// 実行待ち、もしくは実行中のジョブの数を取得する。
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+}
+
+impl<T: Send + 'static> Drop for Pool<T> {
+    fn drop(&mut self) {
+        // 送信側を閉じると、ワーカーは残りのジョブを実行したのちに終了する。
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Semaphore構造体
+/// 同時に実行できる処理の数を制限する。
+pub struct Semaphore {
+    count: Mutex<usize>,
+    cond: Condvar,
+}
+
+/// SemaphoreGuard構造体
+/// 破棄された際に、Semaphoreへ枠を返却する。
+pub struct SemaphoreGuard<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Semaphore {
+    /// Semaphore構造体のコンストラクタ
+    pub fn new(count: usize) -> Self {
+        Semaphore { count: Mutex::new(count.max(1)), cond: Condvar::new() }
+    }
+
+    /// 枠が空くまで待ち、取得する。
+    pub fn acquire(&self) -> SemaphoreGuard<'_> {
+        let mut count = self.count.lock().unwrap_or_else(|e| e.into_inner());
+        while *count == 0 {
+            count = self.cond.wait(count).unwrap_or_else(|e| e.into_inner());
+        }
+        *count -= 1;
+        SemaphoreGuard { semaphore: self }
+    }
+}
+
+impl<'a> Drop for SemaphoreGuard<'a> {
+    fn drop(&mut self) {
+        let mut count = self.semaphore.count.lock().unwrap_or_else(|e| e.into_inner());
+        *count += 1;
+        self.semaphore.cond.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn test_pool() {
+        let mut pool = Pool::new(4);
+        let semaphore = Arc::new(Semaphore::new(2));
+        let running = Arc::new(AtomicUsize::new(0));
+        let max = Arc::new(AtomicUsize::new(0));
+        for i in 0..8 {
+            let (semaphore, running, max) = (semaphore.clone(), running.clone(), max.clone());
+            pool.execute(move || {
+                let _guard = semaphore.acquire();
+                let n = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max.fetch_max(n, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(20));
+                running.fetch_sub(1, Ordering::SeqCst);
+                i
+            });
+        }
+        let mut results = Vec::new();
+        while let Some(i) = pool.wait_one() {
+            results.push(i);
+        }
+        results.sort();
+        assert_eq!((0..8).collect::<Vec<_>>(), results);
+        assert_eq!(2, max.load(Ordering::SeqCst));
+    }
+}
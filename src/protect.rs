@@ -0,0 +1,202 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use walkdir::WalkDir;
+
+/// ProtectConfig構造体
+/// 作成したアーカイブ(スナップショット)を削除や改変から保護する設定 (`[protect]`)
+/// ミラーは更新し続けるため対象外とする。
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct ProtectConfig {
+    /// 作成したアーカイブを読み取り専用にするかどうか
+    /// スナップショットはディレクトリも読み取り専用とし、ファイルの削除も防ぐ。
+    pub read_only: bool,
+    /// 作成したアーカイブに設定するLinuxのファイル属性
+    /// 設定には CAP_LINUX_IMMUTABLE が必要で、対応していないファイルシステムでは警告のみとなる。
+    pub attribute: Attribute,
+}
+
+/// Attribute列挙型
+/// アーカイブに設定するLinuxのファイル属性 (chattr)
+#[derive(Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Attribute {
+    /// 設定しない。
+    #[default]
+    None,
+    /// 変更、削除、名前の変更ができない (chattr +i)
+    Immutable,
+    /// 追記のみ可能 (chattr +a)
+    AppendOnly,
+}
+
+impl ProtectConfig {
+    /// 保護するかどうか
+    pub fn is_enabled(&self) -> bool {
+        self.read_only || self.attribute != Attribute::None
+    }
+}
+
+/// アーカイブ(ファイル、もしくはスナップショットのディレクトリ)を保護する。
+/// ディレクトリの場合は配下のファイルを先に保護し、最後にディレクトリ自身を保護する。
+/// 属性を設定できなかった場合もほかのファイルの保護を続け、アーカイブごとに警告する。
+/// 属性を設定できなかったファイルの数を返却する。
+pub fn protect<P: AsRef<Path>>(path: P, config: &ProtectConfig) -> io::Result<usize> {
+    if !config.is_enabled() {
+        return Ok(0);
+    }
+    let path = path.as_ref();
+    let mut failed = 0;
+    let mut first_err = None;
+    for entry in WalkDir::new(path).contents_first(true) {
+        let entry = entry.map_err(io::Error::from)?;
+        if entry.path_is_symlink() {
+            continue;
+        }
+        if config.read_only {
+            let mut permissions = entry.metadata().map_err(io::Error::from)?.permissions();
+            permissions.set_readonly(true);
+            fs::set_permissions(entry.path(), permissions)?;
+        }
+        if config.attribute != Attribute::None {
+            if let Err(err) = attribute::set(entry.path(), config.attribute) {
+                debug!("protect: failed to set {:?} attribute on {:?}: {}", config.attribute, entry.path(), err);
+                failed += 1;
+                first_err.get_or_insert(err);
+            }
+        }
+    }
+    if let Some(err) = first_err {
+        warn!("protect: failed to set {:?} attribute on {} files in {:?}: {}", config.attribute, failed, path, err);
+    }
+    Ok(failed)
+}
+
+/// アーカイブの保護を解除する。(保持数による削除の前に呼び出す)
+/// 属性の解除に失敗した場合は、続く削除も失敗するため、エラーとして返却する。
+pub fn unprotect<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    for entry in WalkDir::new(path) {
+        let entry = entry.map_err(io::Error::from)?;
+        if entry.path_is_symlink() {
+            continue;
+        }
+        attribute::clear(entry.path())?;
+        let info = entry.metadata().map_err(io::Error::from)?;
+        if info.is_dir() && info.permissions().readonly() {
+            make_writable(entry.path(), info.permissions())?;
+        }
+    }
+    Ok(())
+}
+
+/// 所有者の書き込み権限を付与する。
+#[cfg(unix)]
+fn make_writable(path: &Path, mut permissions: fs::Permissions) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    permissions.set_mode(permissions.mode() | 0o200);
+    fs::set_permissions(path, permissions)
+}
+
+#[cfg(not(unix))]
+fn make_writable(path: &Path, mut permissions: fs::Permissions) -> io::Result<()> {
+    permissions.set_readonly(false);
+    fs::set_permissions(path, permissions)
+}
+
+#[cfg(target_os = "linux")]
+mod attribute {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+
+    use super::Attribute;
+
+    // linux/fs.h
+    const FS_IOC_GETFLAGS: ::libc::c_ulong = 0x8008_6601;
+    const FS_IOC_SETFLAGS: ::libc::c_ulong = 0x4008_6602;
+    const FS_IMMUTABLE_FL: ::libc::c_int = 0x0000_0010;
+    const FS_APPEND_FL: ::libc::c_int = 0x0000_0020;
+
+    fn get_flags(file: &File) -> io::Result<::libc::c_int> {
+        let mut flags: ::libc::c_int = 0;
+        if unsafe { ::libc::ioctl(file.as_raw_fd(), FS_IOC_GETFLAGS as _, &mut flags) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(flags)
+    }
+
+    fn set_flags(file: &File, flags: ::libc::c_int) -> io::Result<()> {
+        if unsafe { ::libc::ioctl(file.as_raw_fd(), FS_IOC_SETFLAGS as _, &flags) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// 属性を設定する。
+    pub fn set(path: &Path, attribute: Attribute) -> io::Result<()> {
+        let flag = match attribute {
+            Attribute::None => return Ok(()),
+            Attribute::Immutable => FS_IMMUTABLE_FL,
+            Attribute::AppendOnly => FS_APPEND_FL,
+        };
+        let file = File::open(path)?;
+        let flags = get_flags(&file)?;
+        if flags & flag == 0 {
+            set_flags(&file, flags | flag)?;
+        }
+        Ok(())
+    }
+
+    /// immutable、append-only の属性を解除する。
+    /// 属性に対応していないファイルシステムの場合は何もしない。
+    pub fn clear(path: &Path) -> io::Result<()> {
+        let file = File::open(path)?;
+        let flags = match get_flags(&file) {
+            Ok(flags) => flags,
+            Err(_) => return Ok(()),
+        };
+        if flags & (FS_IMMUTABLE_FL | FS_APPEND_FL) != 0 {
+            set_flags(&file, flags & !(FS_IMMUTABLE_FL | FS_APPEND_FL))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod attribute {
+    use std::io;
+    use std::path::Path;
+
+    use super::Attribute;
+
+    pub fn set(_path: &Path, _attribute: Attribute) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "file attributes are not supported"))
+    }
+
+    pub fn clear(_path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_protect() {
+        let dir = env::temp_dir().join(format!("backupfs-protect-test-{}", process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/a.txt"), b"a").unwrap();
+        let config = ProtectConfig { read_only: true, ..ProtectConfig::default() };
+        assert_eq!(0, protect(&dir, &config).unwrap());
+        assert!(fs::metadata(dir.join("sub/a.txt")).unwrap().permissions().readonly());
+        assert!(fs::metadata(&dir).unwrap().permissions().readonly());
+        unprotect(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,96 +1,257 @@
 use std::error;
 use std::fmt;
 use std::io;
+use std::path::{Path, PathBuf};
 use std::sync::PoisonError;
 use std::result;
 
 use filedb::Error as FileDBError;
 use serde_json::Error as JsonError;
+use toml::de::Error as TomlError;
 use walkdir::Error as WalkDirError;
 use zip::result::ZipError;
 
-pub enum Error<T> {
-    FileDB(FileDBError),
-    Io(io::Error),
-    Json(JsonError),
-    Poison(PoisonError<T>),
-    WalkDir(WalkDirError),
-    Zip(ZipError),
+/// ErrorKind列挙型
+/// エラーの種別
+/// コマンドの終了コードはこの種別ごとに決まる。
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum ErrorKind {
+    /// バックアップ対象が存在しない、もしくは読み込めない。
+    SourceMissing,
+    /// バックアップ先へ書き込めない。
+    DestinationUnwritable,
+    /// アーカイブの作成に失敗した。
+    ArchiveFailed,
+    /// filedbのバックアップ対象の登録情報が読み書きできない、もしくは壊れている。
+    RegistryCorrupt,
+    /// 設定ファイルが不正
+    InvalidConfig,
+    /// リモートの転送先(S3など)との通信に失敗した。
+    Remote,
+    /// コマンドの引数が不正
+    InvalidArgument,
+    /// その他の入出力エラー
+    Io,
+    /// フックコマンドが失敗した、もしくはタイムアウトした。
+    HookFailed,
+    /// バックアップ先の空き容量が不足している。
+    InsufficientSpace,
+    /// 他のbackupfsdが実行中
+    AlreadyRunning,
+    /// ファイルの数の急減により、バックアップを保留している。
+    BackupHeld,
 }
 
-impl<T> fmt::Debug for Error<T> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            Error::FileDB(ref err) => write!(f, "[backup-fs] {}", err),
-            Error::Io(ref err) => write!(f, "[backup-fs] {}", err),
-            Error::Json(ref err) => write!(f, "[backup-fs] {}", err),
-            Error::Poison(ref err) => write!(f, "[backup-fs] {}", err),
-            Error::WalkDir(ref err) => write!(f, "[backup-fs] {}", err),
-            Error::Zip(ref err) => write!(f, "[backup-fs] {}", err),
+impl ErrorKind {
+    /// 種別ごとのコマンドの終了コードを取得する。
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::InvalidArgument => 2,
+            ErrorKind::SourceMissing => 3,
+            ErrorKind::DestinationUnwritable => 4,
+            ErrorKind::ArchiveFailed => 5,
+            ErrorKind::RegistryCorrupt => 6,
+            ErrorKind::InvalidConfig => 7,
+            ErrorKind::Remote => 8,
+            ErrorKind::Io => 9,
+            ErrorKind::HookFailed => 10,
+            ErrorKind::InsufficientSpace => 11,
+            ErrorKind::AlreadyRunning => 12,
+            ErrorKind::BackupHeld => 13,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::SourceMissing => "source missing",
+            ErrorKind::DestinationUnwritable => "destination unwritable",
+            ErrorKind::ArchiveFailed => "archive failed",
+            ErrorKind::RegistryCorrupt => "registry corrupt",
+            ErrorKind::InvalidConfig => "invalid config",
+            ErrorKind::Remote => "remote error",
+            ErrorKind::InvalidArgument => "invalid argument",
+            ErrorKind::Io => "i/o error",
+            ErrorKind::HookFailed => "hook failed",
+            ErrorKind::InsufficientSpace => "insufficient space",
+            ErrorKind::AlreadyRunning => "already running",
+            ErrorKind::BackupHeld => "backup held",
         }
     }
 }
 
-impl<T> fmt::Display for Error<T> {
+impl fmt::Display for ErrorKind {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            Error::FileDB(ref err) => write!(f, "[backup-fs] {}", err),
-            Error::Io(ref err) => write!(f, "[backup-fs] {}", err),
-            Error::Json(ref err) => write!(f, "[backup-fs] {}", err),
-            Error::Poison(ref err) => write!(f, "[backup-fs] {}", err),
-            Error::WalkDir(ref err) => write!(f, "[backup-fs] {}", err),
-            Error::Zip(ref err) => write!(f, "[backup-fs] {}", err),
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Error構造体
+/// backupfsで発生するエラーを表す。
+/// 種別、対象のパス、メッセージと、原因となったエラー(source)を保持する。
+pub struct Error {
+    kind: ErrorKind,
+    path: Option<PathBuf>,
+    message: String,
+    source: Option<Box<dyn error::Error + Send + Sync>>,
+}
+
+impl Error {
+    /// Error構造体のコンストラクタ
+    pub fn new<S: Into<String>>(kind: ErrorKind, message: S) -> Self {
+        Error { kind, path: None, message: message.into(), source: None }
+    }
+
+    /// 原因となったエラーを持つErrorを生成する。
+    pub fn from_source<E>(kind: ErrorKind, source: E) -> Self
+        where E: Into<Box<dyn error::Error + Send + Sync>>
+    {
+        Error { kind, path: None, message: String::new(), source: Some(source.into()) }
+    }
+
+    /// 対象のパスを設定する。
+    pub fn with_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// 種別を取得する。
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// 対象のパスを取得する。
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// 自身と原因となったエラーのメッセージを、外側から順に取得する。
+    pub fn chain(&self) -> Vec<String> {
+        let mut messages = vec![self.to_string()];
+        let mut source = error::Error::source(self);
+        while let Some(err) = source {
+            messages.push(err.to_string());
+            source = err.source();
         }
+        messages
+    }
+
+    /// 本エラーを原因とした、別の種別のエラーに包む。
+    fn wrap(self, kind: ErrorKind, path: PathBuf) -> Self {
+        if self.path.is_none() && self.message.is_empty() && self.source.is_some() {
+            // 種別とパスのみ付与されていないエラーは、包まずに書き換える。
+            return Error { kind, path: Some(path), ..self };
+        }
+        Error { kind, path: Some(path), message: String::new(), source: Some(Box::new(self)) }
+    }
+}
+
+impl fmt::Debug for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.chain().join(": "))
     }
 }
 
-impl<T> error::Error for Error<T> {
-    fn description<'a>(&'a self) -> &'a str {
-        match *self {
-            Error::FileDB(_) => "",
-            Error::Io(ref err) => err.description(),
-            Error::Json(ref err) => err.description(),
-            Error::Poison(ref err) => err.description(),
-            Error::WalkDir(ref err) => err.description(),
-            Error::Zip(ref err) => err.description(),
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[backup-fs] {}", self.kind)?;
+        if let Some(ref path) = self.path {
+            write!(f, " {:?}", path)?;
+        }
+        if !self.message.is_empty() {
+            write!(f, ": {}", self.message)?;
         }
+        Ok(())
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        self.source.as_ref().map(|err| &**err as &(dyn error::Error + 'static))
     }
 }
 
-impl<T> From<FileDBError> for Error<T> {
+/// ResultExtトレイト
+/// 結果のエラーに種別と対象のパスを付与する。
+pub trait ResultExt<T> {
+    /// エラーの場合、種別と対象のパスを付与する。
+    fn context<P: AsRef<Path>>(self, kind: ErrorKind, path: P) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> ResultExt<T> for result::Result<T, E> {
+    fn context<P: AsRef<Path>>(self, kind: ErrorKind, path: P) -> Result<T> {
+        self.map_err(|err| err.into().wrap(kind, path.as_ref().to_path_buf()))
+    }
+}
+
+impl From<FileDBError> for Error {
     fn from(err: ::filedb::Error) -> Self {
-        Error::FileDB(err)
+        // filedbのエラーはstd::error::Errorを実装していないため、
+        // I/Oエラーのみ原因として保持し、それ以外はメッセージとする。
+        match err {
+            FileDBError::IOError { error } => Error::from_source(ErrorKind::RegistryCorrupt, error),
+            FileDBError::DBFile { path } => Error::new(ErrorKind::RegistryCorrupt, "db file not exists")
+                .with_path(path),
+        }
     }
 }
 
-impl<T> From<io::Error> for Error<T> {
+impl From<io::Error> for Error {
     fn from(err: io::Error) -> Self {
-        Error::Io(err)
+        Error::from_source(ErrorKind::Io, err)
     }
 }
 
-impl<T> From<JsonError> for Error<T> {
+impl From<JsonError> for Error {
     fn from(err: ::serde_json::Error) -> Self {
-        Error::Json(err)
+        Error::from_source(ErrorKind::RegistryCorrupt, err)
     }
 }
 
-impl<T> From<PoisonError<T>> for Error<T> {
+impl<T> From<PoisonError<T>> for Error {
     fn from(err: PoisonError<T>) -> Self {
-        Error::Poison(err)
+        Error::new(ErrorKind::RegistryCorrupt, err.to_string())
+    }
+}
+
+impl From<TomlError> for Error {
+    fn from(err: ::toml::de::Error) -> Self {
+        Error::from_source(ErrorKind::InvalidConfig, err)
     }
 }
 
-impl<T> From<WalkDirError> for Error<T> {
+impl From<WalkDirError> for Error {
     fn from(err: ::walkdir::Error) -> Self {
-        Error::WalkDir(err)
+        let path = err.path().map(|p| p.to_path_buf());
+        let err = Error::from_source(ErrorKind::SourceMissing, err);
+        match path {
+            Some(path) => err.with_path(path),
+            None => err,
+        }
     }
 }
 
-impl<T> From<ZipError> for Error<T> {
+impl From<ZipError> for Error {
     fn from(err: ::zip::result::ZipError) -> Self {
-        Error::Zip(err)
+        Error::from_source(ErrorKind::ArchiveFailed, err)
     }
 }
 
-pub type Result<T> = result::Result<T, Error<T>>;
\ No newline at end of file
+pub type Result<T> = result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context() {
+        let res: result::Result<(), io::Error> = Err(io::Error::from(io::ErrorKind::PermissionDenied));
+        let err = res.context(ErrorKind::DestinationUnwritable, "/backup").unwrap_err();
+        assert_eq!(ErrorKind::DestinationUnwritable, err.kind());
+        assert_eq!(Some(Path::new("/backup")), err.path());
+        assert!(error::Error::source(&err).is_some());
+
+        let err = Err::<(), Error>(err).context(ErrorKind::ArchiveFailed, "/src").unwrap_err();
+        assert_eq!(3, err.chain().len());
+        assert_eq!(5, err.kind().exit_code());
+    }
+}
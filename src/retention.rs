@@ -0,0 +1,169 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use archiver::{Mode, Snapshot};
+use catalog;
+use config::Config;
+use destination::Destination;
+use layout;
+use marks::Marks;
+use naming;
+use protect;
+use result::{ErrorKind, Result, ResultExt};
+
+/// RetentionConfig構造体
+/// アーカイブ(スナップショット)の保持数の設定
+/// 全体の設定(`[retention]`)とバックアップ対象ごとの設定(`[target.retention]`)があり、
+/// 対象ごとに設定した項目が優先される。
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct RetentionConfig {
+    /// 保持する最新のアーカイブの数 (設定されていない場合は削除しない)
+    pub keep_last: Option<usize>,
+}
+
+impl RetentionConfig {
+    /// 対象ごとの設定(self)に、設定されていない項目を全体の設定(global)で補う。
+    pub fn merge(&self, global: &RetentionConfig) -> RetentionConfig {
+        RetentionConfig { keep_last: self.keep_last.or(global.keep_last) }
+    }
+}
+
+/// バックアップ対象のディレクトリ配下のアーカイブ(スナップショット)を古い順に取得する。
+/// ミラーは1つのディレクトリを更新するため、対象外とする。
+pub fn list<P: AsRef<Path>>(dir: P, mode: Mode) -> io::Result<Vec<PathBuf>> {
+    match mode {
+        Mode::Zip => {
+            let mut archives: Vec<PathBuf> = Vec::new();
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                if entry.file_type()?.is_file() && name.ends_with(".zip") && naming::is_archive_name(&name) {
+                    archives.push(entry.path());
+                }
+            }
+            naming::sort_by_created(&mut archives);
+            Ok(archives)
+        },
+        Mode::Snapshot => Snapshot::list(dir),
+        Mode::Mirror => Ok(Vec::new()),
+    }
+}
+
+/// 最新のkeep件を残し、古いアーカイブ(スナップショット)を削除する。
+/// 保持(リーガルホールド)されているアーカイブは削除せず、件数にも含めない。
+/// 大量の変更を検知して削除を一時停止している場合は何も削除しない。
+/// 削除中に保持されたアーカイブを削除しないよう、印を読み込んでから記録するまでロックする。
+/// 削除した数を返却する。
+pub fn prune<P: AsRef<Path>>(dir: P, mode: Mode, keep: usize) -> io::Result<usize> {
+    let dir = dir.as_ref();
+    let _lock = Marks::lock(dir)?;
+    let mut marks = Marks::load(dir)?;
+    if marks.prune_paused {
+        info!("retention: pruning paused for {:?}", dir);
+        return Ok(0);
+    }
+    let archives: Vec<PathBuf> = list(dir, mode)?.into_iter()
+        .filter(|path| !marks.is_held(&archive_name(path)))
+        .collect();
+    let marked = marks.archives.len();
+    let remove = archives.len().saturating_sub(keep);
+    let mut removed = Vec::new();
+    for path in &archives[..remove] {
+        info!("retention: remove {:?}", path);
+        // 読み取り専用、immutable などの保護を解除してから削除する。
+        protect::unprotect(path)?;
+        if path.is_dir() {
+            fs::remove_dir_all(path)?;
+        } else {
+            fs::remove_file(path)?;
+        }
+        marks.archives.remove(&archive_name(path));
+        removed.push(archive_name(path));
+    }
+    // 削除したアーカイブのファイルをカタログから取り除く。
+    if let Some(target) = layout::read_target(dir) {
+        if let Err(err) = catalog::remove(&Config::state_dir(), &target, &removed) {
+            error!("{:?}", err);
+        }
+    }
+    // 削除したアーカイブの印を取り除く。
+    if marks.archives.len() != marked {
+        marks.save(dir)?;
+    }
+    Ok(remove)
+}
+
+/// 転送先(S3など)の、バックアップ対象のディレクトリに対応するアーカイブのうち、
+/// 最新のkeep件を残して古いものを削除する。
+/// 保持(リーガルホールド)と削除の一時停止は、ローカルのバックアップ対象のディレクトリの印に従う。
+/// 削除した数を返却する。
+pub fn prune_remote(remote: &dyn Destination, dir: &Path, keep: usize) -> Result<usize> {
+    let _lock = Marks::lock(dir).context(ErrorKind::Io, dir)?;
+    let marks = Marks::load(dir).context(ErrorKind::Io, dir)?;
+    if marks.prune_paused {
+        info!("retention: pruning paused for {:?}", dir);
+        return Ok(0);
+    }
+    // 転送先のキーはバックアップ先からの相対パス(<対象のディレクトリ名>/<アーカイブ名>)となる。
+    let prefix = PathBuf::from(archive_name(dir));
+    let mut keys: Vec<PathBuf> = remote.list(&format!("{}/", prefix.display()))?.into_iter()
+        .map(|object| PathBuf::from(object.key))
+        .filter(|key| key.parent() == Some(prefix.as_path()))
+        .filter(|key| {
+            let name = archive_name(key);
+            name.ends_with(".zip") && naming::is_archive_name(&name) && !marks.is_held(&name)
+        })
+        .collect();
+    naming::sort_by_created(&mut keys);
+    let remove = keys.len().saturating_sub(keep);
+    for key in &keys[..remove] {
+        info!("retention: remove remote {:?}", key);
+        remote.delete(&key.to_string_lossy())?;
+    }
+    Ok(remove)
+}
+
+/// アーカイブ(スナップショット)の名前を取得する。
+fn archive_name(path: &Path) -> String {
+    path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_prune_hold() {
+        let dir = env::temp_dir().join(format!("backupfs-retention-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in &["1.zip", "2.zip", "3.zip"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        // 削除の途中(一覧の取得から削除まで)に保持されたアーカイブは削除しない。
+        let lock = Marks::lock(&dir).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let prune_dir = dir.clone();
+        let handle = thread::spawn(move || {
+            sender.send(prune(&prune_dir, Mode::Zip, 1).unwrap()).unwrap();
+        });
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+        let mut marks = Marks::load(&dir).unwrap();
+        marks.hold("1.zip", "2018-10-18T00:00:00Z".to_string(), None);
+        marks.save(&dir).unwrap();
+        drop(lock);
+        handle.join().unwrap();
+        assert_eq!(1, receiver.recv().unwrap());
+        assert!(dir.join("1.zip").exists());
+        assert!(!dir.join("2.zip").exists());
+        assert!(dir.join("3.zip").exists());
+        assert!(Marks::load(&dir).unwrap().is_held("1.zip"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
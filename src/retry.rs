@@ -0,0 +1,179 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use result::{Error, ErrorKind};
+
+/// RetryConfig構造体
+/// バックアップに失敗した対象の再試行と、失敗が続いた場合の通知の設定 (`[retry]`)
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// 最初の再試行までの待ち時間(秒)
+    pub initial_backoff: u64,
+    /// 再試行までの待ち時間の上限(秒)
+    pub max_backoff: u64,
+    /// 連続して失敗した場合に異常(unhealthy)とみなす回数
+    pub unhealthy_threshold: u32,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig { initial_backoff: 30, max_backoff: 3600, unhealthy_threshold: 5 }
+    }
+}
+
+/// Failure構造体
+/// バックアップ対象ごとの連続した失敗の状態
+#[derive(Clone, Debug)]
+pub struct Failure {
+    count: u32,
+    kind: ErrorKind,
+    next_retry: Instant,
+    unhealthy: bool,
+}
+
+impl Failure {
+    /// 連続した失敗の回数を取得する。
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// 直近の失敗の種別を取得する。(空き容量の不足など)
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// 異常とみなしているかどうか
+    pub fn is_unhealthy(&self) -> bool {
+        self.unhealthy
+    }
+}
+
+/// Retry構造体
+/// 失敗したバックアップ対象を指数的に間隔を空けて再試行させ、
+/// 失敗が閾値まで続いた場合は異常として通知する。
+#[derive(Default)]
+pub struct Retry {
+    config: RetryConfig,
+    failures: HashMap<PathBuf, Failure>,
+}
+
+impl Retry {
+    /// Retry構造体のコンストラクタ
+    pub fn new(config: RetryConfig) -> Self {
+        Retry { config, failures: HashMap::new() }
+    }
+
+    /// 設定を変更する。(設定ファイルの再読み込み)
+    /// 連続した失敗の回数、再試行の時刻、異常とみなしている状態は引き継ぐ。
+    pub fn set_config(&mut self, config: RetryConfig) {
+        self.config = config;
+    }
+
+    /// 再試行待ちのバックアップ対象かどうか
+    pub fn is_waiting(&self, path: &Path, now: Instant) -> bool {
+        self.failures.get(path).map(|f| now < f.next_retry).unwrap_or(false)
+    }
+
+    /// 前回のバックアップに失敗しているかどうか
+    /// 変更がなくても再試行の対象となる。
+    pub fn has_failed(&self, path: &Path) -> bool {
+        self.failures.contains_key(path)
+    }
+
+    /// バックアップ対象の失敗の状態を取得する。
+    pub fn failure(&self, path: &Path) -> Option<&Failure> {
+        self.failures.get(path)
+    }
+
+    /// 前回のバックアップに失敗しているバックアップ対象の一覧を取得する。
+    pub fn failures(&self) -> Vec<(&Path, &Failure)> {
+        self.failures.iter().map(|(p, f)| (p.as_path(), f)).collect()
+    }
+
+    /// 異常とみなしているバックアップ対象の一覧を取得する。
+    pub fn unhealthy(&self) -> Vec<&Path> {
+        self.failures.iter()
+            .filter(|(_, f)| f.unhealthy)
+            .map(|(p, _)| p.as_path())
+            .collect()
+    }
+
+    /// バックアップの成功を記録し、失敗の状態をリセットする。
+    pub fn succeeded(&mut self, path: &Path) {
+        if let Some(failure) = self.failures.remove(path) {
+            info!("retry: {:?} recovered after {} failures", path, failure.count);
+        }
+    }
+
+    /// バックアップの失敗を記録し、次の再試行の時刻を決める。
+    /// 連続した失敗が閾値に達した場合は異常とみなす。
+    /// 今回の失敗で異常とみなした場合(通知すべき場合)は true を返却する。
+    pub fn failed(&mut self, path: &Path, err: &Error) -> bool {
+        let count = {
+            let failure = self.failures.entry(path.to_path_buf()).or_insert(Failure {
+                count: 0,
+                kind: err.kind(),
+                next_retry: Instant::now(),
+                unhealthy: false,
+            });
+            failure.count += 1;
+            failure.kind = err.kind();
+            failure.count
+        };
+        let backoff = self.backoff(count);
+
+        let failure = self.failures.get_mut(path).unwrap();
+        failure.next_retry = Instant::now() + backoff;
+        warn!("retry: {:?} failed {} times, retry in {}s", path, failure.count, backoff.as_secs());
+
+        if !failure.unhealthy && failure.count >= self.config.unhealthy_threshold {
+            failure.unhealthy = true;
+            error!("retry: {:?} is unhealthy", path);
+            return true;
+        }
+        false
+    }
+
+    /// 失敗回数に応じた再試行までの待ち時間を取得する。
+    /// initial_backoff から失敗ごとに倍となり、max_backoff を上限とする。
+    pub fn backoff(&self, count: u32) -> Duration {
+        let shift = cmp::min(count.saturating_sub(1), 31);
+        let secs = self.config.initial_backoff.saturating_mul(1 << shift);
+        Duration::from_secs(cmp::min(secs, self.config.max_backoff))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use result::ErrorKind;
+
+    #[test]
+    fn test_backoff() {
+        let mut retry = Retry::new(RetryConfig { initial_backoff: 10, max_backoff: 60, unhealthy_threshold: 2 });
+        assert_eq!(Duration::from_secs(10), retry.backoff(1));
+        assert_eq!(Duration::from_secs(40), retry.backoff(3));
+        assert_eq!(Duration::from_secs(60), retry.backoff(100));
+
+        let path = Path::new("/tmp/docs");
+        let err = Error::new(ErrorKind::ArchiveFailed, "test");
+        assert!(!retry.failed(path, &err));
+        assert!(retry.is_waiting(path, Instant::now()));
+        assert!(retry.unhealthy().is_empty());
+        assert!(retry.failed(path, &err));
+        assert_eq!(vec![path], retry.unhealthy());
+        // 異常とみなした後は、続けて失敗しても重ねて通知しない。
+        assert!(!retry.failed(path, &err));
+
+        // 設定を再読み込みしても失敗の状態は引き継ぐ。
+        retry.set_config(RetryConfig { initial_backoff: 5, ..RetryConfig::default() });
+        assert_eq!(vec![path], retry.unhealthy());
+        assert_eq!(3, retry.failures()[0].1.count());
+        assert_eq!(Duration::from_secs(5), retry.backoff(1));
+        retry.succeeded(path);
+        assert!(!retry.has_failed(path));
+    }
+}
@@ -0,0 +1,368 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use chrono::prelude::*;
+use chrono::Duration as ChronoDuration;
+
+use config::Config;
+use result::{Error, ErrorKind, Result};
+
+/// 変更検知のデフォルトの間隔(秒)
+pub const DEFAULT_CHECK_INTERVAL: u64 = 5;
+
+/// デーモンが待機する最大の時間(秒)
+const MAX_WAIT: u64 = 60;
+
+/// 次の実行時刻を探す範囲(日)
+/// 2月29日のみのような、数年に一度しか一致しない式も見つけられるようにする。
+const SEARCH_DAYS: i64 = 366 * 8;
+
+/// CatchUp列挙型
+/// デーモンが停止していた間に実行されなかったスケジュールの扱い
+#[derive(Deserialize, Serialize, Clone, Copy, Eq, PartialEq, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum CatchUp {
+    /// 起動時に1回だけ実行する。
+    #[default]
+    Run,
+    /// 実行せず、次のスケジュールを待つ。
+    Skip,
+}
+
+/// Schedule列挙型
+/// 変更の有無によらずバックアップを行うスケジュール
+///
+/// - cron形式 (分 時 日 月 曜日): `"0 3 * * *"`, `"*/15 9-18 * * 1-5"`
+/// - 別名: `@hourly`, `@daily`, `@weekly`, `@monthly`, `@yearly`
+/// - 一定間隔: `"@every 6h"` (単位は s, m, h, d)
+#[derive(Clone, Debug, PartialEq)]
+pub enum Schedule {
+    Cron(Cron),
+    Every(Duration),
+}
+
+impl Schedule {
+    /// スケジュールの文字列を解析する。
+    pub fn parse(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let expr = match s {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            _ => s,
+        };
+        if let Some(interval) = expr.strip_prefix("@every") {
+            return parse_duration(interval.trim())
+                .map(Schedule::Every)
+                .ok_or_else(|| invalid(s));
+        }
+        Cron::parse(expr).map(Schedule::Cron).ok_or_else(|| invalid(s))
+    }
+
+    /// 指定した時刻より後の、次の実行時刻を取得する。
+    pub fn next_after(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match *self {
+            Schedule::Cron(ref cron) => cron.next_after(time),
+            Schedule::Every(interval) => ChronoDuration::from_std(interval).ok().map(|d| time + d),
+        }
+    }
+}
+
+/// Cron構造体
+/// cron形式の式の各項目を、一致する値のビットで保持する。
+/// 時刻はローカルタイムで解釈する。
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    /// cron形式 (分 時 日 月 曜日) の式を解析する。
+    pub fn parse(expr: &str) -> Option<Self> {
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            return None;
+        }
+        // 曜日の7は日曜日(0)とみなす。
+        let weekdays = parse_field(fields[4], 0, 7)?;
+        Some(Cron {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays: (weekdays | (weekdays >> 7)) & 0x7f,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+
+    /// 指定した時刻より後の、次の実行時刻を取得する。
+    pub fn next_after(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let local = time.with_timezone(&Local).naive_local();
+        let start = local.date().and_hms(local.hour(), local.minute(), 0) + ChronoDuration::minutes(1);
+
+        for offset in 0..SEARCH_DAYS {
+            let date = start.date() + ChronoDuration::days(offset);
+            if !self.matches_date(date) {
+                continue;
+            }
+            for hour in 0..24 {
+                if !bit(self.hours, hour) || (offset == 0 && hour < start.hour()) {
+                    continue;
+                }
+                for minute in 0..60 {
+                    if !bit(self.minutes, minute) || (offset == 0 && hour == start.hour() && minute < start.minute()) {
+                        continue;
+                    }
+                    // 夏時間の切り替えで存在しない時刻は飛ばす。
+                    if let Some(t) = Local.from_local_datetime(&date.and_hms(hour, minute, 0)).earliest() {
+                        return Some(t.with_timezone(&Utc));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// 日と曜日の両方が指定されている場合は、どちらかに一致すればよい。(cronと同じ)
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        if !bit(self.months, date.month()) {
+            return false;
+        }
+        let day = bit(self.days, date.day());
+        let weekday = bit(self.weekdays, date.weekday().num_days_from_sunday());
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (false, true) => day,
+            (true, false) => weekday,
+            (false, false) => day || weekday,
+        }
+    }
+}
+
+/// Scheduler構造体
+/// バックアップ対象ごとに、変更検知を行う時刻とスケジュールによる実行時刻を管理する。
+/// スケジュールの前回の実行時刻は、デーモンの停止中に実行されなかったスケジュールの判定に利用するため、
+/// filedbへ保存する。
+#[derive(Default)]
+pub struct Scheduler {
+    config: Config,
+    entries: HashMap<PathBuf, Entry>,
+    last_runs: HashMap<PathBuf, DateTime<Utc>>,
+}
+
+struct Entry {
+    watch: bool,
+    check_interval: Duration,
+    /// 次に変更検知を行う時刻 (Noneの場合はすぐに行う)
+    next_check: Option<Instant>,
+    schedule: Option<Schedule>,
+    next_run: Option<DateTime<Utc>>,
+}
+
+impl Scheduler {
+    /// Scheduler構造体のコンストラクタ
+    pub fn new(config: Config) -> Self {
+        Scheduler { config, entries: HashMap::new(), last_runs: HashMap::new() }
+    }
+
+    /// スケジュールの前回の実行時刻を設定する。
+    pub fn set_last_runs(&mut self, last_runs: HashMap<PathBuf, DateTime<Utc>>) {
+        self.last_runs = last_runs;
+        self.entries.clear();
+    }
+
+    /// スケジュールの前回の実行時刻を取得する。
+    pub fn last_runs(&self) -> &HashMap<PathBuf, DateTime<Utc>> {
+        &self.last_runs
+    }
+
+    /// 変更検知を行う時刻になっているかどうか
+    /// 時刻になっている場合は、次の変更検知の時刻を設定する。
+    pub fn take_check(&mut self, path: &Path, now: Instant) -> bool {
+        let entry = self.entry(path);
+        if !entry.watch || entry.next_check.map(|t| now < t).unwrap_or(false) {
+            return false;
+        }
+        entry.next_check = Some(now + entry.check_interval);
+        true
+    }
+
+    /// スケジュールによる実行時刻になっているかどうか
+    /// 時刻になっている場合は、実行時刻を記録し、次の実行時刻を設定する。
+    pub fn take_due(&mut self, path: &Path, now: DateTime<Utc>) -> bool {
+        let due = {
+            let entry = self.entry(path);
+            match (entry.next_run, entry.schedule.as_ref()) {
+                (Some(next_run), Some(schedule)) if next_run <= now => {
+                    entry.next_run = schedule.next_after(now);
+                    true
+                },
+                _ => false,
+            }
+        };
+        if due {
+            info!("scheduler: scheduled backup {:?}", path);
+            self.last_runs.insert(path.to_path_buf(), now);
+        }
+        due
+    }
+
+    /// 次に変更検知、もしくはスケジュールによる実行を行うまでの時間を取得する。
+    /// まだ一度も確認していない対象がある場合や、時刻を過ぎている場合は0となる。
+    pub fn next_wake<'a, I>(&self, paths: I, now: Instant) -> Duration
+        where I: Iterator<Item = &'a PathBuf>
+    {
+        let utc_now = Utc::now();
+        let mut wait = Duration::from_secs(MAX_WAIT);
+        for path in paths {
+            let entry = match self.entries.get(path) {
+                Some(entry) => entry,
+                None => return Duration::from_secs(0),
+            };
+            if entry.watch {
+                let until = entry.next_check.map(|t| t.saturating_duration_since(now)).unwrap_or_default();
+                wait = cmp::min(wait, until);
+            }
+            if let Some(next_run) = entry.next_run {
+                let until = (next_run - utc_now).to_std().unwrap_or_default();
+                wait = cmp::min(wait, until);
+            }
+        }
+        wait
+    }
+
+    fn entry(&mut self, path: &Path) -> &mut Entry {
+        if !self.entries.contains_key(path) {
+            let entry = self.new_entry(path);
+            self.entries.insert(path.to_path_buf(), entry);
+        }
+        self.entries.get_mut(path).unwrap()
+    }
+
+    fn new_entry(&self, path: &Path) -> Entry {
+        let target = self.config.target(path);
+        let interval = target.check_interval
+            .or(self.config.check_interval)
+            .unwrap_or(DEFAULT_CHECK_INTERVAL);
+        let schedule = target.schedule.as_ref().and_then(|s| match Schedule::parse(s) {
+            Ok(schedule) => Some(schedule),
+            Err(err) => {
+                error!("{:?}", err);
+                None
+            },
+        });
+
+        // 前回の実行時刻から次の実行時刻を求め、既に過ぎている場合は停止中に実行されなかったものとみなす。
+        let now = Utc::now();
+        let next_run = schedule.as_ref().and_then(|schedule| {
+            match self.last_runs.get(path).and_then(|last| schedule.next_after(*last)) {
+                Some(next) if next <= now => {
+                    match target.catch_up {
+                        CatchUp::Run => {
+                            info!("scheduler: missed schedule at {} {:?}, run now", next, path);
+                            Some(now)
+                        },
+                        CatchUp::Skip => schedule.next_after(now),
+                    }
+                },
+                Some(next) => Some(next),
+                None => schedule.next_after(now),
+            }
+        });
+        debug!("scheduler: {:?} next run: {:?}", path, next_run);
+
+        Entry {
+            watch: target.watch,
+            check_interval: Duration::from_secs(interval),
+            next_check: None,
+            schedule,
+            next_run,
+        }
+    }
+}
+
+fn bit(mask: u64, n: u32) -> bool {
+    mask & (1 << n) != 0
+}
+
+/// cron形式の項目を解析する。
+/// `*`, `*/n`, `a`, `a-b`, `a-b/n` とそのカンマ区切りに対応する。
+fn parse_field(field: &str, min: u32, max: u32) -> Option<u64> {
+    let mut mask = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(i) => (&part[..i], part[i + 1..].parse::<u32>().ok().filter(|&s| s > 0)?),
+            None => (part, 1),
+        };
+        let (from, to) = if range == "*" {
+            (min, max)
+        } else if let Some(i) = range.find('-') {
+            (range[..i].parse().ok()?, range[i + 1..].parse().ok()?)
+        } else {
+            let n = range.parse().ok()?;
+            // `a/n` は a から最大値までとみなす。
+            (n, if part.contains('/') { max } else { n })
+        };
+        if from < min || to > max || from > to {
+            return None;
+        }
+        for n in (from..=to).step_by(step as usize) {
+            mask |= 1 << n;
+        }
+    }
+    Some(mask)
+}
+
+/// `30s`, `15m`, `6h`, `1d` の形式の期間を解析する。
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let unit = match s.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    let n: u64 = s[..s.len() - 1].trim().parse().ok()?;
+    if n == 0 {
+        return None;
+    }
+    Some(Duration::from_secs(n * unit))
+}
+
+fn invalid(s: &str) -> Error {
+    Error::new(ErrorKind::InvalidConfig, format!("invalid schedule: {:?}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schedule() {
+        assert_eq!(Schedule::Every(Duration::from_secs(6 * 3600)), Schedule::parse("@every 6h").unwrap());
+        assert!(Schedule::parse("0 3 * *").is_err());
+        assert!(Schedule::parse("61 * * * *").is_err());
+
+        let schedule = Schedule::parse("*/15 9-18 * * 1-5").unwrap();
+        // 2018-10-19 (金) 18:50 の次は 2018-10-22 (月) 09:00
+        let time = Local.ymd(2018, 10, 19).and_hms(18, 50, 0).with_timezone(&Utc);
+        let next = schedule.next_after(time).unwrap().with_timezone(&Local);
+        assert_eq!(Local.ymd(2018, 10, 22).and_hms(9, 0, 0), next);
+
+        let schedule = Schedule::parse("@daily").unwrap();
+        let time = Local.ymd(2018, 10, 19).and_hms(0, 0, 0).with_timezone(&Utc);
+        let next = schedule.next_after(time).unwrap().with_timezone(&Local);
+        assert_eq!(Local.ymd(2018, 10, 20).and_hms(0, 0, 0), next);
+    }
+}
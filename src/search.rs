@@ -0,0 +1,191 @@
+use std::path::PathBuf;
+
+use chrono::prelude::*;
+use regex::{self, Regex};
+
+use catalog::CatalogEntry;
+use result::{Error, ErrorKind, Result};
+
+/// Pattern構造体
+/// ファイルを検索するパターン
+/// `/` を含まないパターンはファイル名、含むパターンはアーカイブ内の相対パス全体と照合する。
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    regex: Regex,
+    whole_path: bool,
+}
+
+impl Pattern {
+    /// グロブのパターンを解析する。
+    /// `*` `?` はパスの区切り(`/`)以外、`**` は区切りを含む任意の文字列、`[...]` は文字クラスとなる。
+    pub fn glob(pattern: &str) -> Result<Self> {
+        let mut re = String::from("^");
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    // `**/` は0個以上のディレクトリとする。
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        re.push_str("(?:.*/)?");
+                    } else {
+                        re.push_str(".*");
+                    }
+                },
+                '*' => re.push_str("[^/]*"),
+                '?' => re.push_str("[^/]"),
+                '[' => {
+                    re.push('[');
+                    if chars.peek() == Some(&'!') {
+                        chars.next();
+                        re.push('^');
+                    }
+                    for c in chars.by_ref() {
+                        if c == ']' {
+                            break;
+                        }
+                        if c == '\\' || c == '[' {
+                            re.push('\\');
+                        }
+                        re.push(c);
+                    }
+                    re.push(']');
+                },
+                c => re.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        re.push('$');
+        let regex = Regex::new(&re).map_err(|err| Error::new(ErrorKind::InvalidArgument, err.to_string()))?;
+        Ok(Pattern { regex, whole_path: pattern.contains('/') })
+    }
+
+    /// 正規表現のパターンを解析する。(部分一致)
+    pub fn regex(pattern: &str) -> Result<Self> {
+        let regex = Regex::new(pattern).map_err(|err| Error::new(ErrorKind::InvalidArgument, err.to_string()))?;
+        Ok(Pattern { regex, whole_path: pattern.contains('/') })
+    }
+
+    /// アーカイブ内の相対パスがパターンに一致するかどうか
+    pub fn matches(&self, path: &str) -> bool {
+        if self.whole_path {
+            self.regex.is_match(path)
+        } else {
+            self.regex.is_match(path.rsplit('/').next().unwrap_or(path))
+        }
+    }
+}
+
+/// Query構造体
+/// カタログからファイルを検索する条件
+#[derive(Clone, Debug)]
+pub struct Query {
+    pub pattern: Pattern,
+    /// バックアップ対象
+    pub target: Option<PathBuf>,
+    /// アーカイブの作成日時がこれ以降
+    pub since: Option<DateTime<Utc>>,
+    /// アーカイブの作成日時がこれ以前
+    pub until: Option<DateTime<Utc>>,
+    /// サイズ(バイト)がこれより大きい
+    pub larger_than: Option<u64>,
+    /// 内容のmd5ハッシュ値(16進数)
+    pub hash: Option<String>,
+    /// 内容が同じでもアーカイブごとに表示するかどうか
+    pub all: bool,
+}
+
+impl Query {
+    /// 条件に一致するかどうか
+    pub fn matches(&self, entry: &CatalogEntry) -> bool {
+        self.target.as_ref().map(|t| *t == entry.target).unwrap_or(true)
+            && self.since.map(|t| entry.created_at() >= t).unwrap_or(true)
+            && self.until.map(|t| entry.created_at() <= t).unwrap_or(true)
+            && self.larger_than.map(|n| entry.size > n).unwrap_or(true)
+            && self.hash.as_ref().map(|h| h.eq_ignore_ascii_case(&entry.hash)).unwrap_or(true)
+            && self.pattern.matches(&entry.path)
+    }
+}
+
+/// カタログから条件に一致するファイルを検索する。
+/// バックアップ対象、パス、アーカイブの作成日時の順に並べ、
+/// all でない場合は内容が変わったバージョン(最初に含まれるアーカイブ)のみとする。
+pub fn find(entries: &[CatalogEntry], query: &Query) -> Vec<CatalogEntry> {
+    let mut found: Vec<&CatalogEntry> = entries.iter().filter(|e| query.matches(e)).collect();
+    found.sort_by(|a, b| (&a.target, &a.path, a.created).cmp(&(&b.target, &b.path, b.created)));
+    if !query.all {
+        found.dedup_by(|b, a| a.target == b.target && a.path == b.path && a.hash == b.hash);
+    }
+    found.into_iter().cloned().collect()
+}
+
+/// `--larger-than` の値を解析する。
+/// バイト数、もしくは `K` `M` `G` `T` (1024倍ごと) の単位を付けたサイズを受け付ける。
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let upper = s.to_ascii_uppercase();
+    let digits = upper.trim_end_matches('B').trim_end_matches('I');
+    let (number, unit) = match digits.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&digits[..i], c),
+        _ => (digits, ' '),
+    };
+    let shift = match unit {
+        ' ' => 0,
+        'K' => 10,
+        'M' => 20,
+        'G' => 30,
+        'T' => 40,
+        _ => return None,
+    };
+    number.trim().parse::<u64>().ok().and_then(|n| n.checked_mul(1 << shift))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(archive: &str, created: i64, path: &str, hash: &str) -> CatalogEntry {
+        CatalogEntry {
+            target: PathBuf::from("/src"),
+            archive: archive.to_string(),
+            created,
+            path: path.to_string(),
+            size: 10,
+            mtime: 0,
+            hash: hash.to_string(),
+            crc32: None,
+        }
+    }
+
+    #[test]
+    fn test_find() {
+        let yml = Pattern::glob("*.yml").unwrap();
+        assert!(yml.matches("config/app.yml"));
+        assert!(!yml.matches("config/app.yml.bak"));
+        let path = Pattern::glob("config/**/*.y?l").unwrap();
+        assert!(path.matches("config/app.yml"));
+        assert!(path.matches("config/a/b/app.yml"));
+        assert!(!path.matches("other/app.yml"));
+        assert!(Pattern::glob("[!a]pp.yml").unwrap().matches("x/bpp.yml"));
+        assert!(Pattern::regex("^app\\.(yml|yaml)$").unwrap().matches("config/app.yaml"));
+
+        let entries = vec![
+            entry("3", 3, "config/app.yml", "b"),
+            entry("1", 1, "config/app.yml", "a"),
+            entry("2", 2, "config/app.yml", "a"),
+            entry("2", 2, "main.rs", "c"),
+        ];
+        let mut query = Query {
+            pattern: yml, target: None, since: None, until: None, larger_than: None, hash: None, all: false,
+        };
+        let found: Vec<String> = find(&entries, &query).into_iter().map(|e| e.archive).collect();
+        assert_eq!(vec!["1", "3"], found);
+        query.all = true;
+        assert_eq!(3, find(&entries, &query).len());
+
+        assert_eq!(Some(1536), parse_size("1536"));
+        assert_eq!(Some(10 << 20), parse_size("10M"));
+        assert_eq!(Some(1 << 30), parse_size("1GiB"));
+        assert_eq!(None, parse_size("10X"));
+    }
+}
@@ -0,0 +1,71 @@
+use std::io;
+use std::sync::mpsc::Sender;
+
+/// Signal列挙型
+/// デーモンが受け付けるシグナル
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Signal {
+    /// SIGTERM, SIGINT: 実行中のバックアップを完了(もしくは中断)して終了する。
+    Terminate,
+    /// SIGHUP: 設定ファイルを再読み込みする。
+    Reload,
+}
+
+/// SIGTERM, SIGINT, SIGHUP を呼び出したスレッドでブロックする。
+/// その後に生成したスレッドへ引き継がれるため、他のスレッドを生成する前に呼び出し、
+/// シグナルは listen() のスレッドのみで受け取るようにする。
+/// (子プロセスを起動する際はstdによりマスクがリセットされる)
+#[cfg(target_os = "linux")]
+pub fn block() -> io::Result<()> {
+    let set = signal_set();
+    let res = unsafe { ::libc::pthread_sigmask(::libc::SIG_BLOCK, &set, ::std::ptr::null_mut()) };
+    if res != 0 {
+        return Err(io::Error::from_raw_os_error(res));
+    }
+    Ok(())
+}
+
+/// シグナルを待ち受けるスレッドを起動し、受け取ったシグナルをsenderへ送る。
+#[cfg(target_os = "linux")]
+pub fn listen(sender: Sender<Signal>) -> io::Result<()> {
+    ::std::thread::Builder::new()
+        .name("backupfs-signal".to_string())
+        .spawn(move || {
+            let set = signal_set();
+            loop {
+                let mut sig: ::libc::c_int = 0;
+                if unsafe { ::libc::sigwait(&set, &mut sig) } != 0 {
+                    continue;
+                }
+                let signal = if sig == ::libc::SIGHUP { Signal::Reload } else { Signal::Terminate };
+                info!("signal: received {}", sig);
+                if sender.send(signal).is_err() {
+                    return;
+                }
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn signal_set() -> ::libc::sigset_t {
+    unsafe {
+        let mut set: ::libc::sigset_t = ::std::mem::zeroed();
+        ::libc::sigemptyset(&mut set);
+        ::libc::sigaddset(&mut set, ::libc::SIGTERM);
+        ::libc::sigaddset(&mut set, ::libc::SIGINT);
+        ::libc::sigaddset(&mut set, ::libc::SIGHUP);
+        set
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn block() -> io::Result<()> {
+    Ok(())
+}
+
+/// Linux以外では未対応のため、呼び出し元でCtrl-Cのハンドラーなどを利用する。
+#[cfg(not(target_os = "linux"))]
+pub fn listen(_sender: Sender<Signal>) -> io::Result<()> {
+    Err(io::Error::other("signal handling is not supported on this platform"))
+}
@@ -0,0 +1,104 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use walkdir::WalkDir;
+
+/// SpaceConfig構造体
+/// バックアップ先の空き容量の設定 (`[space]`)
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct SpaceConfig {
+    /// バックアップ後も残しておく空き容量(バイト)
+    pub min_free: u64,
+}
+
+/// パスを含むファイルシステムの、一般ユーザーが利用できる空き容量(バイト)を取得する。
+#[cfg(unix)]
+pub fn available<P: AsRef<Path>>(path: P) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_ref().as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut stat: ::libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { ::libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64))
+}
+
+/// 空き容量を取得できないプラットフォームでは、制限しない。
+#[cfg(not(unix))]
+pub fn available<P: AsRef<Path>>(_path: P) -> io::Result<u64> {
+    Ok(u64::MAX)
+}
+
+/// バックアップに必要な容量(バイト)を見積もる。
+/// referenceには前回のミラー、スナップショットを指定し、
+/// サイズと更新日時が同じファイルは容量を消費しない(ハードリンク、もしくはコピー不要)とみなす。
+/// ZIPは圧縮後のサイズが分からないため、元のサイズの合計とする。
+pub fn estimate<P: AsRef<Path>>(src: P, reference: Option<&Path>) -> u64 {
+    let src = src.as_ref();
+    // ファイルの場合はファイル名で配置されるため、親ディレクトリからの相対パスとする。
+    let root = if src.is_file() {
+        src.parent().map(|p| p.to_path_buf()).unwrap_or_default()
+    } else {
+        src.to_path_buf()
+    };
+
+    let mut total = 0;
+    for entry in WalkDir::new(src).into_iter().filter_map(|e| e.ok()) {
+        let info = match entry.metadata() {
+            Ok(info) => info,
+            Err(_) => continue,
+        };
+        if !info.is_file() {
+            continue;
+        }
+        let unchanged = reference.and_then(|reference| {
+            let rel = entry.path().strip_prefix(&root).ok()?;
+            let prev = fs::symlink_metadata(reference.join(rel)).ok()?;
+            Some(prev.len() == info.len() && prev.modified().ok() == info.modified().ok())
+        }).unwrap_or(false);
+        if !unchanged {
+            total += info.len();
+        }
+    }
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_estimate() {
+        let root = env::temp_dir().join(format!("backupfs-space-test-{}", process::id()));
+        let src = root.join("src");
+        let reference = root.join("reference");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::create_dir_all(&reference).unwrap();
+        fs::write(src.join("a.txt"), vec![0; 100]).unwrap();
+        fs::write(src.join("sub/b.txt"), vec![0; 20]).unwrap();
+        assert_eq!(120, estimate(&src, None));
+        assert_eq!(100, estimate(src.join("a.txt"), None));
+
+        // 前回と同じサイズ、更新日時のファイルは容量を消費しない。
+        let modified = fs::metadata(src.join("a.txt")).unwrap().modified().unwrap();
+        fs::copy(src.join("a.txt"), reference.join("a.txt")).unwrap();
+        fs::File::open(reference.join("a.txt")).unwrap().set_modified(modified).unwrap();
+        assert_eq!(20, estimate(&src, Some(&reference)));
+        // ファイルの場合はファイル名で比較する。
+        assert_eq!(0, estimate(src.join("a.txt"), Some(&reference)));
+        // サイズが異なる場合は変更ありとみなす。
+        fs::write(reference.join("a.txt"), vec![0; 99]).unwrap();
+        assert_eq!(120, estimate(&src, Some(&reference)));
+
+        assert!(available(&root).unwrap() > 0);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
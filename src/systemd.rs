@@ -0,0 +1,238 @@
+use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use dirs;
+
+use config::Config;
+
+/// ユーザーユニットのファイル名
+pub const UNIT_NAME: &str = "backupfsd.service";
+
+/// メトリクスのソケットユニットのファイル名 (サービスと同じ名前のため、起動時にソケットが渡される)
+pub const SOCKET_NAME: &str = "backupfsd.socket";
+
+/// ユニットに設定するウォッチドッグの時間(秒)
+const WATCHDOG_SEC: u64 = 300;
+
+/// ソケットアクティベーションで渡されるファイルディスクリプタの開始番号
+const LISTEN_FDS_START: i32 = 3;
+
+/// ソケットアクティベーションで渡された、名前とファイルディスクリプタの組 (読み込み前は None)
+static LISTEN_FDS: Mutex<Option<Vec<(String, i32)>>> = Mutex::new(None);
+
+/// systemdへ状態を通知する。(sd_notify)
+/// `Type=notify` で起動されていない(NOTIFY_SOCKET がない)場合は何もせず、false を返却する。
+#[cfg(target_os = "linux")]
+pub fn notify(state: &str) -> io::Result<bool> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => PathBuf::from(path),
+        None => return Ok(false),
+    };
+    let socket = UnixDatagram::unbound()?;
+    let path_str = path.to_string_lossy();
+    // '@' で始まる場合は抽象名前空間のソケット
+    if let Some(name) = path_str.strip_prefix('@') {
+        let addr = SocketAddr::from_abstract_name(name.as_bytes())?;
+        socket.send_to_addr(state.as_bytes(), &addr)?;
+    } else {
+        socket.send_to(state.as_bytes(), &path)?;
+    }
+    Ok(true)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn notify(_state: &str) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// 起動が完了したことを通知する。
+pub fn ready(status: &str) {
+    send(&format!("READY=1\nSTATUS={}", status));
+}
+
+/// 現在の状態を通知する。(`systemctl status` に表示される)
+pub fn status(status: &str) {
+    send(&format!("STATUS={}", status));
+}
+
+/// 設定ファイルの再読み込みを開始したことを通知する。
+/// 完了後は ready() を呼び出す。
+pub fn reloading() {
+    send("RELOADING=1");
+}
+
+/// 終了処理を開始したことを通知する。
+pub fn stopping() {
+    send("STOPPING=1\nSTATUS=stopping");
+}
+
+/// ウォッチドッグへ正常に動作していることを通知する。
+pub fn watchdog() {
+    send("WATCHDOG=1");
+}
+
+/// ウォッチドッグへ通知すべき間隔を取得する。
+/// ウォッチドッグが有効でない場合は None となる。
+/// 余裕をもたせるため、WatchdogSec の半分の時間とする。
+pub fn watchdog_interval() -> Option<Duration> {
+    if !is_own_pid("WATCHDOG_PID", true) {
+        return None;
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if usec == 0 {
+        return None;
+    }
+    Some(Duration::from_micros(usec / 2))
+}
+
+/// ソケットアクティベーションで渡されたファイルディスクリプタのうち、
+/// ソケットユニットの FileDescriptorName が name のものを取得する。(sd_listen_fds_with_names)
+/// 渡されていない場合や、名前が一致しない場合は None となる。
+/// 初回の呼び出しで環境変数を読み込んで削除し、子プロセスへ引き継がないようにする。
+pub fn listen_fd(name: &str) -> Option<i32> {
+    let mut fds = LISTEN_FDS.lock().unwrap_or_else(|e| e.into_inner());
+    let fds = fds.get_or_insert_with(|| {
+        let fds = if is_own_pid("LISTEN_PID", false) {
+            parse_listen_fds(env::var("LISTEN_FDS").ok().as_deref(), env::var("LISTEN_FDNAMES").ok().as_deref())
+        } else {
+            Vec::new()
+        };
+        for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            env::remove_var(name);
+        }
+        fds
+    });
+    fds.iter().find(|(n, _)| n == name).map(|&(_, fd)| fd)
+}
+
+/// LISTEN_FDS と LISTEN_FDNAMES から、名前とファイルディスクリプタの組を生成する。
+/// 名前が渡されていないものは、systemdと同様に "unknown" とする。
+fn parse_listen_fds(fds: Option<&str>, names: Option<&str>) -> Vec<(String, i32)> {
+    let count = fds.and_then(|n| n.parse::<i32>().ok()).unwrap_or(0);
+    let mut names = names.unwrap_or_default().split(':');
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| (names.next().filter(|n| !n.is_empty()).unwrap_or("unknown").to_string(), fd))
+        .collect()
+}
+
+/// ユーザーユニットの格納先のパスを取得する。
+/// (~/.config/systemd/user/backupfsd.service)
+pub fn user_unit_path() -> PathBuf {
+    user_unit_dir().join(UNIT_NAME)
+}
+
+/// メトリクスのソケットユニットの格納先のパスを取得する。
+/// (~/.config/systemd/user/backupfsd.socket)
+pub fn user_socket_path() -> PathBuf {
+    user_unit_dir().join(SOCKET_NAME)
+}
+
+fn user_unit_dir() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| dirs::home_dir().unwrap_or_default().join(".config"))
+        .join("systemd")
+        .join("user")
+}
+
+/// backupfsd を `Type=notify` で起動するユーザーユニットの内容を生成する。
+/// 停止までの猶予は、設定ファイルの shutdown_timeout に余裕をもたせた時間とする。
+pub fn user_unit(daemon: &Path, config_path: &Path, config: &Config) -> String {
+    let stop_timeout = config.shutdown_timeout().as_secs() + 30;
+    format!("[Unit]
+Description=backupfs backup daemon
+
+[Service]
+Type=notify
+NotifyAccess=main
+ExecStart={daemon} --config {config}
+ExecReload=/bin/kill -HUP $MAINPID
+Environment=RUST_LOG=info
+WatchdogSec={watchdog}
+TimeoutStopSec={stop_timeout}
+Restart=on-failure
+
+[Install]
+WantedBy=default.target
+",
+        daemon = quote(daemon),
+        config = quote(config_path),
+        watchdog = WATCHDOG_SEC,
+        stop_timeout = stop_timeout)
+}
+
+/// メトリクスのHTTPサーバーのソケットを systemd が待ち受けるソケットユニットの内容を生成する。
+/// backupfsd は "metrics" という名前のソケットのみをメトリクスに利用する。
+pub fn user_socket(config: &Config) -> String {
+    format!("[Unit]
+Description=backupfs metrics socket
+
+[Socket]
+ListenStream={listen}
+FileDescriptorName=metrics
+
+[Install]
+WantedBy=sockets.target
+",
+        listen = config.metrics.listen)
+}
+
+fn send(state: &str) {
+    if let Err(err) = notify(state) {
+        warn!("systemd: failed to notify: {:?}", err);
+    }
+}
+
+/// 環境変数のPIDが自身のプロセスと一致するかどうか
+/// 設定されていない場合は default となる。
+fn is_own_pid(name: &str, default: bool) -> bool {
+    env::var(name)
+        .map(|pid| pid.parse::<u32>().ok() == Some(::std::process::id()))
+        .unwrap_or(default)
+}
+
+/// ユニットファイルのコマンドラインに記述できるよう、空白などを含むパスを引用符で囲む。
+fn quote(path: &Path) -> String {
+    let s = path.to_string_lossy();
+    if s.chars().any(|c| c.is_whitespace() || c == '"' || c == '\\') {
+        format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        s.into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_unit() {
+        let config = Config { shutdown_timeout: Some(90), ..Config::default() };
+        let unit = user_unit(Path::new("/usr/bin/backupfsd"), Path::new("/home/a b/config.toml"), &config);
+        assert!(unit.contains("Type=notify\n"));
+        assert!(unit.contains("ExecStart=/usr/bin/backupfsd --config \"/home/a b/config.toml\"\n"));
+        assert!(unit.contains("TimeoutStopSec=120\n"));
+    }
+
+    #[test]
+    fn test_user_socket() {
+        let socket = user_socket(&Config::default());
+        assert!(socket.contains("FileDescriptorName=metrics\n"));
+        assert!(socket.contains("ListenStream=127.0.0.1:9898\n"));
+    }
+
+    #[test]
+    fn test_parse_listen_fds() {
+        assert!(parse_listen_fds(None, None).is_empty());
+        assert_eq!(vec![("unknown".to_string(), 3)], parse_listen_fds(Some("1"), None));
+        assert_eq!(
+            vec![("control".to_string(), 3), ("metrics".to_string(), 4), ("unknown".to_string(), 5)],
+            parse_listen_fds(Some("3"), Some("control:metrics")),
+        );
+    }
+}
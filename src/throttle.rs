@@ -0,0 +1,312 @@
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// 一時停止の条件を確認する間隔
+const PAUSE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// ThrottleConfig構造体
+/// バックアップ処理のI/O、CPUの制限の設定 (`[throttle]`)
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct ThrottleConfig {
+    /// 読み込みの帯域の上限(バイト/秒) (0の場合は制限しない)
+    pub read_bps: u64,
+    /// 書き込みの帯域の上限(バイト/秒) (0の場合は制限しない)
+    pub write_bps: u64,
+    /// デーモンのnice値 (-20〜19)
+    pub nice: Option<i32>,
+    /// デーモンのI/Oスケジューリングクラス (Linuxのみ)
+    pub ioprio_class: Option<IoPrioClass>,
+    /// I/Oスケジューリングの優先度 (0〜7、小さいほど優先。best-effortのみ)
+    pub ioprio_level: Option<u8>,
+    /// バッテリー駆動中はバックアップを一時停止する。
+    pub pause_on_battery: bool,
+    /// ロードアベレージ(1分)がこの値を超えている間はバックアップを一時停止する。
+    pub max_load: Option<f64>,
+}
+
+/// IoPrioClass列挙型
+/// ioprio_set(2) のスケジューリングクラス
+#[derive(Deserialize, Serialize, Clone, Copy, Eq, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoPrioClass {
+    BestEffort,
+    Idle,
+}
+
+/// Bucket構造体
+/// トークンバケットによる帯域制限
+/// 不足分を前借りして待つため、並列に消費しても合計が上限を超えない。
+struct Bucket {
+    rate: u64,
+    tokens: f64,
+    last: Option<Instant>,
+}
+
+impl Bucket {
+    const fn new() -> Self {
+        Bucket { rate: 0, tokens: 0.0, last: None }
+    }
+
+    /// nバイト分のトークンを消費し、待つべき時間を返却する。
+    fn take(&mut self, n: usize) -> Duration {
+        if self.rate == 0 {
+            return Duration::from_secs(0);
+        }
+        let now = Instant::now();
+        let rate = self.rate as f64;
+        if let Some(last) = self.last {
+            // バーストは1秒分までとする。
+            let elapsed = now.duration_since(last);
+            self.tokens = (self.tokens + elapsed.as_secs_f64() * rate).min(rate);
+        } else {
+            self.tokens = rate;
+        }
+        self.last = Some(now);
+        self.tokens -= n as f64;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+/// Pause構造体
+/// 一時停止の条件と、前回の確認結果
+struct Pause {
+    on_battery: bool,
+    max_load: Option<f64>,
+    checked: Option<(Instant, bool)>,
+}
+
+static READ: Mutex<Bucket> = Mutex::new(Bucket::new());
+static WRITE: Mutex<Bucket> = Mutex::new(Bucket::new());
+/// 実行中のバックアップを中断するかどうか
+static ABORT: AtomicBool = AtomicBool::new(false);
+
+static PAUSE: Mutex<Pause> = Mutex::new(Pause { on_battery: false, max_load: None, checked: None });
+
+/// 帯域制限と一時停止の設定を反映する。
+/// デーモン全体(すべてのワーカー)で共有される。
+pub fn configure(config: &ThrottleConfig) {
+    lock(&READ).rate = config.read_bps;
+    lock(&WRITE).rate = config.write_bps;
+    let mut pause = lock(&PAUSE);
+    pause.on_battery = config.pause_on_battery;
+    pause.max_load = config.max_load;
+    pause.checked = None;
+}
+
+/// nice値、I/Oスケジューリングクラスを設定する。
+/// 呼び出したスレッドと、その後に生成したスレッドへ適用されるため、
+/// ワーカースレッドを生成する前に呼び出すことを期待する。
+pub fn apply_priority(config: &ThrottleConfig) {
+    if let Some(nice) = config.nice {
+        if let Err(err) = set_nice(nice) {
+            warn!("throttle: failed to set nice {}: {:?}", nice, err);
+        }
+    }
+    if let Some(class) = config.ioprio_class {
+        if let Err(err) = set_ioprio(class, config.ioprio_level.unwrap_or(7)) {
+            warn!("throttle: failed to set ioprio {:?}: {:?}", class, err);
+        }
+    }
+}
+
+/// 実行中のすべてのバックアップの読み書きを中断させる。
+/// 以降の読み書きはエラーとなり、アーカイバーは途中で失敗する。
+pub fn abort() {
+    ABORT.store(true, Ordering::SeqCst);
+}
+
+/// 中断を解除する。
+pub fn resume() {
+    ABORT.store(false, Ordering::SeqCst);
+}
+
+/// 中断されているかどうか
+pub fn is_aborted() -> bool {
+    ABORT.load(Ordering::SeqCst)
+}
+
+/// 読み込みに帯域制限をかける。
+pub fn reader<R: Read>(inner: R) -> Throttled<R> {
+    Throttled { inner }
+}
+
+/// 書き込みに帯域制限をかける。
+pub fn writer<W: Write>(inner: W) -> Throttled<W> {
+    Throttled { inner }
+}
+
+/// 一時停止の条件(バッテリー駆動、高負荷)を満たしている間は待機する。
+/// アーカイバーのファイルごとの処理の前に呼び出す。
+pub fn wait_while_paused() {
+    let mut logged = false;
+    while !is_aborted() && is_paused() {
+        if !logged {
+            info!("throttle: paused (on battery or high load)");
+            logged = true;
+        }
+        thread::sleep(PAUSE_CHECK_INTERVAL);
+    }
+    if logged {
+        info!("throttle: resumed");
+    }
+}
+
+/// Throttled構造体
+/// 読み書きしたバイト数に応じて待機し、帯域を制限する。
+pub struct Throttled<T> {
+    inner: T,
+}
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        check_abort()?;
+        let n = self.inner.read(buf)?;
+        let wait = lock(&READ).take(n);
+        sleep(wait);
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for Throttled<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        check_abort()?;
+        let n = self.inner.write(buf)?;
+        let wait = lock(&WRITE).take(n);
+        sleep(wait);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: Seek> Seek for Throttled<S> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// 中断されている場合はエラーとする。
+/// (Interrupted は io::copy などで再試行されるため利用しない)
+fn check_abort() -> io::Result<()> {
+    if is_aborted() {
+        return Err(io::Error::other("backup aborted"));
+    }
+    Ok(())
+}
+
+fn sleep(duration: Duration) {
+    if duration > Duration::from_secs(0) {
+        thread::sleep(duration);
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> ::std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn is_paused() -> bool {
+    let mut pause = lock(&PAUSE);
+    if !pause.on_battery && pause.max_load.is_none() {
+        return false;
+    }
+    if let Some((checked, paused)) = pause.checked {
+        if checked.elapsed() < PAUSE_CHECK_INTERVAL {
+            return paused;
+        }
+    }
+    let paused = (pause.on_battery && on_battery())
+        || pause.max_load.map(|max| load_average().map(|l| l > max).unwrap_or(false)).unwrap_or(false);
+    pause.checked = Some((Instant::now(), paused));
+    paused
+}
+
+/// AC電源に接続されておらず、バッテリーで駆動しているかどうか
+/// /sys/class/power_supply から判断し、判断できない場合はAC電源とみなす。
+fn on_battery() -> bool {
+    let entries = match fs::read_dir("/sys/class/power_supply") {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    let mut has_battery = false;
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let kind = fs::read_to_string(path.join("type")).unwrap_or_default();
+        match kind.trim() {
+            "Mains" | "USB" if fs::read_to_string(path.join("online")).map(|s| s.trim() == "1").unwrap_or(false) => {
+                return false;
+            },
+            "Battery" => has_battery = true,
+            _ => {},
+        }
+    }
+    has_battery
+}
+
+fn load_average() -> Option<f64> {
+    fs::read_to_string("/proc/loadavg").ok()?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+#[cfg(unix)]
+fn set_nice(nice: i32) -> io::Result<()> {
+    // PRIO_PROCESS に0を指定すると、Linuxでは呼び出したスレッドが対象となる。
+    if unsafe { ::libc::setpriority(::libc::PRIO_PROCESS as _, 0, nice) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_nice(_nice: i32) -> io::Result<()> {
+    Err(io::Error::other("nice is not supported on this platform"))
+}
+
+#[cfg(target_os = "linux")]
+fn set_ioprio(class: IoPrioClass, level: u8) -> io::Result<()> {
+    const IOPRIO_WHO_PROCESS: ::libc::c_int = 1;
+    const IOPRIO_CLASS_SHIFT: ::libc::c_int = 13;
+    let value = match class {
+        IoPrioClass::BestEffort => (2 << IOPRIO_CLASS_SHIFT) | ::libc::c_int::from(level.min(7)),
+        IoPrioClass::Idle => 3 << IOPRIO_CLASS_SHIFT,
+    };
+    if unsafe { ::libc::syscall(::libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, value) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_ioprio(_class: IoPrioClass, _level: u8) -> io::Result<()> {
+    Err(io::Error::other("ioprio is not supported on this platform"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        let mut bucket = Bucket::new();
+        assert_eq!(Duration::from_secs(0), bucket.take(1 << 20));
+        bucket.rate = 1000;
+        bucket.last = None;
+        // 最初の1秒分はバーストとして待たない。
+        assert_eq!(Duration::from_secs(0), bucket.take(1000));
+        let wait = bucket.take(500);
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500));
+    }
+}